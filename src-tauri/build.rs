@@ -1,3 +1,5 @@
 fn main() {
+  // blectl 单独构建时不需要 Tauri 的应用配置
+  #[cfg(feature = "gui")]
   tauri_build::build()
 }
@@ -0,0 +1,7 @@
+fn main() {
+  #[cfg(feature = "gui")]
+  tauri_bluetooth_tool_lib::cli::main(|| drop(tauri_plugin_blec::init()));
+  // 无 GUI 构建不包含 BLE 插件
+  #[cfg(not(feature = "gui"))]
+  tauri_bluetooth_tool_lib::cli::main(|| {});
+}
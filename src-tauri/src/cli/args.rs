@@ -0,0 +1,339 @@
+use std::path::PathBuf;
+
+use crate::ota::image::DEFAULT_PAD_BYTE;
+use crate::ota::package::FirmwareTarget;
+use crate::transfer::serial::SerialConfig;
+use crate::transfer::sim::SimModel;
+
+pub const USAGE: &str = "\
+Usage: blectl [OPTIONS] <COMMAND>
+
+Link (one is required except for `scan`, `package` and `sign`):
+  --serial <PORT>        Serial port, e.g. /dev/ttyUSB0
+  --baud <RATE>          Serial baud rate [default: 115200]
+  --sim <MODEL>          Built-in simulator: valve, channel or airpressure
+  --ble <ADDRESS>        BLE device address
+
+Options:
+  --json                 Print one JSON value per line
+  -v, --verbose          Log protocol traffic to stderr
+  --state-dir <DIR>      Where console history and unfinished OTA progress
+                         are kept [default: ~/.blectl]
+  -h, --help             Show this help
+
+Commands:
+  scan [--timeout <SECS>] [--prefix <NAME>]
+  info
+  ping
+  config read
+  config write <JSON>
+  stream valve-info [--count <N>]
+  reboot
+  ota [--public-key <FILE>] [--pad <BYTE>] <FILE>
+                         Flash a firmware package, checking its signatures
+                         if a public key is given, or an unsigned .bin, .hex,
+                         .srec or .elf image if not
+  console                Type text or `hex:<BYTES>`; `:history`, `!<N>`, `:quit`
+  package --model <M> --hardware <H> --version <V> --min-bootloader <V>
+          --signatures <FILE> [--base <ADDR>] [--pad <BYTE>] <IMAGE> <OUT>
+                         Wrap a raw image and its concatenated 64-byte block
+                         signatures into a firmware package
+  sign --model <M> --hardware <H> --version <V> --min-bootloader <V>
+       --key <FILE> [--base <ADDR>] [--pad <BYTE>] <IMAGE> <OUT>
+                         Sign every block of an image with an Ed25519
+                         private key (32 bytes, raw or hex) into a package
+
+Images may be raw binary, Intel HEX, S-record or ELF. Addressed images start
+at --base, or their lowest address, with gaps filled with --pad [default:
+0xff].";
+
+/// Where the block signatures of a new package come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockSignatures {
+  /// Concatenated signatures made elsewhere.
+  File(PathBuf),
+  /// Ed25519 private key to sign with.
+  Key(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Link {
+  Serial(SerialConfig),
+  Sim(SimModel),
+  Ble(String),
+}
+
+impl Link {
+  /// Names the device for logs and saved state.
+  pub fn address(&self) -> String {
+    match self {
+      Link::Serial(config) => config.port.clone(),
+      Link::Sim(model) => format!("sim:{:?}", model).to_lowercase(),
+      Link::Ble(address) => address.clone(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+  Scan {
+    timeout_secs: u64,
+    prefix: Option<String>,
+  },
+  Info,
+  Ping,
+  ConfigRead,
+  ConfigWrite(serde_json::Value),
+  StreamValveInfo {
+    count: Option<usize>,
+  },
+  Reboot,
+  Ota {
+    path: PathBuf,
+    public_key: Option<PathBuf>,
+    pad: u8,
+  },
+  Console,
+  Package {
+    target: FirmwareTarget,
+    signatures: BlockSignatures,
+    base: Option<u32>,
+    pad: u8,
+    image: PathBuf,
+    output: PathBuf,
+  },
+  Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+  pub link: Option<Link>,
+  pub json: bool,
+  pub verbose: bool,
+  pub state_dir: Option<PathBuf>,
+  pub command: Command,
+}
+
+fn value<T: std::str::FromStr>(
+  args: &mut impl Iterator<Item = String>,
+  flag: &str,
+) -> Result<T, String> {
+  let raw = args
+    .next()
+    .ok_or_else(|| format!("{} needs a value", flag))?;
+  raw
+    .parse()
+    .map_err(|_| format!("Invalid value for {}: {}", flag, raw))
+}
+
+// 地址和填充字节常写成十六进制
+fn number<T: TryFrom<u64>>(
+  args: &mut impl Iterator<Item = String>,
+  flag: &str,
+) -> Result<T, String> {
+  let raw: String = value(args, flag)?;
+  let parsed = match raw.strip_prefix("0x").or_else(|| raw.strip_prefix("0X")) {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => raw.parse().ok(),
+  };
+  parsed
+    .and_then(|number| T::try_from(number).ok())
+    .ok_or_else(|| format!("Invalid value for {}: {}", flag, raw))
+}
+
+fn sim_model(name: &str) -> Result<SimModel, String> {
+  serde_json::from_value(serde_json::Value::String(name.to_lowercase()))
+    .map_err(|_| format!("Unknown simulator model: {}", name))
+}
+
+/// Parses the arguments after the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+  let mut args = args.into_iter();
+  let mut link = None;
+  let mut baud = None;
+  let mut json = false;
+  let mut verbose = false;
+  let mut state_dir = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".blectl"));
+  let mut positional = Vec::new();
+  let mut timeout_secs = 5;
+  let mut prefix = None;
+  let mut count = None;
+  let mut model = None;
+  let mut hardware = None;
+  let mut version = None;
+  let mut min_bootloader = None;
+  let mut signatures = None;
+  let mut key = None;
+  let mut public_key = None;
+  let mut base = None;
+  let mut pad = DEFAULT_PAD_BYTE;
+
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--serial" => {
+        link = Some(Link::Serial(SerialConfig {
+          port: value(&mut args, "--serial")?,
+          ..SerialConfig::default()
+        }))
+      }
+      "--baud" => baud = Some(value(&mut args, "--baud")?),
+      "--sim" => link = Some(Link::Sim(sim_model(&value::<String>(&mut args, "--sim")?)?)),
+      "--ble" => link = Some(Link::Ble(value(&mut args, "--ble")?)),
+      "--json" => json = true,
+      "--state-dir" => state_dir = Some(value(&mut args, "--state-dir")?),
+      "-v" | "--verbose" => verbose = true,
+      "-h" | "--help" => positional = vec!["help".to_string()],
+      "--timeout" => timeout_secs = value(&mut args, "--timeout")?,
+      "--prefix" => prefix = Some(value(&mut args, "--prefix")?),
+      "--count" => count = Some(value(&mut args, "--count")?),
+      "--model" => model = Some(value(&mut args, "--model")?),
+      "--hardware" => hardware = Some(value(&mut args, "--hardware")?),
+      "--version" => version = Some(value(&mut args, "--version")?),
+      "--min-bootloader" => min_bootloader = Some(value(&mut args, "--min-bootloader")?),
+      "--signatures" => signatures = Some(value::<PathBuf>(&mut args, "--signatures")?),
+      "--key" => key = Some(value::<PathBuf>(&mut args, "--key")?),
+      "--base" => base = Some(number(&mut args, "--base")?),
+      "--pad" => pad = number(&mut args, "--pad")?,
+      "--public-key" => public_key = Some(value::<PathBuf>(&mut args, "--public-key")?),
+      flag if flag.starts_with('-') => return Err(format!("Unknown option: {}", flag)),
+      _ => positional.push(arg),
+    }
+  }
+
+  if let (Some(Link::Serial(config)), Some(baud)) = (&mut link, baud) {
+    config.baud = baud;
+  }
+
+  let words: Vec<&str> = positional.iter().map(String::as_str).collect();
+  let command = match words.as_slice() {
+    [] | ["help"] => Command::Help,
+    ["scan"] => Command::Scan {
+      timeout_secs,
+      prefix,
+    },
+    ["info"] => Command::Info,
+    ["ping"] => Command::Ping,
+    ["config", "read"] => Command::ConfigRead,
+    ["config", "write", config] => Command::ConfigWrite(
+      serde_json::from_str(config).map_err(|e| format!("Invalid config JSON: {}", e))?,
+    ),
+    ["stream", "valve-info"] => Command::StreamValveInfo { count },
+    ["reboot"] => Command::Reboot,
+    ["ota", file] => Command::Ota {
+      path: PathBuf::from(file),
+      public_key,
+      pad,
+    },
+    ["console"] => Command::Console,
+    [verb @ ("package" | "sign"), image, output] => {
+      let required =
+        |flag: &str, value: Option<String>| value.ok_or_else(|| format!("{} needs {}", verb, flag));
+      let target = FirmwareTarget {
+        model: required("--model", model)?,
+        hardware: required("--hardware", hardware)?,
+        version: required("--version", version)?,
+        min_bootloader: required("--min-bootloader", min_bootloader)?,
+      };
+      let signatures = match *verb {
+        "package" => BlockSignatures::File(signatures.ok_or("package needs --signatures")?),
+        _ => BlockSignatures::Key(key.ok_or("sign needs --key")?),
+      };
+      Command::Package {
+        target,
+        signatures,
+        base,
+        pad,
+        image: PathBuf::from(image),
+        output: PathBuf::from(output),
+      }
+    }
+    _ => return Err(format!("Unknown command: {}", words.join(" "))),
+  };
+  let offline = matches!(
+    command,
+    Command::Scan { .. } | Command::Package { .. } | Command::Help
+  );
+  if link.is_none() && !offline {
+    return Err("No link given, use --serial, --sim or --ble".to_string());
+  }
+
+  Ok(Args {
+    link,
+    json,
+    verbose,
+    state_dir,
+    command,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse_line(line: &str) -> Result<Args, String> {
+    parse(line.split_whitespace().map(str::to_string))
+  }
+
+  #[test]
+  fn parses_link_and_command() {
+    let args = parse_line("--serial /dev/ttyUSB0 --baud 921600 --json config read").unwrap();
+    let Some(Link::Serial(config)) = args.link else {
+      panic!("expected a serial link");
+    };
+    assert_eq!(
+      (config.port.as_str(), config.baud),
+      ("/dev/ttyUSB0", 921600)
+    );
+    assert!(args.json);
+    assert_eq!(args.command, Command::ConfigRead);
+
+    let args = parse_line("stream valve-info --sim AirPressure --count 3").unwrap();
+    assert_eq!(args.link, Some(Link::Sim(SimModel::AirPressure)));
+    assert_eq!(args.command, Command::StreamValveInfo { count: Some(3) });
+    assert_eq!(
+      parse_line("--sim valve ota --pad 0x00 fw.hex")
+        .unwrap()
+        .command,
+      Command::Ota {
+        path: PathBuf::from("fw.hex"),
+        public_key: None,
+        pad: 0,
+      }
+    );
+    assert_eq!(
+      parse_line("--ble AA:BB console").unwrap().command,
+      Command::Console
+    );
+
+    assert_eq!(
+      parse_line("config write {\"model\":\"v2\"}").unwrap_err(),
+      "No link given, use --serial, --sim or --ble"
+    );
+  }
+
+  #[test]
+  fn rejects_unknown_input() {
+    assert_eq!(
+      parse_line("--sim valve flash").unwrap_err(),
+      "Unknown command: flash"
+    );
+    assert_eq!(
+      parse_line("--sim toaster ping").unwrap_err(),
+      "Unknown simulator model: toaster"
+    );
+    assert_eq!(parse_line("--baud").unwrap_err(), "--baud needs a value");
+    assert_eq!(
+      parse_line("--sim valve ota --pad 0x100 fw.hex").unwrap_err(),
+      "Invalid value for --pad: 0x100"
+    );
+    assert_eq!(parse_line("scan").unwrap().link, None);
+    assert_eq!(
+      parse_line("package --model V --hardware A --version 1.0 fw.bin fw.pkg").unwrap_err(),
+      "package needs --min-bootloader"
+    );
+    assert_eq!(
+      parse_line("sign --model V --hardware A --version 1.0 --min-bootloader 1.0 a b").unwrap_err(),
+      "sign needs --key"
+    );
+  }
+}
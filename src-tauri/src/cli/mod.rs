@@ -0,0 +1,660 @@
+//! `blectl`: the command layer without a window, for scripts and field
+//! laptops. Talks to one device per invocation over serial, BLE or the
+//! built-in simulator.
+
+pub mod args;
+
+use log::LevelFilter;
+use serde::Serialize;
+use serde_json::json;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::commands::console::{
+  ConsoleHistory, ConsoleInput, ConsoleLine, Direction, open_console, send_input,
+};
+use crate::commands::handshake::handshake;
+use crate::commands::ota::flash;
+use crate::commands::ping::ping_device;
+use crate::commands::reboot::reboot_device;
+use crate::commands::{read_config, valve_info, write_config};
+use crate::error::{Error, ErrorKind};
+use crate::ota::image::FlatImage;
+use crate::ota::package::{FirmwarePackage, FirmwareTarget, SIGNATURE_LEN};
+use crate::ota::progress::{OtaProgressStore, device_key};
+use crate::ota::sample::SampleOta;
+use crate::ota::signing::{load_signing_key, load_verifying_key, sign_package};
+use crate::session::DeviceSession;
+use crate::transfer::Transfer;
+#[cfg(feature = "gui")]
+use crate::transfer::advertisement::ScanOptions;
+use crate::transfer::sim::{SimConfig, SimTransfer};
+use args::{Args, BlockSignatures, Command, Link, USAGE};
+
+/// Writes results either as human readable lines or as one JSON value per
+/// line.
+pub struct Printer {
+  json: bool,
+  captured: Option<Mutex<Vec<String>>>,
+}
+
+impl Printer {
+  pub fn stdout(json: bool) -> Self {
+    Printer {
+      json,
+      captured: None,
+    }
+  }
+
+  #[cfg(test)]
+  fn capture(json: bool) -> Self {
+    Printer {
+      json,
+      captured: Some(Mutex::new(Vec::new())),
+    }
+  }
+
+  pub fn print(&self, value: &impl Serialize, human: impl Display) {
+    let line = if self.json {
+      serde_json::to_string(value).unwrap_or_default()
+    } else {
+      human.to_string()
+    };
+    match &self.captured {
+      Some(lines) => lines.lock().unwrap().push(line),
+      None => println!("{}", line),
+    }
+  }
+}
+
+// 插件初始化会引入 Tauri 窗口运行时，只在可执行文件中引用
+static BLE_INIT: OnceLock<fn()> = OnceLock::new();
+
+/// Entry point of the `blectl` binary. `init_ble` sets up the BLE plugin
+/// and may panic if there is no adapter.
+pub fn main(init_ble: fn()) {
+  let _ = BLE_INIT.set(init_ble);
+  let args = match args::parse(std::env::args().skip(1)) {
+    Ok(args) => args,
+    Err(message) => {
+      eprintln!("{}\n\n{}", message, USAGE);
+      std::process::exit(2);
+    }
+  };
+  if args.command == Command::Help {
+    println!("{}", USAGE);
+    return;
+  }
+  let level = if args.verbose {
+    LevelFilter::Debug
+  } else {
+    LevelFilter::Warn
+  };
+  let _ = fern::Dispatch::new()
+    .format(|out, message, record| out.finish(format_args!("[{}] {}", record.level(), message)))
+    .level(level)
+    .chain(std::io::stderr())
+    .apply();
+
+  let printer = Arc::new(Printer::stdout(args.json));
+  let runtime = tokio::runtime::Runtime::new().expect("failed to start tokio runtime");
+  if let Err(e) = runtime.block_on(run(&args, printer.clone())) {
+    if args.json {
+      printer.print(&json!({ "error": e }), "");
+    } else {
+      eprintln!("error: {}", e);
+    }
+    std::process::exit(1);
+  }
+}
+
+pub async fn run(args: &Args, printer: Arc<Printer>) -> Result<(), Error> {
+  if let Command::Package {
+    target,
+    signatures,
+    base,
+    pad,
+    image,
+    output,
+  } = &args.command
+  {
+    let image = FlatImage::load(&read_file(image)?, *base, *pad)?;
+    return package(target, signatures, image, output, &printer);
+  }
+  if let Command::Scan {
+    timeout_secs,
+    prefix,
+  } = &args.command
+  {
+    return scan(*timeout_secs, prefix.clone(), &printer).await;
+  }
+  let link = args.link.as_ref().ok_or_else(Error::not_connected)?;
+  let transfer = open(link).await?;
+  let session = Arc::new(DeviceSession::new());
+  session.attach(transfer.clone()).await?;
+  handshake(&session).await;
+
+  let result = execute(&session, args, printer).await;
+  session.detach().await.ok();
+  transfer.deactivate().await.ok();
+  result
+}
+
+async fn open(link: &Link) -> Result<Arc<dyn Transfer>, Error> {
+  let transfer: Arc<dyn Transfer> = match link {
+    #[cfg(unix)]
+    Link::Serial(config) => Arc::new(crate::transfer::serial::SerialTransfer::new(config.clone())),
+    #[cfg(not(unix))]
+    Link::Serial(_) => return Err(crate::transfer::serial::serial_unavailable()),
+    Link::Sim(model) => Arc::new(SimTransfer::new(SimConfig {
+      model: *model,
+      ..SimConfig::default()
+    })),
+    Link::Ble(address) => return open_ble(address).await,
+  };
+  transfer.activate().await?;
+  Ok(transfer)
+}
+
+// 插件的事件循环是私有的，只在 Tauri 应用的插件 setup 中启动，命令行下连接可能等不到结果
+#[cfg(feature = "gui")]
+const BLE_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+// 插件通常随 Tauri 应用初始化，命令行下需要自行创建，且其内部使用独立运行时
+#[cfg(feature = "gui")]
+fn init_ble() -> Result<(), Error> {
+  if tauri_plugin_blec::get_handler().is_ok() {
+    return Ok(());
+  }
+  let init = *BLE_INIT
+    .get()
+    .ok_or_else(|| Error::new(ErrorKind::Unavailable, "BLE is not available"))?;
+  std::thread::spawn(move || std::panic::catch_unwind(init))
+    .join()
+    .ok()
+    .and_then(Result::ok)
+    .ok_or_else(|| Error::new(ErrorKind::Unavailable, "No usable Bluetooth adapter"))
+}
+
+#[cfg(feature = "gui")]
+async fn open_ble(address: &str) -> Result<Arc<dyn Transfer>, Error> {
+  use crate::transfer::ble::{BleTransfer, ble_handler};
+  use crate::transfer::profile::ProfileRegistry;
+
+  init_ble()?;
+  let connect = ble_handler()?.connect(address, tauri_plugin_blec::OnDisconnectHandler::None);
+  tokio::time::timeout(BLE_CONNECT_TIMEOUT, connect)
+    .await
+    .map_err(|_| {
+      Error::new(
+        ErrorKind::Unavailable,
+        format!(
+          "BLE connect {} timed out; BLE links are only supported inside the desktop app",
+          address
+        ),
+      )
+    })?
+    .map_err(|e| Error::transport(format!("BLE connect {} failed: {:?}", address, e)))?;
+  Ok(Arc::new(BleTransfer::new(&ProfileRegistry::new()).await?))
+}
+
+// 未启用 gui 特性时不链接 BLE 插件，只保留串口和模拟器
+#[cfg(not(feature = "gui"))]
+fn ble_unsupported() -> Error {
+  Error::new(
+    ErrorKind::Unavailable,
+    "blectl was built without BLE support",
+  )
+}
+
+#[cfg(not(feature = "gui"))]
+async fn open_ble(_address: &str) -> Result<Arc<dyn Transfer>, Error> {
+  Err(ble_unsupported())
+}
+
+async fn scan(
+  timeout_secs: u64,
+  prefix: Option<String>,
+  printer: &Arc<Printer>,
+) -> Result<(), Error> {
+  match crate::transfer::serial::list_serial_ports().await {
+    Ok(ports) => {
+      for port in ports {
+        printer.print(&json!({ "serial": port }), format!("serial  {}", port));
+      }
+    }
+    Err(e) => log::warn!("Skipping serial ports: {}", e),
+  }
+  scan_ble(timeout_secs, prefix, printer).await
+}
+
+#[cfg(not(feature = "gui"))]
+async fn scan_ble(
+  _timeout_secs: u64,
+  _prefix: Option<String>,
+  _printer: &Arc<Printer>,
+) -> Result<(), Error> {
+  log::warn!("Skipping BLE scan: {}", ble_unsupported());
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+async fn scan_ble(
+  timeout_secs: u64,
+  prefix: Option<String>,
+  printer: &Arc<Printer>,
+) -> Result<(), Error> {
+  if let Err(e) = init_ble() {
+    log::warn!("Skipping BLE scan: {}", e);
+    return Ok(());
+  }
+  let options = ScanOptions {
+    name_prefix: prefix,
+    service: None,
+  };
+  let sink = printer.clone();
+  crate::transfer::ble::start_scan(options, timeout_secs * 1000, move |update| {
+    let kind = update
+      .advertisement
+      .as_ref()
+      .map_or("unknown".to_string(), |ad| {
+        format!("{:?} v{}", ad.kind, ad.firmware).to_lowercase()
+      });
+    let rssi = update.rssi.map_or("-".to_string(), |rssi| rssi.to_string());
+    let human = format!(
+      "ble     {}  {}  {} dBm  {}",
+      update.address, update.name, rssi, kind
+    );
+    sink.print(&update, human);
+  })
+  .await?
+  .await
+  .map_err(|e| Error::transport(format!("BLE scan aborted: {}", e)))
+}
+
+fn read_file(path: &std::path::Path) -> Result<Vec<u8>, Error> {
+  std::fs::read(path).map_err(|e| {
+    Error::new(
+      ErrorKind::Io,
+      format!("Failed to read {}: {}", path.display(), e),
+    )
+  })
+}
+
+// 签名可由外部签名服务按块生成后传入，也可用本地私钥直接签名
+fn package(
+  target: &FirmwareTarget,
+  signatures: &BlockSignatures,
+  image: FlatImage,
+  output: &std::path::Path,
+  printer: &Printer,
+) -> Result<(), Error> {
+  let load_address = image.load_address;
+  let image = image.data;
+  let package = match signatures {
+    BlockSignatures::File(path) => {
+      let signatures = read_file(path)?;
+      if !signatures.len().is_multiple_of(SIGNATURE_LEN) {
+        return Err(Error::invalid_data(format!(
+          "Signature file is not a multiple of {} bytes",
+          SIGNATURE_LEN
+        )));
+      }
+      let signatures = signatures
+        .chunks_exact(SIGNATURE_LEN)
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect();
+      FirmwarePackage::new(target.clone(), image, signatures)
+    }
+    BlockSignatures::Key(path) => {
+      let key = load_signing_key(path)?;
+      log::info!(
+        "Signing with public key {}",
+        hex::encode(key.verifying_key().as_bytes())
+      );
+      sign_package(target.clone(), image, &key)
+    }
+  }
+  .with_load_address(load_address);
+  // 写出前按读取时的规则检查一遍，避免生成设备端无法使用的包
+  let bytes = package.to_bytes();
+  FirmwarePackage::parse(&bytes)?;
+  std::fs::write(output, bytes).map_err(|e| {
+    Error::new(
+      ErrorKind::Io,
+      format!("Failed to write {}: {}", output.display(), e),
+    )
+  })?;
+  printer.print(
+    &package.manifest,
+    format!(
+      "{} {} for {} rev {}, {} blocks",
+      output.display(),
+      target.version,
+      target.model,
+      target.hardware,
+      package.block_count()
+    ),
+  );
+  Ok(())
+}
+
+// 保存状态的文件，没有状态目录时只保存在内存中
+fn state_file(args: &Args, name: &str) -> Option<std::path::PathBuf> {
+  args.state_dir.as_ref().map(|dir| dir.join(name))
+}
+
+async fn execute(
+  session: &Arc<DeviceSession>,
+  args: &Args,
+  printer: Arc<Printer>,
+) -> Result<(), Error> {
+  match &args.command {
+    Command::Info => match session.info() {
+      Some(info) => {
+        let human = format!(
+          "model     {}\nhardware  {}\nfirmware  {}\nserial    {}\nprotocol  v{}\ncommands  {:?}",
+          info.model,
+          info.hardware,
+          info.firmware,
+          info.serial.as_deref().unwrap_or("-"),
+          session.protocol_version(),
+          info.capabilities
+        );
+        printer.print(&info, human);
+      }
+      None => printer.print(&json!(null), "Device did not identify itself"),
+    },
+    Command::Ping => {
+      ping_device(session).await?;
+      printer.print(&json!({ "ok": true }), "pong");
+    }
+    Command::ConfigRead => {
+      let config: serde_json::Value = read_config(session).await?;
+      let human = serde_json::to_string_pretty(&config).unwrap_or_default();
+      printer.print(&config, human);
+    }
+    Command::ConfigWrite(config) => {
+      write_config(session, config).await?;
+      printer.print(&json!({ "ok": true }), "Config written");
+    }
+    Command::StreamValveInfo { count } => stream_valve_info(session, *count, printer).await?,
+    Command::Reboot => {
+      reboot_device(session).await?;
+      printer.print(&json!({ "ok": true }), "Reboot requested");
+    }
+    Command::Ota {
+      path,
+      public_key,
+      pad,
+    } => {
+      let image = read_file(path)?;
+      let public_key = public_key.as_deref().map(load_verifying_key).transpose()?;
+      let progress = state_file(args, "ota_progress.json")
+        .map_or_else(OtaProgressStore::new, OtaProgressStore::load);
+      let link = args.link.as_ref().map(Link::address).unwrap_or_default();
+      let info = session.info();
+      flash(
+        session,
+        |transfer| {
+          SampleOta::new(transfer)
+            .with_progress(Arc::new(progress), device_key(info.as_ref(), &link))
+            .with_device(info)
+            .with_public_key(public_key)
+            .with_pad_byte(*pad)
+        },
+        Arc::new(image),
+        &|progress| {
+          printer.print(
+            &json!({ "progress": progress }),
+            format!("OTA {}%", progress),
+          );
+        },
+      )
+      .await?;
+      printer.print(&json!({ "ok": true }), "OTA complete");
+    }
+    Command::Console => {
+      let history = state_file(args, "console_history.json")
+        .map_or_else(ConsoleHistory::new, ConsoleHistory::load);
+      console(session, history, printer).await?
+    }
+    Command::Scan { .. } | Command::Package { .. } | Command::Help => {}
+  }
+  Ok(())
+}
+
+fn print_console_line(printer: &Printer, line: &ConsoleLine) {
+  let millis = line.timestamp_ms % 86_400_000;
+  let mut human = format!(
+    "{:02}:{:02}:{:02}.{:03} {}  {}  |{}|",
+    millis / 3_600_000,
+    millis / 60_000 % 60,
+    millis / 1000 % 60,
+    millis % 1000,
+    match line.direction {
+      Direction::Tx => "TX",
+      Direction::Rx => "RX",
+    },
+    line.hex,
+    line.ascii
+  );
+  if let Some(decoded) = &line.decoded {
+    human.push_str(&format!("  {}", decoded));
+  }
+  printer.print(line, human);
+}
+
+// 逐行读取标准输入，直到 EOF、`:quit` 或 Ctrl-C
+async fn console(
+  session: &Arc<DeviceSession>,
+  history: ConsoleHistory,
+  printer: Arc<Printer>,
+) -> Result<(), Error> {
+  use tokio::io::{AsyncBufReadExt, BufReader};
+
+  let sink = printer.clone();
+  open_console(session, move |line| print_console_line(&sink, &line));
+
+  let mut lines = BufReader::new(tokio::io::stdin()).lines();
+  loop {
+    let line = tokio::select! {
+      line = lines.next_line() => line.map_err(|e| Error::new(ErrorKind::Io, e.to_string()))?,
+      _ = tokio::signal::ctrl_c() => None,
+    };
+    let Some(line) = line else {
+      break;
+    };
+    let line = line.trim_end_matches(['\r', '\n']);
+    let input = match line {
+      "" => continue,
+      ":quit" => break,
+      ":history" => {
+        for (index, input) in history.entries().iter().enumerate() {
+          printer.print(input, format!("{:>4}  {}", index, history_text(input)));
+        }
+        continue;
+      }
+      _ => match line.strip_prefix('!').map(str::parse::<usize>) {
+        Some(Ok(index)) => match history.entries().get(index) {
+          Some(input) => input.clone(),
+          None => {
+            eprintln!("No history entry {}", index);
+            continue;
+          }
+        },
+        _ => ConsoleInput::parse(line),
+      },
+    };
+    let protocol = session.protocol();
+    let sent = send_input(session, &input, |data| {
+      if let Err(e) = history.push(input.clone()) {
+        log::warn!("{}", e);
+      }
+      print_console_line(&printer, &ConsoleLine::new(Direction::Tx, data, protocol));
+    });
+    if let Err(e) = sent.await {
+      eprintln!("error: {}", e);
+    }
+  }
+  session.set_monitor(None);
+  Ok(())
+}
+
+fn history_text(input: &ConsoleInput) -> String {
+  match input {
+    ConsoleInput::Text(text) => text.clone(),
+    ConsoleInput::Hex(hex) => format!("hex:{}", hex),
+  }
+}
+
+// 持续输出直至达到数量或收到 Ctrl-C
+async fn stream_valve_info(
+  session: &Arc<DeviceSession>,
+  count: Option<usize>,
+  printer: Arc<Printer>,
+) -> Result<(), Error> {
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+  valve_info::start(session, move |sample| {
+    let _ = tx.send(sample);
+  })
+  .await?;
+  let mut received = 0;
+  while count.is_none_or(|count| received < count) {
+    let sample = tokio::select! {
+      sample = rx.recv() => sample,
+      _ = tokio::signal::ctrl_c() => None,
+      _ = tokio::time::sleep(Duration::from_secs(10)) => {
+        log::warn!("No valve info for 10 seconds");
+        continue;
+      }
+    };
+    let Some(sample) = sample else {
+      break;
+    };
+    received += 1;
+    let mut human = format!(
+      "ticks {:>6}  status {}",
+      sample.total_ticks, sample.current_status
+    );
+    if let (Some(target), Some(fault)) = (sample.target_ticks, sample.fault_code) {
+      human.push_str(&format!("  target {:>6}  fault {}", target, fault));
+    }
+    printer.print(&sample, human);
+  }
+  valve_info::stop(session).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sim_args(command: Command) -> Args {
+    Args {
+      link: Some(Link::Sim(crate::transfer::sim::SimModel::Valve)),
+      json: true,
+      verbose: false,
+      state_dir: None,
+      command,
+    }
+  }
+
+  async fn run_captured(command: Command) -> (Result<(), Error>, Vec<String>) {
+    let printer = Arc::new(Printer::capture(true));
+    let result = run(&sim_args(command), printer.clone()).await;
+    let lines = printer.captured.as_ref().unwrap().lock().unwrap().clone();
+    (result, lines)
+  }
+
+  #[tokio::test]
+  async fn reads_config_and_streams_from_simulator() {
+    let (result, lines) = run_captured(Command::ConfigRead).await;
+    result.unwrap();
+    let config: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(config["model"], "SIM-VALVE");
+
+    let (result, lines) = run_captured(Command::StreamValveInfo { count: Some(2) }).await;
+    result.unwrap();
+    assert_eq!(lines.len(), 2);
+    let sample: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+    assert!(sample["total_ticks"].is_i64());
+  }
+
+  #[tokio::test]
+  async fn identifies_simulated_device() {
+    let (result, lines) = run_captured(Command::Info).await;
+    result.unwrap();
+    let info: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(info["capabilities"], json!(["valve", "ota", "ota_resume"]));
+  }
+
+  #[tokio::test]
+  async fn signs_image_and_flashes_only_with_matching_key() {
+    let dir = std::env::temp_dir().join(format!("blectl-ota-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let signing = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+    let other = ed25519_dalek::SigningKey::from_bytes(&[4; 32]);
+    std::fs::write(dir.join("fw.bin"), vec![0x5a; 3000]).unwrap();
+    std::fs::write(dir.join("key"), hex::encode(signing.to_bytes())).unwrap();
+    std::fs::write(dir.join("key.pub"), signing.verifying_key().as_bytes()).unwrap();
+    std::fs::write(dir.join("other.pub"), other.verifying_key().as_bytes()).unwrap();
+
+    let (result, _) = run_captured(Command::Package {
+      target: FirmwareTarget {
+        model: "SIM-VALVE".to_string(),
+        hardware: "sim".to_string(),
+        version: "2.0.0".to_string(),
+        min_bootloader: "1.0.0".to_string(),
+      },
+      signatures: BlockSignatures::Key(dir.join("key")),
+      base: None,
+      pad: 0xff,
+      image: dir.join("fw.bin"),
+      output: dir.join("fw.pkg"),
+    })
+    .await;
+    result.unwrap();
+
+    let ota = |public_key: &str| Command::Ota {
+      path: dir.join("fw.pkg"),
+      public_key: Some(dir.join(public_key)),
+      pad: 0xff,
+    };
+    let (rejected, lines) = run_captured(ota("other.pub")).await;
+    assert_eq!(
+      rejected.unwrap_err().message,
+      "Block 0 is not signed by the trusted firmware key"
+    );
+    assert!(lines.is_empty());
+
+    let (result, lines) = run_captured(ota("key.pub")).await;
+    std::fs::remove_dir_all(&dir).ok();
+    result.unwrap();
+    assert_eq!(lines.last().unwrap(), r#"{"ok":true}"#);
+    assert!(lines.contains(&r#"{"progress":100}"#.to_string()));
+  }
+
+  #[tokio::test]
+  async fn flashes_intel_hex_only_inside_device_flash() {
+    let path = std::env::temp_dir().join(format!("blectl-ota-{}.hex", std::process::id()));
+    let ota = Command::Ota {
+      path: path.clone(),
+      public_key: None,
+      pad: 0xff,
+    };
+    // 模拟器应用区从 0x08008000 开始
+    std::fs::write(&path, ":020000040800F2\n:048010000102030462\n:00000001FF\n").unwrap();
+    let (result, lines) = run_captured(ota.clone()).await;
+    result.unwrap();
+    assert_eq!(lines.last().unwrap(), r#"{"ok":true}"#);
+
+    std::fs::write(&path, ":020000040800F2\n:0400000001020304F2\n:00000001FF\n").unwrap();
+    let (result, _) = run_captured(ota).await;
+    std::fs::remove_file(&path).ok();
+    assert_eq!(
+      result.unwrap_err().message,
+      "Image data at 0x08000000 lies below the start address 0x08008000"
+    );
+  }
+}
@@ -1,59 +1,45 @@
-use super::{AirPressureConfig, do_request_response};
-use crate::transfer::ble::BleTransfer;
-use std::sync::Arc;
-use tauri::Emitter;
+use super::identify::Capability;
+use super::{AirPressureConfig, do_request_response, queue::Priority, read_config, write_config};
+use crate::error::Error;
+use crate::registry::{DeviceEvent, DeviceRegistry};
+use tauri::{Emitter, State};
 
 #[tauri::command]
-pub async fn airpressure_configure(config: AirPressureConfig) -> Result<(), String> {
-  let payload = serde_json::to_string(&config)
-    .inspect(|json| log::info!("Serialized JSON: {}", json))
-    .map(|json| format!("config_write {}\r\n", json)) // 拼接前缀
-    .map_err(|e| format!("Serialization failed: {}", e))?;
-
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(Arc::new(ble_transfer), &payload, 3, false, None).await
+pub async fn airpressure_configure(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  config: AirPressureConfig,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Airpressure)?;
+  write_config(&session, &config).await
 }
 
 #[tauri::command]
-pub async fn airpressure_readconfig(app_handle: tauri::AppHandle) -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
-    "config_read\r\n",
-    3,
-    false,
-    Some(Arc::new(
-      move |data: Vec<u8>| match serde_json::from_slice::<AirPressureConfig>(&data) {
-        Ok(channel_config) => {
-          log::debug!("Channel config: {:?}", channel_config);
-          if let Err(e) = app_handle.emit("channel_config", channel_config) {
-            log::error!("Failed to emit channel config: {}", e);
-          }
-        }
-        Err(e) => {
-          log::error!("Failed to parse valve config: {}", e);
-        }
-      },
-    )),
-  )
-  .await
+pub async fn airpressure_readconfig(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Airpressure)?;
+  let airpressure_config: AirPressureConfig = read_config(&session).await?;
+  log::debug!("AirPressure config: {:?}", airpressure_config);
+  if let Err(e) = app_handle.emit(
+    "channel_config",
+    DeviceEvent::new(&device, airpressure_config),
+  ) {
+    log::error!("Failed to emit airpressure config: {}", e);
+  }
+  Ok(())
 }
 
 #[tauri::command]
-pub async fn airpressure_refactory() -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
-    "config_refactory\r\n",
-    3,
-    false,
-    None,
-  )
-  .await
-}
\ No newline at end of file
+pub async fn airpressure_refactory(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Airpressure)?;
+  do_request_response(&session, "config_refactory\r\n", 3, Priority::Normal, None).await
+}
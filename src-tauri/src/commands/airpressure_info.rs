@@ -1,41 +1,56 @@
-use super::{AirPressureVal, do_request_response};
-use crate::transfer::ble::BleTransfer;
+use super::identify::{Capability, LEGACY_PROTOCOL_VERSION};
+use super::payload::decode_airpressure;
+use super::{start_stream, stop_stream};
+use crate::error::Error;
+use crate::registry::{DeviceEvent, DeviceRegistry};
 use std::sync::Arc;
-use tauri::Emitter;
+use tauri::{Emitter, State};
 
 #[tauri::command]
-pub async fn start_airpressure_info(app_handle: tauri::AppHandle) -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
+pub async fn start_airpressure_info(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Airpressure)?;
+  let session_ref = Arc::downgrade(&session);
+  start_stream(
+    &session,
+    "airpressure_info",
     "airpressure_info 1\r\n",
     3,
-    true,
-    Some(Arc::new(move |data: Vec<u8>| {
-      if data.len() != std::mem::size_of::<AirPressureVal>() {
-        log::error!(
-          "Received data length mismatch. Expected {}, got {}",
-          std::mem::size_of::<AirPressureVal>(),
-          data.len()
-        );
-        return;
-      }
-      let airpressure_info: AirPressureVal = *bytemuck::from_bytes::<AirPressureVal>(&data);
+    Arc::new(move |data: Vec<u8>| {
+      let version = session_ref
+        .upgrade()
+        .map_or(LEGACY_PROTOCOL_VERSION, |session| {
+          session.protocol_version()
+        });
+      let airpressure_info = match decode_airpressure(version, &data) {
+        Ok(airpressure_info) => airpressure_info,
+        Err(e) => {
+          log::error!("Failed to decode airpressure info: {}", e);
+          return;
+        }
+      };
       log::debug!("AirPressure Info: {:?}", airpressure_info);
-      if let Err(e) = app_handle.emit("airpressure_info", airpressure_info) {
+      if let Err(e) = app_handle.emit(
+        "airpressure_info",
+        DeviceEvent::new(&device, airpressure_info),
+      ) {
         log::error!("Failed to emit airpressure info: {}", e);
       }
-    })),
+    }),
   )
   .await
 }
 
 #[tauri::command]
-pub async fn stop_airpressure_info() -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(Arc::new(ble_transfer), "airpressure_info 0\r\n", 3, false, None).await
+pub async fn stop_airpressure_info(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Airpressure)?;
+  stop_stream(&session, "airpressure_info", "airpressure_info 0\r\n", 3).await
 }
@@ -1,59 +1,42 @@
-use super::{ChannelConfig, do_request_response};
-use crate::transfer::ble::BleTransfer;
-use std::sync::Arc;
-use tauri::Emitter;
+use super::identify::Capability;
+use super::{ChannelConfig, do_request_response, queue::Priority, read_config, write_config};
+use crate::error::Error;
+use crate::registry::{DeviceEvent, DeviceRegistry};
+use tauri::{Emitter, State};
 
 #[tauri::command]
-pub async fn channel_configure(config: ChannelConfig) -> Result<(), String> {
-  let payload = serde_json::to_string(&config)
-    .inspect(|json| log::info!("Serialized JSON: {}", json))
-    .map(|json| format!("config_write {}\r\n", json)) // 拼接前缀
-    .map_err(|e| format!("Serialization failed: {}", e))?;
-
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(Arc::new(ble_transfer), &payload, 3, false, None).await
+pub async fn channel_configure(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  config: ChannelConfig,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Channel)?;
+  write_config(&session, &config).await
 }
 
 #[tauri::command]
-pub async fn channel_readconfig(app_handle: tauri::AppHandle) -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
-    "config_read\r\n",
-    3,
-    false,
-    Some(Arc::new(
-      move |data: Vec<u8>| match serde_json::from_slice::<ChannelConfig>(&data) {
-        Ok(channel_config) => {
-          log::debug!("Channel config: {:?}", channel_config);
-          if let Err(e) = app_handle.emit("channel_config", channel_config) {
-            log::error!("Failed to emit channel config: {}", e);
-          }
-        }
-        Err(e) => {
-          log::error!("Failed to parse valve config: {}", e);
-        }
-      },
-    )),
-  )
-  .await
+pub async fn channel_readconfig(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Channel)?;
+  let channel_config: ChannelConfig = read_config(&session).await?;
+  log::debug!("Channel config: {:?}", channel_config);
+  if let Err(e) = app_handle.emit("channel_config", DeviceEvent::new(&device, channel_config)) {
+    log::error!("Failed to emit channel config: {}", e);
+  }
+  Ok(())
 }
 
 #[tauri::command]
-pub async fn channel_refactory() -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
-    "config_refactory\r\n",
-    3,
-    false,
-    None,
-  )
-  .await
-}
\ No newline at end of file
+pub async fn channel_refactory(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Channel)?;
+  do_request_response(&session, "config_refactory\r\n", 3, Priority::Normal, None).await
+}
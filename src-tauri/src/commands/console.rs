@@ -0,0 +1,454 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "gui")]
+use tauri::{Emitter, State};
+use tokio::time::timeout;
+
+use super::queue::Priority;
+use super::{CMD_ERR, CMD_OK};
+use crate::error::{Error, ErrorKind};
+use crate::protocol::{FrameDecoder, FrameType, Protocol};
+#[cfg(feature = "gui")]
+use crate::registry::{DeviceEvent, DeviceRegistry};
+use crate::session::DeviceSession;
+
+// 原始命令不一定有应答，最多占用命令队列这么久
+const CONSOLE_ACK_WAIT: Duration = Duration::from_millis(500);
+const HISTORY_LIMIT: usize = 200;
+
+/// A line typed into the console.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "format", content = "data", rename_all = "snake_case")]
+pub enum ConsoleInput {
+  /// Sent as UTF-8 after expanding `\r`, `\n`, `\t`, `\\` and `\xNN`, with
+  /// `\r\n` appended unless the text already ends with a line break.
+  Text(String),
+  /// Hex bytes, optionally separated by spaces, `:` or `,`.
+  Hex(String),
+}
+
+impl ConsoleInput {
+  /// Reads `hex:` prefixed lines as hex and anything else as text.
+  pub fn parse(line: &str) -> Self {
+    match line.strip_prefix("hex:") {
+      Some(hex) => ConsoleInput::Hex(hex.trim().to_string()),
+      None => ConsoleInput::Text(line.to_string()),
+    }
+  }
+
+  pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+    match self {
+      ConsoleInput::Text(text) => {
+        let mut bytes = unescape(text)?;
+        if !bytes.ends_with(b"\n") {
+          bytes.extend_from_slice(b"\r\n");
+        }
+        Ok(bytes)
+      }
+      ConsoleInput::Hex(hex) => {
+        let digits: String = hex
+          .chars()
+          .filter(|c| !c.is_whitespace() && !matches!(c, ':' | ','))
+          .collect();
+        if !digits.len().is_multiple_of(2) {
+          return Err(Error::invalid_data("Hex input needs two digits per byte"));
+        }
+        (0..digits.len())
+          .step_by(2)
+          .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+              .map_err(|_| Error::invalid_data(format!("Invalid hex byte: {}", &digits[i..i + 2])))
+          })
+          .collect()
+      }
+    }
+  }
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, Error> {
+  let mut bytes = Vec::with_capacity(text.len());
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      let mut buf = [0; 4];
+      bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+      continue;
+    }
+    match chars.next() {
+      Some('r') => bytes.push(b'\r'),
+      Some('n') => bytes.push(b'\n'),
+      Some('t') => bytes.push(b'\t'),
+      Some('\\') => bytes.push(b'\\'),
+      Some('x') => {
+        let hex: String = chars.by_ref().take(2).collect();
+        let byte = u8::from_str_radix(&hex, 16)
+          .map_err(|_| Error::invalid_data(format!("Invalid escape: \\x{}", hex)))?;
+        bytes.push(byte);
+      }
+      other => {
+        return Err(Error::invalid_data(format!(
+          "Invalid escape: \\{}",
+          other.map(String::from).unwrap_or_default()
+        )));
+      }
+    }
+  }
+  Ok(bytes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+  Tx,
+  Rx,
+}
+
+/// One write or notification as shown in the console, emitted as `console`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConsoleLine {
+  /// Milliseconds since the Unix epoch.
+  pub timestamp_ms: u64,
+  pub direction: Direction,
+  pub hex: String,
+  pub ascii: String,
+  /// What the bytes mean to the app, if anything.
+  pub decoded: Option<String>,
+}
+
+impl ConsoleLine {
+  pub fn new(direction: Direction, data: &[u8], protocol: Protocol) -> Self {
+    ConsoleLine {
+      timestamp_ms: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64),
+      direction,
+      hex: data
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" "),
+      ascii: data
+        .iter()
+        .map(|&b| match b {
+          0x20..=0x7e => b as char,
+          _ => '.',
+        })
+        .collect(),
+      decoded: describe(data, protocol),
+    }
+  }
+}
+
+fn response_name(code: u16) -> String {
+  match code {
+    CMD_OK => "CMD_OK".to_string(),
+    CMD_ERR => "CMD_ERR".to_string(),
+    code => format!("{:#06x}", code),
+  }
+}
+
+fn describe(data: &[u8], protocol: Protocol) -> Option<String> {
+  match protocol {
+    Protocol::Binary => {
+      let frames = FrameDecoder::default().push(data);
+      let described: Vec<String> = frames
+        .iter()
+        .map(|frame| match (frame.kind, frame.payload.as_slice()) {
+          (FrameType::Ack, [b0, b1, rest @ ..]) => format!(
+            "Ack #{} {} +{} bytes",
+            frame.sequence,
+            response_name(u16::from_le_bytes([*b0, *b1])),
+            rest.len()
+          ),
+          (FrameType::Command, payload) => format!(
+            "Command #{} {}",
+            frame.sequence,
+            String::from_utf8_lossy(payload)
+          ),
+          (kind, payload) => format!("{:?} #{} {} bytes", kind, frame.sequence, payload.len()),
+        })
+        .collect();
+      (!described.is_empty()).then(|| described.join(", "))
+    }
+    Protocol::Text => match data {
+      [b0, b1, rest @ ..] if matches!(u16::from_le_bytes([*b0, *b1]), CMD_OK | CMD_ERR) => {
+        let name = response_name(u16::from_le_bytes([*b0, *b1]));
+        Some(match rest.len() {
+          0 => name,
+          len => format!("{} +{} bytes", name, len),
+        })
+      }
+      _ => None,
+    },
+  }
+}
+
+/// Sends `input`, holding the command queue like any other request until the
+/// device acks or a short wait passes. Returns the ack, if any.
+///
+/// Hex goes out unchanged. Text becomes a command frame while the binary
+/// protocol is active, since the firmware ignores raw lines then. `on_send`
+/// is given the bytes right before they are written.
+pub async fn send_input(
+  session: &DeviceSession,
+  input: &ConsoleInput,
+  on_send: impl FnOnce(&[u8]),
+) -> Result<Option<u16>, Error> {
+  let data = input.to_bytes()?;
+  let _slot = session.queue().acquire(Priority::Normal).await?;
+  let transfer = session.transfer()?;
+  let (request, rx) = match (input, session.protocol()) {
+    (ConsoleInput::Text(_), Protocol::Binary) => {
+      let command = String::from_utf8(data)
+        .map_err(|_| Error::invalid_data("Binary protocol commands must be UTF-8"))?;
+      session.begin_request(&command, None)
+    }
+    _ => (data, session.begin_raw_request()),
+  };
+  on_send(&request);
+  if let Err(e) = transfer.send(&request).await {
+    session.end_request();
+    return Err(e.context("Failed to send"));
+  }
+  let ack = timeout(CONSOLE_ACK_WAIT, rx)
+    .await
+    .ok()
+    .and_then(Result::ok);
+  session.end_request();
+  Ok(ack)
+}
+
+/// Console inputs, newest last, saved so they survive restarts.
+pub struct ConsoleHistory {
+  entries: RwLock<Vec<ConsoleInput>>,
+  path: Option<PathBuf>,
+}
+
+impl ConsoleHistory {
+  pub fn new() -> Self {
+    ConsoleHistory {
+      entries: RwLock::new(Vec::new()),
+      path: None,
+    }
+  }
+
+  /// Loads the history from `path`, which is also where it is saved.
+  pub fn load(path: PathBuf) -> Self {
+    let entries = match std::fs::read(&path) {
+      Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+        log::error!("Ignoring invalid console history in {:?}: {}", path, e);
+        Vec::new()
+      }),
+      Err(_) => Vec::new(),
+    };
+    ConsoleHistory {
+      entries: RwLock::new(entries),
+      path: Some(path),
+    }
+  }
+
+  pub fn entries(&self) -> Vec<ConsoleInput> {
+    self.entries.read().unwrap().clone()
+  }
+
+  pub fn push(&self, input: ConsoleInput) -> Result<(), Error> {
+    {
+      let mut entries = self.entries.write().unwrap();
+      // 连续重复发送只记录一次
+      if entries.last() == Some(&input) {
+        return Ok(());
+      }
+      entries.push(input);
+      let excess = entries.len().saturating_sub(HISTORY_LIMIT);
+      entries.drain(..excess);
+    }
+    self.persist()
+  }
+
+  fn persist(&self) -> Result<(), Error> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+    let io_error = |e: std::io::Error| {
+      Error::new(
+        ErrorKind::Io,
+        format!("Failed to save console history: {}", e),
+      )
+    };
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let json = serde_json::to_vec(&*self.entries.read().unwrap())
+      .map_err(|e| Error::invalid_data(format!("Serialization failed: {}", e)))?;
+    std::fs::write(path, json).map_err(io_error)
+  }
+}
+
+impl Default for ConsoleHistory {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Reports every notification of `session` to `on_line` until closed.
+pub fn open_console(
+  session: &Arc<DeviceSession>,
+  on_line: impl Fn(ConsoleLine) + Send + Sync + 'static,
+) {
+  let session_ref = Arc::downgrade(session);
+  session.set_monitor(Some(Arc::new(move |data: Vec<u8>| {
+    let protocol = session_ref
+      .upgrade()
+      .map_or(Protocol::Text, |session| session.protocol());
+    on_line(ConsoleLine::new(Direction::Rx, &data, protocol));
+  })));
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn console_open(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  open_console(&session, move |line| {
+    let _ = app_handle.emit("console", DeviceEvent::new(&device, line));
+  });
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn console_close(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  devices.session(&device)?.set_monitor(None);
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn console_send(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  history: State<'_, ConsoleHistory>,
+  device: String,
+  input: ConsoleInput,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  let protocol = session.protocol();
+  send_input(&session, &input, |data| {
+    if let Err(e) = history.push(input.clone()) {
+      log::warn!("{}", e);
+    }
+    let line = ConsoleLine::new(Direction::Tx, data, protocol);
+    let _ = app_handle.emit("console", DeviceEvent::new(&device, line));
+  })
+  .await
+  .map(|_| ())
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn console_history(
+  history: State<'_, ConsoleHistory>,
+) -> Result<Vec<ConsoleInput>, Error> {
+  Ok(history.entries())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::Frame;
+  use crate::transfer::mock::MockTransfer;
+  use std::sync::Mutex;
+
+  #[test]
+  fn parses_text_and_hex_input() {
+    assert_eq!(
+      ConsoleInput::parse("valve_info 1").to_bytes().unwrap(),
+      b"valve_info 1\r\n"
+    );
+    assert_eq!(
+      ConsoleInput::parse(r"raw\x00\n").to_bytes().unwrap(),
+      b"raw\x00\n"
+    );
+    assert_eq!(
+      ConsoleInput::parse("hex: a5:01 00,FF").to_bytes().unwrap(),
+      vec![0xa5, 0x01, 0x00, 0xff]
+    );
+    assert_eq!(
+      ConsoleInput::parse("hex:abc")
+        .to_bytes()
+        .unwrap_err()
+        .message,
+      "Hex input needs two digits per byte"
+    );
+  }
+
+  #[test]
+  fn decodes_acks_in_either_protocol() {
+    let text = ConsoleLine::new(Direction::Rx, &[0xfe, 0xca, b'o', b'k'], Protocol::Text);
+    assert_eq!(text.hex, "fe ca 6f 6b");
+    assert_eq!(text.ascii, "..ok");
+    assert_eq!(text.decoded.as_deref(), Some("CMD_OK +2 bytes"));
+
+    let frame = Frame::new(FrameType::Ack, 4, CMD_ERR.to_le_bytes().to_vec()).encode();
+    let binary = ConsoleLine::new(Direction::Rx, &frame, Protocol::Binary);
+    assert_eq!(binary.decoded.as_deref(), Some("Ack #4 CMD_ERR +0 bytes"));
+    assert_eq!(
+      ConsoleLine::new(Direction::Rx, b"hello", Protocol::Text).decoded,
+      None
+    );
+  }
+
+  #[tokio::test]
+  async fn raw_send_waits_for_ack_and_monitor_sees_it() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on("ping\r\n", vec![CMD_OK.to_le_bytes().to_vec()]);
+    let session = Arc::new(DeviceSession::new());
+    session.attach(mock.clone()).await.unwrap();
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let sink = lines.clone();
+    open_console(&session, move |line| sink.lock().unwrap().push(line));
+
+    let ack = send_input(&session, &ConsoleInput::parse("ping"), |_| {})
+      .await
+      .unwrap();
+
+    assert_eq!(ack, Some(CMD_OK));
+    assert_eq!(mock.writes(), vec![b"ping\r\n".to_vec()]);
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].decoded.as_deref(), Some("CMD_OK"));
+  }
+
+  #[tokio::test]
+  async fn text_is_framed_under_binary_protocol() {
+    let mock = Arc::new(MockTransfer::new());
+    let ping = Frame::new(FrameType::Command, 0, "ping").encode();
+    let ack = Frame::new(FrameType::Ack, 0, CMD_OK.to_le_bytes().to_vec()).encode();
+    mock.on(&ping, vec![ack]);
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    session.set_protocol(Protocol::Binary);
+    let sent = Mutex::new(Vec::new());
+
+    let ack = send_input(&session, &ConsoleInput::parse("ping"), |data| {
+      sent.lock().unwrap().push(data.to_vec())
+    })
+    .await
+    .unwrap();
+    send_input(&session, &ConsoleInput::parse("hex:a5 01"), |_| {})
+      .await
+      .unwrap();
+
+    assert_eq!(ack, Some(CMD_OK));
+    assert_eq!(*sent.lock().unwrap(), vec![ping.clone()]);
+    assert_eq!(mock.writes(), vec![ping, vec![0xa5, 0x01]]);
+  }
+}
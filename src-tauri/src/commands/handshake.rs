@@ -0,0 +1,97 @@
+use super::do_request_response;
+use super::identify::identify;
+use super::queue::Priority;
+use crate::protocol::Protocol;
+use crate::session::DeviceSession;
+
+// 固件接受该命令后，后续收发均改用带CRC的二进制帧
+pub(crate) const BINARY_PROTOCOL_CMD: &str = "proto crc16\r\n";
+const NEGOTIATE_WAIT: u64 = 1;
+
+/// Agrees on the wire protocol with a freshly attached device and asks it
+/// to identify itself.
+///
+/// Firmware that rejects or ignores either request keeps the text protocol
+/// or stays unidentified, so the handshake never fails a connection.
+pub async fn handshake(session: &DeviceSession) {
+  match do_request_response(
+    session,
+    BINARY_PROTOCOL_CMD,
+    NEGOTIATE_WAIT,
+    Priority::High,
+    None,
+  )
+  .await
+  {
+    Ok(()) => {
+      log::info!("Device accepted the binary frame protocol");
+      session.set_protocol(Protocol::Binary);
+    }
+    Err(e) => log::info!("Using text protocol: {}", e),
+  }
+  match identify(session).await {
+    Ok(info) => log::info!("Identified device: {:?}", info),
+    Err(e) => log::info!("Device did not identify itself: {}", e),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commands::{CMD_ERR, CMD_OK};
+  use crate::protocol::{Frame, FrameType};
+  use crate::transfer::mock::{MockTransfer, collector, received};
+  use std::sync::Arc;
+
+  fn ack(sequence: u8, code: u16, payload: &[u8]) -> Vec<u8> {
+    let mut data = code.to_le_bytes().to_vec();
+    data.extend_from_slice(payload);
+    Frame::new(FrameType::Ack, sequence, data).encode()
+  }
+
+  #[tokio::test]
+  async fn supported_firmware_switches_to_binary_frames() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on(BINARY_PROTOCOL_CMD, vec![CMD_OK.to_le_bytes().to_vec()]);
+    let info = br#"{"model":"V-2","hardware":"A","firmware":"2.0.0","capabilities":["valve"]}"#;
+    mock.on(
+      Frame::new(FrameType::Command, 0, "info").encode(),
+      vec![ack(0, CMD_OK, info)],
+    );
+    let ping = Frame::new(FrameType::Command, 1, "ping").encode();
+    // 模拟数据恰好以0xcafe开头，不应被误判为应答
+    let data = Frame::new(FrameType::Data, 0, CMD_OK.to_le_bytes().to_vec()).encode();
+    mock.on(&ping, vec![data, ack(1, CMD_OK, b"pong")]);
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+
+    handshake(&session).await;
+    assert_eq!(session.protocol(), Protocol::Binary);
+    assert_eq!(session.info().unwrap().model, "V-2");
+
+    let (mut rx, callback) = collector();
+    do_request_response(&session, "ping\r\n", 1, Priority::Normal, Some(callback))
+      .await
+      .unwrap();
+
+    assert_eq!(mock.writes()[2], ping);
+    assert_eq!(received(&mut rx).await, CMD_OK.to_le_bytes().to_vec());
+    assert_eq!(received(&mut rx).await, b"pong".to_vec());
+  }
+
+  #[tokio::test]
+  async fn rejecting_firmware_keeps_text() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on(BINARY_PROTOCOL_CMD, vec![CMD_ERR.to_le_bytes().to_vec()]);
+    mock.on("ping\r\n", vec![CMD_OK.to_le_bytes().to_vec()]);
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+
+    handshake(&session).await;
+
+    assert_eq!(session.protocol(), Protocol::Text);
+    do_request_response(&session, "ping\r\n", 1, Priority::Normal, None)
+      .await
+      .unwrap();
+  }
+}
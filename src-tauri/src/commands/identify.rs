@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "gui")]
+use tauri::State;
+
+use super::queue::Priority;
+use super::request_payload;
+use crate::error::Error;
+use crate::ota::image::FlashLayout;
+#[cfg(feature = "gui")]
+use crate::registry::DeviceRegistry;
+use crate::session::DeviceSession;
+
+const INFO_CMD: &str = "info\r\n";
+const INFO_WAIT: u64 = 1;
+/// Newest payload layout version this app can decode.
+pub const PROTOCOL_VERSION: u8 = 2;
+// 不上报版本的固件使用最初的数据布局
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+
+fn legacy_protocol_version() -> u8 {
+  LEGACY_PROTOCOL_VERSION
+}
+
+/// A command set the firmware implements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+  Valve,
+  Channel,
+  Airpressure,
+  Ota,
+  /// The bootloader can resume an interrupted update, see `SampleOta`.
+  OtaResume,
+  #[serde(other)]
+  Unknown,
+}
+
+/// What a device reports about itself in reply to `info`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+  pub model: String,
+  pub hardware: String,
+  pub firmware: String,
+  #[serde(default)]
+  pub serial: Option<String>,
+  /// Bootloader version, for firmware packages that need a newer one.
+  #[serde(default)]
+  pub bootloader: Option<String>,
+  /// Application flash area, for checking image addresses before OTA.
+  #[serde(default)]
+  pub flash: Option<FlashLayout>,
+  #[serde(default)]
+  pub capabilities: Vec<Capability>,
+  /// Payload layout version the firmware speaks.
+  #[serde(default = "legacy_protocol_version")]
+  pub protocol_version: u8,
+}
+
+impl DeviceInfo {
+  pub fn supports(&self, capability: Capability) -> bool {
+    self.capabilities.contains(&capability)
+  }
+
+  /// The version both sides understand. Newer firmware keeps the fields of
+  /// older layouts in place, so the app can read the prefix it knows.
+  pub fn negotiated_version(&self) -> u8 {
+    self.protocol_version.min(PROTOCOL_VERSION)
+  }
+}
+
+/// Asks the device what it is and remembers the answer in the session.
+///
+/// Firmware without `info` leaves the session unidentified, which keeps all
+/// command sets available as before.
+pub async fn identify(session: &DeviceSession) -> Result<DeviceInfo, Error> {
+  let data = request_payload(session, INFO_CMD, INFO_WAIT, Priority::High).await?;
+  let info: DeviceInfo = serde_json::from_slice(&data).map_err(|e| {
+    Error::invalid_data(format!("Invalid device info: {}", e)).with_command(INFO_CMD)
+  })?;
+  session.set_info(Some(info.clone()));
+  Ok(info)
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn device_info(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<Option<DeviceInfo>, Error> {
+  Ok(devices.session(&device)?.info())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commands::{CMD_ERR, CMD_OK};
+  use crate::error::ErrorKind;
+  use crate::transfer::mock::{MockTransfer, ack};
+  use std::sync::Arc;
+
+  #[tokio::test]
+  async fn identified_device_only_allows_its_command_sets() {
+    let mock = Arc::new(MockTransfer::new());
+    let reply = br#"{"model":"AP-1","hardware":"B","firmware":"1.4.0","serial":"0042","capabilities":["airpressure","ota","led"]}"#;
+    mock.on(INFO_CMD, vec![ack(CMD_OK, reply)]);
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+
+    let info = identify(&session).await.unwrap();
+
+    assert_eq!(info.model, "AP-1");
+    assert_eq!(session.protocol_version(), LEGACY_PROTOCOL_VERSION);
+    assert_eq!(
+      info.capabilities,
+      vec![
+        Capability::Airpressure,
+        Capability::Ota,
+        Capability::Unknown
+      ]
+    );
+    assert!(session.require(Capability::Airpressure).is_ok());
+    assert_eq!(
+      session.require(Capability::Valve).unwrap_err().kind,
+      ErrorKind::Unsupported
+    );
+
+    session.detach().await.unwrap();
+    assert_eq!(session.info(), None);
+  }
+
+  #[tokio::test]
+  async fn unidentified_device_allows_everything() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on(INFO_CMD, vec![ack(CMD_ERR, &[])]);
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+
+    assert_eq!(
+      identify(&session).await.unwrap_err().kind,
+      ErrorKind::Rejected
+    );
+    assert_eq!(session.info(), None);
+    assert!(session.require(Capability::Valve).is_ok());
+  }
+
+  #[tokio::test]
+  async fn newer_firmware_negotiates_down_to_app_version() {
+    let mock = Arc::new(MockTransfer::new());
+    let reply = br#"{"model":"V-3","hardware":"C","firmware":"3.0.0","capabilities":["valve"],"protocol_version":3}"#;
+    mock.on(INFO_CMD, vec![ack(CMD_OK, reply)]);
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+
+    let info = identify(&session).await.unwrap();
+
+    assert_eq!(info.protocol_version, 3);
+    assert_eq!(session.protocol_version(), PROTOCOL_VERSION);
+  }
+}
@@ -0,0 +1,32 @@
+use crate::error::Error;
+use crate::registry::DeviceRegistry;
+use crate::transfer::framing::Framing;
+use tauri::State;
+
+// 当前链路单次写入的最大字节数，BLE 链路在写入被拒后会自动降低
+#[tauri::command]
+pub async fn link_mtu(devices: State<'_, DeviceRegistry>, device: String) -> Result<usize, Error> {
+  let session = devices.session(&device)?;
+  Ok(session.transfer()?.get_mtu())
+}
+
+#[tauri::command]
+pub async fn link_framing(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<Framing, Error> {
+  let session = devices.session(&device)?;
+  Ok(session.framing())
+}
+
+// 设备的回复可能跨多个通知，按设备固件的分帧方式重组后再交给命令
+#[tauri::command]
+pub async fn set_link_framing(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  framing: Framing,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  log::info!("Link framing: {:?}", framing);
+  session.set_framing(framing)
+}
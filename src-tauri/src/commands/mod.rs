@@ -1,22 +1,40 @@
-use crate::transfer::Transfer;
+use crate::error::{Error, ErrorKind};
+use crate::session::{DeviceSession, FrameHandler};
 use bytemuck::{Pod, Zeroable};
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use queue::Priority;
+use serde::de::DeserializeOwned;
+#[cfg(feature = "gui")]
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
 use tokio::time::{Duration, timeout};
 
+#[cfg(feature = "gui")]
+pub mod airpressure_config;
+#[cfg(feature = "gui")]
+pub mod airpressure_info;
+#[cfg(feature = "gui")]
+pub mod channel_config;
+pub mod console;
+pub mod handshake;
+pub mod identify;
+#[cfg(feature = "gui")]
+pub mod link;
 pub mod ota;
+pub mod payload;
 pub mod ping;
+pub mod queue;
 pub mod reboot;
+#[cfg(feature = "gui")]
+pub mod reconnect;
+#[cfg(feature = "gui")]
 pub mod valve_config;
 pub mod valve_info;
-pub mod channel_config;
-pub mod airpressure_config;
-pub mod airpressure_info;
 
-const CMD_OK: u16 = 0xcafe;
-const CMD_ERR: u16 = 0xdead;
+pub(crate) const CMD_OK: u16 = 0xcafe;
+pub(crate) const CMD_ERR: u16 = 0xdead;
 
+#[cfg(feature = "gui")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ValveConfig {
   model: String,
@@ -31,11 +49,24 @@ pub struct ValveVal {
   current_status: u32,
 }
 
+/// Protocol v2 valve reading: v1 followed by the target position and the
+/// last fault code.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable, Serialize)]
+pub struct ValveValV2 {
+  total_ticks: i32,
+  current_status: u32,
+  target_ticks: i32,
+  fault_code: u32,
+}
+
+#[cfg(feature = "gui")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelConfig {
   model: String,
 }
 
+#[cfg(feature = "gui")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AirPressureConfig {
   model: String,
@@ -48,90 +79,331 @@ pub struct AirPressureVal {
   current_pressure: u16,
 }
 
-async fn do_request_response(
-  transfer: Arc<dyn Transfer>,
+pub(crate) async fn do_request_response(
+  session: &DeviceSession,
   command_str: &str,
   time_wait: u64,
-  keep_subscribe: bool,
-  callback: Option<Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>>,
-) -> Result<(), String> {
-  let (tx, mut rx) = mpsc::channel::<u16>(1);
-  transfer.unsubscribe().await.ok();
-  transfer
-    .subscribe(Arc::new(move |data: Vec<u8>| {
-      match data.as_slice() {
-        [b0, b1, rest @ ..] => {
-          // 捕获剩余数据
-          let response_code = u16::from_le_bytes([*b0, *b1]);
-          if response_code == CMD_OK || response_code == CMD_ERR {
-            let _ = tx.blocking_send(response_code);
-            // 如果有剩余数据，并且回调存在，则调用回调
-            if !rest.is_empty() {
-              if let Some(cb) = callback.clone() {
-                cb(rest.to_vec()); // 将剩余数据传递给回调
-              }
-            }
-          } else if let Some(cb) = callback.clone() {
-            cb(data); // 如果不是CMD_OK或CMD_ERR，则传递整个数据
-          }
-        }
-        _ => {
-          if let Some(cb) = callback.clone() {
-            cb(data);
-          }
-        }
-      }
-    }))
+  priority: Priority,
+  callback: Option<FrameHandler>,
+) -> Result<(), Error> {
+  // 同一时间只允许一个请求等待应答，避免并发命令互相抢占CMD_OK/CMD_ERR
+  let _slot = session
+    .queue()
+    .acquire(priority)
     .await
-    .map_err(|e| format!("Failed to subscirbe: {}", e))?;
+    .map_err(|e| e.with_command(command_str))?;
+  let transfer = session
+    .transfer()
+    .map_err(|e| e.with_command(command_str))?;
+  let (request, rx) = session.begin_request(command_str, callback);
 
-  if let Err(e) = transfer.send(command_str.as_bytes()).await {
-    if !keep_subscribe {
-      transfer.unsubscribe().await.ok();
-    }
-    return Err(format!("Failed to send {}: {}", command_str, e));
+  if let Err(e) = transfer.send(&request).await {
+    session.end_request();
+    return Err(e.context("Failed to send").with_command(command_str));
   }
 
-  let result = match timeout(Duration::from_secs(time_wait), rx.recv()).await {
-    Ok(Some(response_code)) => {
+  let result = match timeout(Duration::from_secs(time_wait), rx).await {
+    Ok(Ok(response_code)) => {
       if response_code == CMD_OK {
         log::info!("Successfully execuate command:{}", command_str);
         Ok(())
       } else if response_code == CMD_ERR {
         log::error!("Fail to execuate command:{}, receive CMD_ERR", command_str);
-        Err(format!("Received CMD_ERR"))
+        Err(Error::new(ErrorKind::Rejected, "Received CMD_ERR").with_command(command_str))
       } else {
         // This case should ideally be handled by the callback, but as a fallback
         // if it somehow reaches here, we'll treat it as an unknown response.
-        Err(format!("Received unknown response: {:?}", response_code))
+        Err(
+          Error::invalid_data(format!("Received unknown response: {:?}", response_code))
+            .with_command(command_str),
+        )
       }
     }
-    Ok(None) => {
+    Ok(Err(_)) => {
       log::error!(
         "Fail to execuate command:{}, without any response",
         command_str
       );
-      Err(format!(
-        "Channel closed before receiving response after {} seconds",
-        time_wait
-      ))
+      // 等待期间链路断开，会话已丢弃该请求
+      Err(
+        Error::new(
+          ErrorKind::NotConnected,
+          "Connection lost before receiving response",
+        )
+        .with_command(command_str),
+      )
     }
     Err(_) => {
       log::error!(
         "Fail to execuate command:{}, timeout {} without any response",
-        time_wait,
-        command_str
+        command_str,
+        time_wait
       );
-      Err(format!("Response timeout in {} seconds", time_wait))
+      Err(
+        Error::timeout(format!("Response timeout in {} seconds", time_wait))
+          .with_command(command_str),
+      )
     }
   };
 
-  if !keep_subscribe {
-    transfer
-      .unsubscribe()
+  session.end_request();
+  result
+}
+
+/// Sends `command_str` and returns the payload that came with its ack.
+pub(crate) async fn request_payload(
+  session: &DeviceSession,
+  command_str: &str,
+  time_wait: u64,
+  priority: Priority,
+) -> Result<Vec<u8>, Error> {
+  let reply = Arc::new(Mutex::new(None));
+  let sink = reply.clone();
+  do_request_response(
+    session,
+    command_str,
+    time_wait,
+    priority,
+    Some(Arc::new(move |data: Vec<u8>| {
+      *sink.lock().unwrap() = Some(data);
+    })),
+  )
+  .await?;
+  let payload = reply.lock().unwrap().take();
+  payload.ok_or_else(|| Error::invalid_data("Empty response").with_command(command_str))
+}
+
+pub(crate) async fn read_config<T: DeserializeOwned>(session: &DeviceSession) -> Result<T, Error> {
+  let data = request_payload(session, "config_read\r\n", 3, Priority::Normal).await?;
+  serde_json::from_slice(&data).map_err(|e| {
+    Error::invalid_data(format!("Failed to parse config: {}", e)).with_command("config_read")
+  })
+}
+
+pub(crate) async fn write_config<T: Serialize>(
+  session: &DeviceSession,
+  config: &T,
+) -> Result<(), Error> {
+  let payload = serde_json::to_string(config)
+    .inspect(|json| log::info!("Serialized JSON: {}", json))
+    .map(|json| format!("config_write {}\r\n", json)) // 拼接前缀
+    .map_err(|e| Error::invalid_data(format!("Serialization failed: {}", e)))?;
+
+  do_request_response(session, &payload, 3, Priority::Normal, None).await
+}
+
+// 数据流在请求前注册，确保ACK之后立即到达的数据不会丢失
+pub(crate) async fn start_stream(
+  session: &DeviceSession,
+  stream: &str,
+  command_str: &str,
+  time_wait: u64,
+  callback: FrameHandler,
+) -> Result<(), Error> {
+  session.add_stream(stream, command_str, callback);
+  do_request_response(session, command_str, time_wait, Priority::Normal, None)
+    .await
+    .inspect_err(|_| session.remove_stream(stream))
+}
+
+pub(crate) async fn stop_stream(
+  session: &DeviceSession,
+  stream: &str,
+  command_str: &str,
+  time_wait: u64,
+) -> Result<(), Error> {
+  let result = do_request_response(session, command_str, time_wait, Priority::High, None).await;
+  session.remove_stream(stream);
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transfer::framing::Framing;
+  use crate::transfer::mock::{MockTransfer, ack, collector, received};
+  use std::sync::Arc;
+
+  async fn attached(mock: &Arc<MockTransfer>) -> DeviceSession {
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    session
+  }
+
+  #[tokio::test]
+  async fn resolves_on_cmd_ok() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on("ping\r\n", vec![ack(CMD_OK, &[])]);
+    let session = attached(&mock).await;
+
+    do_request_response(&session, "ping\r\n", 1, Priority::Normal, None)
       .await
-      .map_err(|e| format!("Failed to unsubscribe: {}", e))?;
+      .unwrap();
+
+    assert_eq!(mock.writes(), vec![b"ping\r\n".to_vec()]);
+    assert!(mock.is_subscribed());
   }
 
-  result
+  #[tokio::test]
+  async fn reports_cmd_err() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on("reboot\r\n", vec![ack(CMD_ERR, &[])]);
+    let session = attached(&mock).await;
+
+    let result = do_request_response(&session, "reboot\r\n", 1, Priority::Normal, None).await;
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Rejected);
+    assert_eq!(error.message, "Received CMD_ERR");
+    assert_eq!(error.command.as_deref(), Some("reboot"));
+    assert!(!error.retryable);
+  }
+
+  #[tokio::test]
+  async fn times_out_without_response() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = attached(&mock).await;
+
+    let result = do_request_response(&session, "ping\r\n", 1, Priority::Normal, None).await;
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Timeout);
+    assert_eq!(error.message, "Response timeout in 1 seconds");
+    assert!(error.retryable);
+  }
+
+  #[tokio::test]
+  async fn send_failure_is_reported() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.fail_next_sends(1);
+    let session = attached(&mock).await;
+
+    let result = do_request_response(&session, "ping\r\n", 1, Priority::Normal, None).await;
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Transport);
+    assert!(error.message.starts_with("Failed to send"));
+    assert_eq!(error.command.as_deref(), Some("ping"));
+    assert!(mock.writes().is_empty());
+  }
+
+  #[tokio::test]
+  async fn concurrent_requests_keep_their_own_ack() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on("ping\r\n", vec![ack(CMD_OK, &[])]);
+    mock.on("reboot\r\n", vec![ack(CMD_ERR, &[])]);
+    let session = attached(&mock).await;
+
+    let (ping, reboot) = tokio::join!(
+      do_request_response(&session, "ping\r\n", 1, Priority::Normal, None),
+      do_request_response(&session, "reboot\r\n", 1, Priority::High, None),
+    );
+
+    assert_eq!(ping, Ok(()));
+    assert_eq!(reboot.unwrap_err().kind, ErrorKind::Rejected);
+    assert_eq!(mock.writes().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn fails_without_attached_device() {
+    let session = DeviceSession::new();
+
+    let result = do_request_response(&session, "ping\r\n", 1, Priority::Normal, None).await;
+
+    assert_eq!(result.unwrap_err().kind, ErrorKind::NotConnected);
+  }
+
+  #[tokio::test]
+  async fn payload_after_ack_goes_to_callback() {
+    let mock = Arc::new(MockTransfer::new());
+    let json = br#"{"model":"v1","tick":100,"dir":true}"#;
+    mock.on("config_read\r\n", vec![ack(CMD_OK, json)]);
+    let session = attached(&mock).await;
+    let (mut rx, callback) = collector();
+
+    do_request_response(
+      &session,
+      "config_read\r\n",
+      1,
+      Priority::Normal,
+      Some(callback),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(received(&mut rx).await, json.to_vec());
+  }
+
+  #[tokio::test]
+  async fn reply_spanning_notifications_is_reassembled() {
+    let mock = Arc::new(MockTransfer::new().with_framing(Framing::Delimited {
+      delimiter: b"\r\n".to_vec(),
+    }));
+    let json = br#"{"model":"v1","tick":100,"dir":true}"#;
+    let mut reply = ack(CMD_OK, json);
+    reply.extend_from_slice(b"\r\n");
+    mock.on(
+      "config_read\r\n",
+      reply.chunks(8).map(|chunk| chunk.to_vec()).collect(),
+    );
+    let session = attached(&mock).await;
+    let (mut rx, callback) = collector();
+
+    do_request_response(
+      &session,
+      "config_read\r\n",
+      1,
+      Priority::Normal,
+      Some(callback),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(received(&mut rx).await, json.to_vec());
+  }
+
+  #[tokio::test]
+  async fn stream_receives_notifications_until_stopped() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on("valve_info 1\r\n", vec![ack(CMD_OK, &[])]);
+    mock.on("valve_info 0\r\n", vec![ack(CMD_OK, &[])]);
+    let session = attached(&mock).await;
+    let (mut rx, callback) = collector();
+
+    start_stream(&session, "valve_info", "valve_info 1\r\n", 1, callback)
+      .await
+      .unwrap();
+    let sample = ValveVal {
+      total_ticks: -42,
+      current_status: 1,
+    };
+    mock.notify(bytemuck::bytes_of(&sample)).await;
+    mock.notify(bytemuck::bytes_of(&sample)).await;
+
+    assert!(mock.is_subscribed());
+    let decoded: ValveVal = *bytemuck::from_bytes(&received(&mut rx).await);
+    assert_eq!(decoded.total_ticks, -42);
+    let decoded: ValveVal = *bytemuck::from_bytes(&received(&mut rx).await);
+    assert_eq!(decoded.total_ticks, -42);
+
+    stop_stream(&session, "valve_info", "valve_info 0\r\n", 1)
+      .await
+      .unwrap();
+    mock.notify(bytemuck::bytes_of(&sample)).await;
+    assert!(rx.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn failed_stream_start_is_unregistered() {
+    let mock = Arc::new(MockTransfer::new());
+    mock.on("valve_info 1\r\n", vec![ack(CMD_ERR, &[])]);
+    let session = attached(&mock).await;
+    let (mut rx, callback) = collector();
+
+    let result = start_stream(&session, "valve_info", "valve_info 1\r\n", 1, callback).await;
+
+    assert_eq!(result.unwrap_err().kind, ErrorKind::Rejected);
+    mock.notify(vec![1, 2, 3]).await;
+    assert!(rx.try_recv().is_err());
+  }
 }
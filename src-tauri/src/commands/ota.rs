@@ -1,15 +1,240 @@
-use crate::{
-  ota::{Ota, sample::SampleOta},
-  transfer::ble::BleTransfer,
-};
+use super::queue::Priority;
+use crate::error::Error;
+#[cfg(feature = "gui")]
+use crate::error::ErrorKind;
+#[cfg(feature = "gui")]
+use crate::ota::image::DEFAULT_PAD_BYTE;
+#[cfg(feature = "gui")]
+use crate::ota::progress::{OtaProgressStore, device_key};
+use crate::ota::sample::{BOOTLOADER_START, SampleOta, UPDATE_CMD};
+#[cfg(feature = "gui")]
+use crate::ota::signing::TrustedKey;
+#[cfg(feature = "gui")]
+use crate::registry::{DeviceEvent, DeviceRegistry};
+use crate::session::DeviceSession;
+use crate::transfer::Transfer;
+#[cfg(feature = "gui")]
+use serde::Serialize;
 use std::sync::Arc;
+#[cfg(feature = "gui")]
+use tauri::{Emitter, Manager, State};
+#[cfg(feature = "gui")]
+use tauri_plugin_dialog::DialogExt;
+#[cfg(feature = "gui")]
+use tauri_plugin_fs::{FilePath, FsExt};
+#[cfg(feature = "gui")]
+use tokio::sync::oneshot;
 
+#[cfg(feature = "gui")]
+/// Percentage of an update, emitted as `ota_progress`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct OtaProgressEvent {
+  pub percentage: u32,
+}
+
+/// Opens the file picker and returns the chosen firmware, or `None` if the
+/// user cancelled.
+#[cfg(feature = "gui")]
 #[tauri::command]
-pub async fn start_valve_ota(app_handle: tauri::AppHandle) -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
+pub async fn pick_firmware(app_handle: tauri::AppHandle) -> Result<Option<String>, Error> {
+  let (tx, rx) = oneshot::channel();
+  app_handle.dialog().file().pick_file(move |file| {
+    let _ = tx.send(file);
+  });
+  let file = rx
     .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  let mut ota_impl = SampleOta::new(Arc::new(ble_transfer));
+    .map_err(|_| Error::new(ErrorKind::Cancelled, "File picker closed"))?;
+  Ok(file.map(|file| file.to_string()))
+}
+
+/// Runs the OTA that `build` sets up on the exclusive view of `session`.
+///
+/// The command queue is held until the update finishes or fails, so other
+/// commands wait instead of being written into the DFU data stream.
+pub(crate) async fn flash(
+  session: &DeviceSession,
+  build: impl FnOnce(Arc<dyn Transfer>) -> SampleOta,
+  image: Arc<Vec<u8>>,
+  on_progress: &(dyn Fn(u32) + Send + Sync),
+) -> Result<(), Error> {
+  let _slot = session.queue().acquire(Priority::High).await?;
+  let ota = build(session.exclusive()?);
+  // 检查都在 update 之前完成，被拒绝的镜像不会让设备停在引导程序里
+  let image = ota.prepare(&image)?;
+  enter_bootloader(session).await?;
+  ota.in_bootloader().start_prepared(image, on_progress).await
+}
+
+// update 需按当前协议成帧，二进制协议下的固件不认原始文本；设备随即重启，不会应答
+async fn enter_bootloader(session: &DeviceSession) -> Result<(), Error> {
+  let transfer = session.transfer()?;
+  let (request, _) = session.begin_request(UPDATE_CMD, None);
+  let sent = transfer.send(&request).await;
+  session.end_request();
+  sent.map_err(|e| e.context("Failed to send").with_command(UPDATE_CMD))?;
+  tokio::time::sleep(BOOTLOADER_START).await;
+  Ok(())
+}
+
+/// Flashes the firmware package at `path` or, if the frontend already
+/// loaded it, the bytes in `data`. Exactly one of them must be given.
+///
+/// An update of the same image that was interrupted earlier continues from
+/// the last block the bootloader wrote.
+///
+/// Without a trusted firmware key, unsigned `.bin`, `.hex`, `.srec` and
+/// `.elf` images are accepted too, with gaps filled with `pad`.
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn start_valve_ota(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  path: Option<FilePath>,
+  data: Option<Vec<u8>>,
+  pad: Option<u8>,
+  app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  let image = match (path, data) {
+    (Some(path), None) => {
+      log::debug!("Reading firmware from {}", path);
+      app_handle
+        .fs()
+        .read(path)
+        .map_err(|e| Error::new(ErrorKind::Io, format!("Failed to read file: {}", e)))?
+    }
+    (None, Some(data)) => data,
+    _ => {
+      return Err(Error::invalid_data(
+        "Give either a firmware path or its data",
+      ));
+    }
+  };
+  let info = session.info();
+  let progress_key = device_key(info.as_ref(), &device);
+  let store = app_handle.state::<Arc<OtaProgressStore>>().inner().clone();
+  let public_key = app_handle.state::<TrustedKey>().key()?;
+
+  let result = flash(
+    &session,
+    |transfer| {
+      SampleOta::new(transfer)
+        .with_progress(store, progress_key)
+        .with_device(info)
+        .with_public_key(public_key)
+        .with_pad_byte(pad.unwrap_or(DEFAULT_PAD_BYTE))
+    },
+    Arc::new(image),
+    &|percentage| {
+      let progress = DeviceEvent::new(&device, OtaProgressEvent { percentage });
+      if let Err(e) = app_handle.emit("ota_progress", progress) {
+        log::error!("Failed to emit OTA progress: {}", e);
+      }
+    },
+  )
+  .await;
+  if let Err(e) = &result
+    && let Err(emit_error) = app_handle.emit("ota_error", DeviceEvent::new(&device, e))
+  {
+    log::error!("Failed to emit OTA error: {}", emit_error);
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commands::do_request_response;
+  use crate::error::ErrorKind;
+  use crate::ota::sample::{DFU_PAGE_LEN, McuDfuState};
+  use crate::protocol::{Frame, FrameType, Protocol};
+  use crate::transfer::bootloader::SimBootloader;
+  use crate::transfer::mock::MockTransfer;
+  use std::time::Duration;
+
+  #[tokio::test]
+  async fn commands_wait_for_ota_to_finish() {
+    let bootloader = Arc::new(SimBootloader::new(180));
+    let session = Arc::new(DeviceSession::new());
+    session.attach(bootloader.clone()).await.unwrap();
+    let firmware: Arc<Vec<u8>> = Arc::new((0..DFU_PAGE_LEN * 8).map(|i| i as u8).collect());
+    let flashing = {
+      let session = session.clone();
+      let firmware = firmware.clone();
+      tokio::spawn(async move { flash(&session, SampleOta::new, firmware, &|_| {}).await })
+    };
+    while bootloader.state() != McuDfuState::Data {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // 升级中途发出的命令排队，不会写进块数据
+    let ping = {
+      let session = session.clone();
+      tokio::spawn(async move {
+        do_request_response(&session, "ping\r\n", 1, Priority::Normal, None).await
+      })
+    };
+    // 等 ping 进入队列，而不是赌固定的睡眠时长
+    while session.queue().depth().queued == 0 {
+      assert!(
+        !flashing.is_finished(),
+        "OTA finished before the ping was queued"
+      );
+      tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    flashing.await.unwrap().unwrap();
+    assert_eq!(bootloader.flashed(), *firmware);
+    // 引导程序不应答文本命令，升级结束后才发出的 ping 超时
+    assert_eq!(ping.await.unwrap().unwrap_err().kind, ErrorKind::Timeout);
+  }
+
+  #[tokio::test]
+  async fn update_command_follows_the_session_protocol() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    session.set_protocol(Protocol::Binary);
+
+    enter_bootloader(&session).await.unwrap();
+
+    let update = Frame::new(FrameType::Command, 0, "update").encode();
+    assert_eq!(mock.writes(), vec![update]);
+  }
+
+  #[tokio::test]
+  async fn rejected_image_never_enters_bootloader() {
+    use crate::ota::package::FirmwareTarget;
+    use crate::ota::signing::sign_package;
+    use ed25519_dalek::SigningKey;
+
+    let mock = Arc::new(MockTransfer::new());
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    let trusted = SigningKey::from_bytes(&[7; 32]).verifying_key();
+    let target = FirmwareTarget {
+      model: "V-2".to_string(),
+      hardware: "r1".to_string(),
+      version: "2.0.0".to_string(),
+      min_bootloader: "1.0.0".to_string(),
+    };
+    let foreign = sign_package(
+      target,
+      vec![0xab; DFU_PAGE_LEN],
+      &SigningKey::from_bytes(&[8; 32]),
+    );
+
+    for image in [foreign.to_bytes(), vec![0xab; DFU_PAGE_LEN]] {
+      let result = flash(
+        &session,
+        |transfer| SampleOta::new(transfer).with_public_key(Some(trusted)),
+        Arc::new(image),
+        &|_| {},
+      )
+      .await;
 
-  ota_impl.start_ota(app_handle).await
+      assert_eq!(result.unwrap_err().kind, ErrorKind::InvalidData);
+      assert!(mock.writes().is_empty());
+    }
+  }
 }
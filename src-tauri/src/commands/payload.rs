@@ -0,0 +1,114 @@
+use bytemuck::Pod;
+use serde::Serialize;
+
+#[cfg(feature = "gui")]
+use super::AirPressureVal;
+use super::{ValveVal, ValveValV2};
+use crate::error::Error;
+
+/// Reads a `T` from the front of `data`.
+///
+/// Newer firmware may append fields an older layout does not know about, so
+/// longer payloads are accepted and the extra bytes ignored.
+pub fn read_pod<T: Pod>(data: &[u8]) -> Result<T, Error> {
+  let size = std::mem::size_of::<T>();
+  if data.len() < size {
+    return Err(Error::invalid_data(format!(
+      "Payload too short. Expected at least {}, got {}",
+      size,
+      data.len()
+    )));
+  }
+  if data.len() > size {
+    log::debug!("Ignoring {} trailing payload bytes", data.len() - size);
+  }
+  Ok(bytemuck::pod_read_unaligned(&data[..size]))
+}
+
+/// A valve reading in the layout of the negotiated protocol version.
+///
+/// Fields added by later versions are `None` for devices speaking an older
+/// one and left out of the event payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ValveSample {
+  pub total_ticks: i32,
+  pub current_status: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub target_ticks: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub fault_code: Option<u32>,
+}
+
+impl ValveSample {
+  pub fn decode(version: u8, data: &[u8]) -> Result<Self, Error> {
+    match version {
+      0 | 1 => {
+        let val: ValveVal = read_pod(data)?;
+        Ok(ValveSample {
+          total_ticks: val.total_ticks,
+          current_status: val.current_status,
+          target_ticks: None,
+          fault_code: None,
+        })
+      }
+      _ => {
+        let val: ValveValV2 = read_pod(data)?;
+        Ok(ValveSample {
+          total_ticks: val.total_ticks,
+          current_status: val.current_status,
+          target_ticks: Some(val.target_ticks),
+          fault_code: Some(val.fault_code),
+        })
+      }
+    }
+  }
+}
+
+#[cfg(feature = "gui")]
+/// Air pressure readings have kept their layout across protocol versions.
+pub fn decode_airpressure(_version: u8, data: &[u8]) -> Result<AirPressureVal, Error> {
+  read_pod(data)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::ErrorKind;
+
+  fn v2_bytes() -> Vec<u8> {
+    [
+      (-42i32).to_le_bytes(),
+      1u32.to_le_bytes(),
+      100i32.to_le_bytes(),
+      7u32.to_le_bytes(),
+    ]
+    .concat()
+  }
+
+  #[test]
+  fn decodes_valve_layout_of_each_version() {
+    let v1 = ValveSample::decode(1, &v2_bytes()[..8]).unwrap();
+    assert_eq!((v1.total_ticks, v1.target_ticks), (-42, None));
+    assert_eq!(
+      serde_json::to_value(v1).unwrap(),
+      serde_json::json!({ "total_ticks": -42, "current_status": 1 })
+    );
+
+    let v2 = ValveSample::decode(2, &v2_bytes()).unwrap();
+    assert_eq!((v2.target_ticks, v2.fault_code), (Some(100), Some(7)));
+  }
+
+  #[test]
+  fn tolerates_appended_fields_but_not_truncation() {
+    // v1 主机收到 v2 固件的数据时只取已知字段
+    let v1 = ValveSample::decode(1, &v2_bytes()).unwrap();
+    assert_eq!(v1.current_status, 1);
+
+    let error = ValveSample::decode(2, &v2_bytes()[..8]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::InvalidData);
+    assert_eq!(
+      error.message,
+      "Payload too short. Expected at least 16, got 8"
+    );
+  }
+}
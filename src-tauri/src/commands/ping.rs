@@ -1,11 +1,18 @@
-use super::do_request_response;
-use crate::transfer::ble::BleTransfer;
-use std::sync::Arc;
+use super::{do_request_response, queue::Priority};
+use crate::error::Error;
+#[cfg(feature = "gui")]
+use crate::registry::DeviceRegistry;
+use crate::session::DeviceSession;
+#[cfg(feature = "gui")]
+use tauri::State;
 
+pub(crate) async fn ping_device(session: &DeviceSession) -> Result<(), Error> {
+  do_request_response(session, "ping\r\n", 3, Priority::Normal, None).await
+}
+
+#[cfg(feature = "gui")]
 #[tauri::command]
-pub async fn ping() -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(Arc::new(ble_transfer), "ping\r\n", 3, false, None).await
+pub async fn ping(devices: State<'_, DeviceRegistry>, device: String) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  ping_device(&session).await
 }
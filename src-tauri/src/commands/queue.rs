@@ -0,0 +1,327 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "gui")]
+use tauri::State;
+use tokio::sync::oneshot;
+
+use crate::error::{Error, ErrorKind};
+#[cfg(feature = "gui")]
+use crate::registry::DeviceRegistry;
+
+pub type DepthListener = Arc<dyn Fn(QueueDepth) + Send + Sync + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+  Normal,
+  /// Commands that must not wait behind routine traffic, such as `reboot`
+  /// and stopping a stream.
+  High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct QueueDepth {
+  pub queued: usize,
+  pub in_flight: bool,
+}
+
+struct Waiter {
+  id: u64,
+  priority: Priority,
+  wake: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct QueueState {
+  busy: bool,
+  next_id: u64,
+  waiting: Vec<Waiter>,
+}
+
+impl QueueState {
+  fn depth(&self) -> QueueDepth {
+    QueueDepth {
+      queued: self.waiting.len(),
+      in_flight: self.busy,
+    }
+  }
+
+  // 高优先级优先，同优先级按入队顺序
+  fn next_waiter(&mut self) -> Option<Waiter> {
+    let index = self
+      .waiting
+      .iter()
+      .enumerate()
+      .max_by(|(_, a), (_, b)| a.priority.cmp(&b.priority).then(b.id.cmp(&a.id)))
+      .map(|(index, _)| index)?;
+    Some(self.waiting.remove(index))
+  }
+}
+
+/// Admits one outstanding request per device.
+///
+/// The firmware answers with a bare `CMD_OK`/`CMD_ERR` and no correlation tag,
+/// so an acknowledgement can only be attributed to a request if nothing else
+/// is waiting for one. Requests wait here until the previous one resolved.
+#[derive(Default)]
+pub struct CommandQueue {
+  state: Mutex<QueueState>,
+  listener: Mutex<Option<DepthListener>>,
+}
+
+impl CommandQueue {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  #[cfg(feature = "gui")]
+  pub fn set_listener(&self, listener: DepthListener) {
+    *self.listener.lock().unwrap() = Some(listener);
+  }
+
+  pub fn depth(&self) -> QueueDepth {
+    self.state.lock().unwrap().depth()
+  }
+
+  fn notify(&self, depth: QueueDepth) {
+    let listener = self.listener.lock().unwrap().clone();
+    if let Some(listener) = listener {
+      listener(depth);
+    }
+  }
+
+  /// Waits for this request's turn. The returned slot keeps the device
+  /// reserved until it is dropped.
+  pub async fn acquire(&self, priority: Priority) -> Result<QueueSlot<'_>, Error> {
+    let (id, rx, depth) = {
+      let mut state = self.state.lock().unwrap();
+      if !state.busy {
+        state.busy = true;
+        let depth = state.depth();
+        drop(state);
+        self.notify(depth);
+        return Ok(QueueSlot { queue: self });
+      }
+      let id = state.next_id;
+      state.next_id += 1;
+      let (wake, rx) = oneshot::channel();
+      state.waiting.push(Waiter { id, priority, wake });
+      (id, rx, state.depth())
+    };
+    self.notify(depth);
+
+    let mut waiting = WaitingGuard {
+      queue: self,
+      id,
+      rx: Some(rx),
+    };
+    match waiting.rx.as_mut().unwrap().await {
+      Ok(()) => {
+        waiting.rx = None;
+        Ok(QueueSlot { queue: self })
+      }
+      Err(_) => {
+        waiting.rx = None;
+        Err(Error::new(ErrorKind::Cancelled, "Request cancelled"))
+      }
+    }
+  }
+
+  #[cfg(feature = "gui")]
+  /// Drops every request that is still waiting for its turn. The request
+  /// already on the wire is left alone.
+  pub fn cancel_queued(&self) -> usize {
+    let (cancelled, depth) = {
+      let mut state = self.state.lock().unwrap();
+      (std::mem::take(&mut state.waiting), state.depth())
+    };
+    if !cancelled.is_empty() {
+      log::info!("Cancelled {} queued requests", cancelled.len());
+      self.notify(depth);
+    }
+    cancelled.len()
+  }
+
+  fn release(&self) {
+    let depth = {
+      let mut state = self.state.lock().unwrap();
+      loop {
+        match state.next_waiter() {
+          // 等待者可能已被丢弃，交给下一个
+          Some(waiter) => {
+            if waiter.wake.send(()).is_ok() {
+              break;
+            }
+          }
+          None => {
+            state.busy = false;
+            break;
+          }
+        }
+      }
+      state.depth()
+    };
+    self.notify(depth);
+  }
+}
+
+pub struct QueueSlot<'a> {
+  queue: &'a CommandQueue,
+}
+
+impl Drop for QueueSlot<'_> {
+  fn drop(&mut self) {
+    self.queue.release();
+  }
+}
+
+// 请求在排队期间被丢弃时，移出队列；若已被唤醒则把名额交还
+struct WaitingGuard<'a> {
+  queue: &'a CommandQueue,
+  id: u64,
+  rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for WaitingGuard<'_> {
+  fn drop(&mut self) {
+    let Some(mut rx) = self.rx.take() else {
+      return;
+    };
+    let removed = {
+      let mut state = self.queue.state.lock().unwrap();
+      let before = state.waiting.len();
+      state.waiting.retain(|waiter| waiter.id != self.id);
+      before != state.waiting.len()
+    };
+    if removed {
+      self.queue.notify(self.queue.depth());
+    } else if rx.try_recv().is_ok() {
+      self.queue.release();
+    }
+  }
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn cancel_queued_commands(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<usize, Error> {
+  let session = devices.session(&device)?;
+  Ok(session.queue().cancel_queued())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  async fn settle() {
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+
+  #[tokio::test]
+  async fn high_priority_jumps_the_queue() {
+    let queue = Arc::new(CommandQueue::new());
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let first = queue.acquire(Priority::Normal).await.unwrap();
+
+    let mut tasks = Vec::new();
+    for (name, priority) in [
+      ("normal-1", Priority::Normal),
+      ("normal-2", Priority::Normal),
+      ("reboot", Priority::High),
+    ] {
+      let queue = queue.clone();
+      let order = order.clone();
+      tasks.push(tokio::spawn(async move {
+        let _slot = queue.acquire(priority).await.unwrap();
+        order.lock().unwrap().push(name);
+      }));
+      settle().await;
+    }
+    assert_eq!(queue.depth().queued, 3);
+
+    drop(first);
+    for task in tasks {
+      task.await.unwrap();
+    }
+    assert_eq!(
+      *order.lock().unwrap(),
+      vec!["reboot", "normal-1", "normal-2"]
+    );
+    assert_eq!(
+      queue.depth(),
+      QueueDepth {
+        queued: 0,
+        in_flight: false
+      }
+    );
+  }
+
+  #[cfg(feature = "gui")]
+  #[tokio::test]
+  async fn cancel_rejects_waiting_requests_only() {
+    let queue = Arc::new(CommandQueue::new());
+    let slot = queue.acquire(Priority::Normal).await.unwrap();
+    let waiting = {
+      let queue = queue.clone();
+      tokio::spawn(async move { queue.acquire(Priority::Normal).await.map(|_| ()) })
+    };
+    settle().await;
+
+    assert_eq!(queue.cancel_queued(), 1);
+    assert_eq!(
+      waiting.await.unwrap(),
+      Err(Error::new(ErrorKind::Cancelled, "Request cancelled"))
+    );
+    assert!(queue.depth().in_flight);
+    drop(slot);
+    assert!(!queue.depth().in_flight);
+  }
+
+  #[tokio::test]
+  async fn abandoned_waiter_does_not_block_the_queue() {
+    let queue = Arc::new(CommandQueue::new());
+    let slot = queue.acquire(Priority::Normal).await.unwrap();
+    let abandoned = {
+      let queue = queue.clone();
+      tokio::spawn(async move { queue.acquire(Priority::Normal).await.map(|_| ()) })
+    };
+    settle().await;
+    abandoned.abort();
+    let _ = abandoned.await;
+    assert_eq!(queue.depth().queued, 0);
+
+    drop(slot);
+    tokio::time::timeout(Duration::from_secs(1), queue.acquire(Priority::Normal))
+      .await
+      .expect("queue stayed busy")
+      .unwrap();
+  }
+
+  #[cfg(feature = "gui")]
+  #[tokio::test]
+  async fn depth_changes_are_reported() {
+    let queue = Arc::new(CommandQueue::new());
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let sink = reports.clone();
+    queue.set_listener(Arc::new(move |depth| sink.lock().unwrap().push(depth)));
+
+    let slot = queue.acquire(Priority::Normal).await.unwrap();
+    drop(slot);
+
+    assert_eq!(
+      *reports.lock().unwrap(),
+      vec![
+        QueueDepth {
+          queued: 0,
+          in_flight: true
+        },
+        QueueDepth {
+          queued: 0,
+          in_flight: false
+        },
+      ]
+    );
+  }
+}
@@ -0,0 +1,332 @@
+use serde::Serialize;
+use std::time::Duration;
+#[cfg(feature = "gui")]
+use tauri::{Emitter, Manager};
+
+use super::do_request_response;
+use super::handshake::handshake;
+use super::queue::Priority;
+use crate::error::Error;
+#[cfg(feature = "gui")]
+use crate::registry::{DeviceEvent, DeviceRegistry};
+use crate::session::{DeviceSession, LostLink};
+#[cfg(feature = "gui")]
+use crate::transfer::ble::BleDevice;
+
+const STREAM_RESTORE_WAIT: u64 = 3;
+
+pub struct ReconnectPolicy {
+  pub initial_delay: Duration,
+  pub max_delay: Duration,
+  pub max_attempts: usize,
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    ReconnectPolicy {
+      initial_delay: Duration::from_millis(500),
+      max_delay: Duration::from_secs(8),
+      max_attempts: 6,
+    }
+  }
+}
+
+/// Progress of a reconnect, emitted to the frontend as `link_state`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LinkState {
+  Reconnecting { attempt: usize, delay_ms: u64 },
+  Restored { streams: Vec<String> },
+  GivenUp { error: Error },
+}
+
+/// Reconnects `lost` with exponential backoff and restarts its streams.
+///
+/// Stops early without reporting if the session is attached or detached in
+/// the meantime. Returns whether the link was restored.
+pub async fn supervise(
+  session: &DeviceSession,
+  lost: &LostLink,
+  policy: &ReconnectPolicy,
+  report: impl Fn(LinkState),
+) -> bool {
+  let mut delay = policy.initial_delay;
+  let mut last_error = Error::not_connected();
+  for attempt in 1..=policy.max_attempts {
+    if !session.is_current(lost) {
+      return false;
+    }
+    report(LinkState::Reconnecting {
+      attempt,
+      delay_ms: delay.as_millis() as u64,
+    });
+    tokio::time::sleep(delay).await;
+    if !session.is_current(lost) {
+      return false;
+    }
+    match reconnect(session, lost).await {
+      Ok(streams) => {
+        report(LinkState::Restored { streams });
+        return true;
+      }
+      Err(e) => {
+        log::warn!("Reconnect attempt {} failed: {}", attempt, e);
+        last_error = e;
+      }
+    }
+    delay = (delay * 2).min(policy.max_delay);
+  }
+  session.abandon(lost);
+  report(LinkState::GivenUp { error: last_error });
+  false
+}
+
+async fn reconnect(session: &DeviceSession, lost: &LostLink) -> Result<Vec<String>, Error> {
+  lost.transfer.activate().await?;
+  session.restore(lost).await?;
+  handshake(session).await;
+
+  let mut restored = Vec::new();
+  for (name, command) in session.streams() {
+    match do_request_response(
+      session,
+      &command,
+      STREAM_RESTORE_WAIT,
+      Priority::Normal,
+      None,
+    )
+    .await
+    {
+      Ok(()) => restored.push(name),
+      Err(e) => {
+        // 单个数据流无法恢复不影响链路本身
+        log::warn!("Failed to restore stream {}: {}", name, e);
+        session.remove_stream(&name);
+      }
+    }
+  }
+  Ok(restored)
+}
+
+#[cfg(feature = "gui")]
+/// Handles a link that dropped on its own: tells the frontend and starts a
+/// supervisor that reconnects in the background.
+///
+/// Nothing is supervised while an OTA holds the exclusive view, since its
+/// retries disconnect and reconnect the link on purpose.
+pub fn link_lost(app_handle: &tauri::AppHandle, device: BleDevice) {
+  let address = device.address().to_string();
+  let lost = app_handle
+    .state::<DeviceRegistry>()
+    .session(&address)
+    .ok()
+    .and_then(|session| Some((session.link_lost()?, session)));
+  let _ = app_handle.emit("ble_status", device.clone().connected(false));
+  let Some((lost, session)) = lost else {
+    return;
+  };
+  let app_handle = app_handle.clone();
+  tauri::async_runtime::spawn(async move {
+    let restored = supervise(&session, &lost, &ReconnectPolicy::default(), |state| {
+      log::info!("Link state of {}: {:?}", address, state);
+      let _ = app_handle.emit("link_state", DeviceEvent::new(&address, state));
+    })
+    .await;
+    if restored {
+      let _ = app_handle.emit("ble_status", device.connected(true));
+    } else if session.is_current(&lost) {
+      app_handle
+        .state::<DeviceRegistry>()
+        .forget(&address, &session);
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commands::handshake::BINARY_PROTOCOL_CMD;
+  use crate::commands::ota::flash;
+  use crate::commands::{CMD_ERR, CMD_OK};
+  use crate::ota::sample::{DFU_ACK_PATTERN, DFU_PAGE_LEN, SampleOta};
+  use crate::transfer::Transfer;
+  use crate::transfer::bootloader::SimBootloader;
+  use crate::transfer::mock::MockTransfer;
+  use async_trait::async_trait;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::{Arc, Mutex};
+
+  fn policy() -> ReconnectPolicy {
+    ReconnectPolicy {
+      initial_delay: Duration::from_millis(1),
+      max_delay: Duration::from_millis(4),
+      max_attempts: 4,
+    }
+  }
+
+  async fn streaming_session(mock: &Arc<MockTransfer>) -> DeviceSession {
+    mock.on(BINARY_PROTOCOL_CMD, vec![CMD_ERR.to_le_bytes().to_vec()]);
+    mock.on("valve_info 1\r\n", vec![CMD_OK.to_le_bytes().to_vec()]);
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    session.add_stream("valve_info", "valve_info 1\r\n", Arc::new(|_| {}));
+    session
+  }
+
+  fn recorder() -> (Arc<Mutex<Vec<LinkState>>>, impl Fn(LinkState)) {
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let sink = states.clone();
+    (states, move |state| sink.lock().unwrap().push(state))
+  }
+
+  #[tokio::test]
+  async fn restores_link_and_restarts_streams() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = streaming_session(&mock).await;
+    mock.deactivate().await.unwrap();
+    let lost = session.link_lost().unwrap();
+    mock.fail_next_activations(1);
+    let (states, report) = recorder();
+
+    assert!(supervise(&session, &lost, &policy(), report).await);
+
+    assert!(mock.is_subscribed());
+    assert!(mock.writes().contains(&b"valve_info 1\r\n".to_vec()));
+    assert_eq!(
+      *states.lock().unwrap(),
+      vec![
+        LinkState::Reconnecting {
+          attempt: 1,
+          delay_ms: 1
+        },
+        LinkState::Reconnecting {
+          attempt: 2,
+          delay_ms: 2
+        },
+        LinkState::Restored {
+          streams: vec!["valve_info".to_string()]
+        },
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn gives_up_after_max_attempts() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = streaming_session(&mock).await;
+    let lost = session.link_lost().unwrap();
+    mock.fail_next_activations(usize::MAX);
+    let (states, report) = recorder();
+
+    assert!(!supervise(&session, &lost, &policy(), report).await);
+
+    let states = states.lock().unwrap();
+    let delays: Vec<u64> = states
+      .iter()
+      .filter_map(|state| match state {
+        LinkState::Reconnecting { delay_ms, .. } => Some(*delay_ms),
+        _ => None,
+      })
+      .collect();
+    assert_eq!(delays, vec![1, 2, 4, 4]);
+    assert!(matches!(states.last(), Some(LinkState::GivenUp { .. })));
+    assert!(session.streams().is_empty());
+  }
+
+  #[tokio::test]
+  async fn stops_when_user_detaches() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = streaming_session(&mock).await;
+    let lost = session.link_lost().unwrap();
+    session.detach().await.unwrap();
+    let (states, report) = recorder();
+
+    assert!(!supervise(&session, &lost, &policy(), report).await);
+    assert!(states.lock().unwrap().is_empty());
+  }
+
+  // 像 BLE 一样在断开时回调，并让第一个状态应答写入失败以触发 OTA 重试
+  struct DroppingLink {
+    bootloader: SimBootloader,
+    failed: AtomicBool,
+    on_disconnect: Mutex<Option<Box<dyn Fn() + Send + Sync>>>,
+  }
+
+  #[async_trait]
+  impl Transfer for DroppingLink {
+    fn get_mtu(&self) -> usize {
+      self.bootloader.get_mtu()
+    }
+
+    async fn activate(&self) -> Result<(), Error> {
+      self.bootloader.activate().await
+    }
+
+    async fn deactivate(&self) -> Result<(), Error> {
+      self.bootloader.deactivate().await?;
+      if let Some(on_disconnect) = &*self.on_disconnect.lock().unwrap() {
+        on_disconnect();
+      }
+      Ok(())
+    }
+
+    async fn is_actived(&self) -> Result<bool, Error> {
+      self.bootloader.is_actived().await
+    }
+
+    async fn send(&self, data: &[u8]) -> Result<(), Error> {
+      if data == DFU_ACK_PATTERN.to_le_bytes() && !self.failed.swap(true, Ordering::SeqCst) {
+        return Err(Error::transport("Link dropped"));
+      }
+      self.bootloader.send(data).await
+    }
+
+    async fn read(&self) -> Result<Vec<u8>, Error> {
+      self.bootloader.read().await
+    }
+
+    async fn subscribe(
+      &self,
+      callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+    ) -> Result<(), Error> {
+      self.bootloader.subscribe(callback).await
+    }
+
+    async fn unsubscribe(&self) -> Result<(), Error> {
+      self.bootloader.unsubscribe().await
+    }
+  }
+
+  #[tokio::test]
+  async fn ota_retry_is_not_supervised() {
+    let link = Arc::new(DroppingLink {
+      bootloader: SimBootloader::new(180),
+      failed: AtomicBool::new(false),
+      on_disconnect: Mutex::new(None),
+    });
+    let session = Arc::new(DeviceSession::new());
+    session.attach(link.clone()).await.unwrap();
+    let (states, report) = recorder();
+    let report = Arc::new(report);
+    *link.on_disconnect.lock().unwrap() = Some(Box::new({
+      let session = session.clone();
+      move || {
+        if let Some(lost) = session.link_lost() {
+          let session = session.clone();
+          let report = report.clone();
+          tokio::spawn(async move { supervise(&session, &lost, &policy(), &*report).await });
+        }
+      }
+    }));
+    let firmware: Arc<Vec<u8>> = Arc::new((0..DFU_PAGE_LEN * 2).map(|i| i as u8).collect());
+
+    flash(&session, SampleOta::new, firmware.clone(), &|_| {})
+      .await
+      .unwrap();
+
+    assert!(link.failed.load(Ordering::SeqCst));
+    assert_eq!(link.bootloader.flashed(), *firmware);
+    assert!(states.lock().unwrap().is_empty());
+    assert!(session.transfer().is_ok());
+  }
+}
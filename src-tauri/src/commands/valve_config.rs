@@ -1,96 +1,79 @@
-use super::{ValveConfig, ValveVal, do_request_response};
-use crate::transfer::ble::BleTransfer;
-use std::sync::Arc;
-use tauri::Emitter;
+use super::identify::Capability;
+use super::valve_info::valve_sample_handler;
+use super::{
+  ValveConfig, do_request_response, queue::Priority, read_config, start_stream, stop_stream,
+  write_config,
+};
+use crate::error::Error;
+use crate::registry::{DeviceEvent, DeviceRegistry};
+use tauri::{Emitter, State};
 
 #[tauri::command]
-pub async fn valve_configure(config: ValveConfig) -> Result<(), String> {
-  let payload = serde_json::to_string(&config)
-    .inspect(|json| log::info!("Serialized JSON: {}", json))
-    .map(|json| format!("config_write {}\r\n", json)) // 拼接前缀
-    .map_err(|e| format!("Serialization failed: {}", e))?;
-
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(Arc::new(ble_transfer), &payload, 3, false, None).await
+pub async fn valve_configure(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  config: ValveConfig,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Valve)?;
+  write_config(&session, &config).await
 }
 
 #[tauri::command]
-pub async fn valve_readconfig(app_handle: tauri::AppHandle) -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
-    "config_read\r\n",
-    3,
-    false,
-    Some(Arc::new(
-      move |data: Vec<u8>| match serde_json::from_slice::<ValveConfig>(&data) {
-        Ok(valve_config) => {
-          log::debug!("Valve config: {:?}", valve_config);
-          if let Err(e) = app_handle.emit("valve_config", valve_config) {
-            log::error!("Failed to emit valve config: {}", e);
-          }
-        }
-        Err(e) => {
-          log::error!("Failed to parse valve config: {}", e);
-        }
-      },
-    )),
-  )
-  .await
+pub async fn valve_readconfig(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Valve)?;
+  let valve_config: ValveConfig = read_config(&session).await?;
+  log::debug!("Valve config: {:?}", valve_config);
+  if let Err(e) = app_handle.emit("valve_config", DeviceEvent::new(&device, valve_config)) {
+    log::error!("Failed to emit valve config: {}", e);
+  }
+  Ok(())
 }
 
 #[tauri::command]
-pub async fn valve_refactory() -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
-    "config_refactory\r\n",
-    3,
-    false,
-    None,
-  )
-  .await
+pub async fn valve_refactory(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Valve)?;
+  do_request_response(&session, "config_refactory\r\n", 3, Priority::Normal, None).await
 }
 
 #[tauri::command]
-pub async fn valve_tuning_start(app_handle: tauri::AppHandle) -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
+pub async fn valve_tuning_start(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Valve)?;
+  start_stream(
+    &session,
+    "valve_tuning",
     "valve_tuning 1\r\n",
     3,
-    true,
-    Some(Arc::new(move |data: Vec<u8>| {
-      if data.len() != std::mem::size_of::<ValveVal>() {
-        log::error!(
-          "Received data length mismatch. Expected {}, got {}",
-          std::mem::size_of::<ValveVal>(),
-          data.len()
-        );
-        return;
-      }
-      let valve_info: ValveVal = *bytemuck::from_bytes::<ValveVal>(&data);
+    valve_sample_handler(&session, move |valve_info| {
       log::debug!("Valve Info: {:?}", valve_info);
-      if let Err(e) = app_handle.emit("valve_tuning", valve_info) {
+      if let Err(e) = app_handle.emit("valve_tuning", DeviceEvent::new(&device, valve_info)) {
         log::error!("Failed to emit valve info: {}", e);
       }
-    })),
+    }),
   )
   .await
 }
 
 #[tauri::command]
-pub async fn valve_tuning_stop() -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(Arc::new(ble_transfer), "valve_tuning 0\r\n", 3, false, None).await
+pub async fn valve_tuning_stop(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  session.require(Capability::Valve)?;
+  stop_stream(&session, "valve_tuning", "valve_tuning 0\r\n", 3).await
 }
@@ -1,41 +1,78 @@
-use super::{ValveVal, do_request_response};
-use crate::transfer::ble::BleTransfer;
+use super::identify::{Capability, LEGACY_PROTOCOL_VERSION};
+use super::payload::ValveSample;
+use super::{start_stream, stop_stream};
+use crate::error::Error;
+#[cfg(feature = "gui")]
+use crate::registry::{DeviceEvent, DeviceRegistry};
+use crate::session::{DeviceSession, FrameHandler};
 use std::sync::Arc;
-use tauri::Emitter;
+#[cfg(feature = "gui")]
+use tauri::{Emitter, State};
 
-#[tauri::command]
-pub async fn start_valve_info(app_handle: tauri::AppHandle) -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(
-    Arc::new(ble_transfer),
+/// Decodes valve readings in the layout the session negotiated when each
+/// one arrives.
+pub(crate) fn valve_sample_handler(
+  session: &Arc<DeviceSession>,
+  on_sample: impl Fn(ValveSample) + Send + Sync + 'static,
+) -> FrameHandler {
+  // 持有弱引用，链路重连后按重新协商的版本解码
+  let session_ref = Arc::downgrade(session);
+  Arc::new(move |data: Vec<u8>| {
+    let version = session_ref
+      .upgrade()
+      .map_or(LEGACY_PROTOCOL_VERSION, |session| {
+        session.protocol_version()
+      });
+    match ValveSample::decode(version, &data) {
+      Ok(sample) => on_sample(sample),
+      Err(e) => log::error!("Failed to decode valve info: {}", e),
+    }
+  })
+}
+
+pub(crate) async fn start(
+  session: &Arc<DeviceSession>,
+  on_sample: impl Fn(ValveSample) + Send + Sync + 'static,
+) -> Result<(), Error> {
+  session.require(Capability::Valve)?;
+  start_stream(
+    session,
+    "valve_info",
     "valve_info 1\r\n",
     3,
-    true,
-    Some(Arc::new(move |data: Vec<u8>| {
-      if data.len() != std::mem::size_of::<ValveVal>() {
-        log::error!(
-          "Received data length mismatch. Expected {}, got {}",
-          std::mem::size_of::<ValveVal>(),
-          data.len()
-        );
-        return;
-      }
-      let valve_info: ValveVal = *bytemuck::from_bytes::<ValveVal>(&data);
-      log::debug!("Valve Info: {:?}", valve_info);
-      if let Err(e) = app_handle.emit("valve_info", valve_info) {
-        log::error!("Failed to emit valve info: {}", e);
-      }
-    })),
+    valve_sample_handler(session, on_sample),
   )
   .await
 }
 
+pub(crate) async fn stop(session: &DeviceSession) -> Result<(), Error> {
+  session.require(Capability::Valve)?;
+  stop_stream(session, "valve_info", "valve_info 0\r\n", 3).await
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn start_valve_info(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+  app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  start(&session, move |valve_info| {
+    log::debug!("Valve Info: {:?}", valve_info);
+    if let Err(e) = app_handle.emit("valve_info", DeviceEvent::new(&device, valve_info)) {
+      log::error!("Failed to emit valve info: {}", e);
+    }
+  })
+  .await
+}
+
+#[cfg(feature = "gui")]
 #[tauri::command]
-pub async fn stop_valve_info() -> Result<(), String> {
-  let ble_transfer = BleTransfer::new()
-    .await
-    .map_err(|e| format!("Create BLE Transfer failed: {}", e))?;
-  do_request_response(Arc::new(ble_transfer), "valve_info 0\r\n", 3, false, None).await
+pub async fn stop_valve_info(
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let session = devices.session(&device)?;
+  stop(&session).await
 }
@@ -0,0 +1,125 @@
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+  /// No device is attached, or the link dropped.
+  NotConnected,
+  /// The BLE adapter or plugin could not be used at all.
+  Unavailable,
+  /// A write, read or subscription failed on the link.
+  Transport,
+  /// The device did not answer in time.
+  Timeout,
+  /// The device answered `CMD_ERR`.
+  Rejected,
+  /// A single write was larger than the link MTU.
+  MtuExceeded,
+  /// The user or the command queue abandoned the operation.
+  Cancelled,
+  /// Data could not be encoded, decoded or understood.
+  InvalidData,
+  /// Reading or writing a local file failed.
+  Io,
+  /// The MCU reported a fault during an update.
+  DeviceFault,
+  /// The device does not implement the requested command set.
+  Unsupported,
+  /// The only link of its kind is already in use by another device.
+  Busy,
+}
+
+impl ErrorKind {
+  // 瞬时性错误重试即可能成功，其余需要用户或设备侧处理
+  pub fn is_retryable(self) -> bool {
+    matches!(self, ErrorKind::Transport | ErrorKind::Timeout)
+  }
+}
+
+/// Error returned by transfers, commands and OTA, serialized to the frontend
+/// as `{ kind, message, command, retryable }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Error {
+  pub kind: ErrorKind,
+  pub message: String,
+  pub command: Option<String>,
+  pub retryable: bool,
+}
+
+impl Error {
+  pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+    Error {
+      kind,
+      message: message.into(),
+      command: None,
+      retryable: kind.is_retryable(),
+    }
+  }
+
+  pub fn not_connected() -> Self {
+    Error::new(ErrorKind::NotConnected, "Device not connected")
+  }
+
+  pub fn transport(message: impl Into<String>) -> Self {
+    Error::new(ErrorKind::Transport, message)
+  }
+
+  pub fn timeout(message: impl Into<String>) -> Self {
+    Error::new(ErrorKind::Timeout, message)
+  }
+
+  pub fn invalid_data(message: impl Into<String>) -> Self {
+    Error::new(ErrorKind::InvalidData, message)
+  }
+
+  pub fn mtu_exceeded(len: usize, mtu: usize) -> Self {
+    Error::new(
+      ErrorKind::MtuExceeded,
+      format!("Data size {} exceeds MTU limit of {}", len, mtu),
+    )
+  }
+
+  /// Records the protocol command this error belongs to, without the line ending.
+  pub fn with_command(mut self, command: &str) -> Self {
+    self.command = Some(command.trim_end().to_string());
+    self
+  }
+
+  /// Prefixes the message with what was being attempted, keeping the kind.
+  pub fn context(mut self, context: impl fmt::Display) -> Self {
+    self.message = format!("{}: {}", context, self.message);
+    self
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.command {
+      Some(command) => write!(f, "{} ({})", self.message, command),
+      None => f.write_str(&self.message),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn serializes_as_object_for_the_frontend() {
+    let error = Error::timeout("Response timeout in 3 seconds").with_command("ping\r\n");
+
+    assert_eq!(
+      serde_json::to_value(&error).unwrap(),
+      serde_json::json!({
+        "kind": "timeout",
+        "message": "Response timeout in 3 seconds",
+        "command": "ping",
+        "retryable": true,
+      })
+    );
+  }
+}
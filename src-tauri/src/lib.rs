@@ -1,14 +1,24 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
-use fern::colors::{Color, ColoredLevelConfig};
+#[cfg(feature = "gui")]
 use log::LevelFilter;
-use std::{path::PathBuf, vec};
-use tauri::Manager;
+#[cfg(all(feature = "gui", target_os = "android"))]
+use std::path::PathBuf;
+#[cfg(feature = "gui")]
+use std::sync::Arc;
+#[cfg(feature = "gui")]
+use tauri::{Emitter, Manager};
 
+pub mod cli;
 mod commands;
+mod error;
 mod ota;
+mod protocol;
+#[cfg(feature = "gui")]
+mod registry;
+mod session;
 mod transfer;
 
-#[cfg(target_os = "android")]
+#[cfg(all(feature = "gui", target_os = "android"))]
 fn default_log_targets() -> Vec<tauri_plugin_log::Target> {
   vec![tauri_plugin_log::Target::new(tauri_plugin_log::TargetKind::Folder {
     path: PathBuf::from("/sdcard/Documents/com.bluetooth.tool/logs"),
@@ -16,7 +26,7 @@ fn default_log_targets() -> Vec<tauri_plugin_log::Target> {
   })]
 }
 
-#[cfg(not(target_os = "android"))]
+#[cfg(all(feature = "gui", not(target_os = "android")))]
 fn default_log_targets() -> Vec<tauri_plugin_log::Target> {
   vec![
     tauri_plugin_log::Target::new(tauri_plugin_log::TargetKind::Stdout),
@@ -24,15 +34,77 @@ fn default_log_targets() -> Vec<tauri_plugin_log::Target> {
   ]
 }
 
+#[cfg(feature = "gui")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
   tauri::Builder::default()
+    .manage(registry::DeviceRegistry::new())
+    .setup(|app| {
+      let config_dir = app
+        .path()
+        .app_config_dir()
+        .inspect_err(|e| log::error!("No config dir, settings will not be saved: {}", e))
+        .ok();
+      let config_file = |name: &str| config_dir.as_ref().map(|dir| dir.join(name));
+      app.manage(
+        config_file("gatt_profiles.json").map_or_else(
+          transfer::profile::ProfileRegistry::new,
+          transfer::profile::ProfileRegistry::load,
+        ),
+      );
+      app.manage(
+        config_file("console_history.json").map_or_else(
+          commands::console::ConsoleHistory::new,
+          commands::console::ConsoleHistory::load,
+        ),
+      );
+      app.manage(
+        config_file("firmware_key.pub")
+          .map_or_else(ota::signing::TrustedKey::none, ota::signing::TrustedKey::load),
+      );
+      app.manage(Arc::new(config_file("ota_progress.json").map_or_else(
+        ota::progress::OtaProgressStore::new,
+        ota::progress::OtaProgressStore::load,
+      )));
+      let app_handle = app.handle().clone();
+      app
+        .state::<registry::DeviceRegistry>()
+        .set_queue_listener(Arc::new(move |address, depth| {
+          let _ = app_handle.emit("command_queue", registry::DeviceEvent::new(address, depth));
+        }));
+      Ok(())
+    })
     .invoke_handler(tauri::generate_handler![
       transfer::ble::connect,
       transfer::ble::disconnect,
+      transfer::ble::scan,
+      transfer::ble::stop_scan,
+      transfer::profile::list_gatt_profiles,
+      transfer::profile::save_gatt_profile,
+      transfer::profile::remove_gatt_profile,
+      transfer::serial::list_serial_ports,
+      transfer::serial::connect_serial,
+      transfer::serial::disconnect_serial,
+      transfer::tcp::connect_tcp,
+      transfer::tcp::disconnect_tcp,
+      transfer::sim::connect_simulator,
+      transfer::sim::disconnect_simulator,
+      transfer::sim::simulator_inject_fault,
+      transfer::sim::simulator_clear_faults,
+      registry::connected_devices,
+      commands::identify::device_info,
+      commands::console::console_open,
+      commands::console::console_close,
+      commands::console::console_send,
+      commands::console::console_history,
+      commands::link::link_framing,
+      commands::link::link_mtu,
+      commands::link::set_link_framing,
+      commands::ota::pick_firmware,
       commands::ota::start_valve_ota,
       commands::ping::ping,
       commands::reboot::reboot_valve,
+      commands::queue::cancel_queued_commands,
       commands::valve_config::valve_configure,
       commands::valve_config::valve_readconfig,
       commands::valve_config::valve_refactory,
@@ -0,0 +1,414 @@
+//! Firmware images as build systems emit them: raw binary, Intel HEX,
+//! Motorola S-record or ELF. Addressed formats are flattened into the
+//! contiguous byte stream `SampleOta` sends block by block.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind};
+
+/// Byte that erased flash reads as, used for gaps between segments.
+pub const DEFAULT_PAD_BYTE: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+  Binary,
+  IntelHex,
+  SRecord,
+  Elf,
+}
+
+impl ImageFormat {
+  pub fn detect(data: &[u8]) -> Self {
+    match data {
+      [0x7f, b'E', b'L', b'F', ..] => ImageFormat::Elf,
+      [b':', ..] => ImageFormat::IntelHex,
+      [b'S', b'0'..=b'9', ..] => ImageFormat::SRecord,
+      _ => ImageFormat::Binary,
+    }
+  }
+}
+
+/// Application area of the device flash, as reported in `info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlashLayout {
+  /// Address the bootloader writes the first block to.
+  pub start: u32,
+  pub size: u32,
+}
+
+impl FlashLayout {
+  /// Checks that `len` bytes loaded at `load_address` fit the application
+  /// area. Images without an address are assumed to start at `start`.
+  pub fn check(&self, load_address: Option<u32>, len: usize) -> Result<(), Error> {
+    let out_of_range = |message: String| Error::new(ErrorKind::Unsupported, message);
+    let start = load_address.unwrap_or(self.start);
+    // 引导程序按块顺序从应用区起始地址写入，镜像必须从这里开始
+    if start != self.start {
+      return Err(out_of_range(format!(
+        "Image starts at {:#010x}, device flash starts at {:#010x}",
+        start, self.start
+      )));
+    }
+    if len as u64 > self.size as u64 {
+      return Err(out_of_range(format!(
+        "Image is {} bytes, device flash holds {}",
+        len, self.size
+      )));
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+  address: u32,
+  data: Vec<u8>,
+}
+
+/// A firmware image reduced to the bytes to flash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlatImage {
+  /// Flash address of the first byte, unknown for raw binaries.
+  pub load_address: Option<u32>,
+  pub data: Vec<u8>,
+}
+
+impl FlatImage {
+  /// Parses `data` in whatever format it is in. Addressed images start at
+  /// `base`, or at their lowest address if not given, with gaps filled
+  /// with `pad`.
+  pub fn load(data: &[u8], base: Option<u32>, pad: u8) -> Result<Self, Error> {
+    let segments = match ImageFormat::detect(data) {
+      ImageFormat::Binary => {
+        return Ok(FlatImage {
+          load_address: base,
+          data: data.to_vec(),
+        });
+      }
+      ImageFormat::IntelHex => parse_intel_hex(text(data)?)?,
+      ImageFormat::SRecord => parse_srecord(text(data)?)?,
+      ImageFormat::Elf => parse_elf(data)?,
+    };
+    flatten(segments, base, pad)
+  }
+}
+
+fn invalid(format: &str, message: impl std::fmt::Display) -> Error {
+  Error::invalid_data(format!("Invalid {}: {}", format, message))
+}
+
+fn text(data: &[u8]) -> Result<&str, Error> {
+  std::str::from_utf8(data).map_err(|_| Error::invalid_data("Firmware text is not ASCII"))
+}
+
+fn flatten(mut segments: Vec<Segment>, base: Option<u32>, pad: u8) -> Result<FlatImage, Error> {
+  segments.retain(|segment| !segment.data.is_empty());
+  segments.sort_by_key(|segment| segment.address);
+  let Some(lowest) = segments.first().map(|segment| segment.address) else {
+    return Err(Error::invalid_data("Firmware image has no data"));
+  };
+  let base = base.unwrap_or(lowest);
+  if lowest < base {
+    return Err(Error::new(
+      ErrorKind::Unsupported,
+      format!(
+        "Image data at {:#010x} lies below the start address {:#010x}",
+        lowest, base
+      ),
+    ));
+  }
+  let mut image = Vec::new();
+  for segment in segments {
+    let offset = (segment.address - base) as usize;
+    if offset < image.len() {
+      return Err(Error::invalid_data(format!(
+        "Overlapping firmware data at {:#010x}",
+        segment.address
+      )));
+    }
+    image.resize(offset, pad);
+    image.extend_from_slice(&segment.data);
+  }
+  Ok(FlatImage {
+    load_address: Some(base),
+    data: image,
+  })
+}
+
+fn hex_bytes(format: &str, line: &str) -> Result<Vec<u8>, Error> {
+  hex::decode(line).map_err(|e| invalid(format, e))
+}
+
+// 相邻记录地址连续时并入同一段，减少段数
+fn push_data(segments: &mut Vec<Segment>, address: u32, data: &[u8]) {
+  match segments.last_mut() {
+    Some(last) if last.address as u64 + last.data.len() as u64 == address as u64 => {
+      last.data.extend_from_slice(data);
+    }
+    _ => segments.push(Segment {
+      address,
+      data: data.to_vec(),
+    }),
+  }
+}
+
+fn parse_intel_hex(text: &str) -> Result<Vec<Segment>, Error> {
+  const FORMAT: &str = "Intel HEX";
+  let mut segments = Vec::new();
+  let mut upper: u32 = 0;
+  for (number, line) in text
+    .lines()
+    .enumerate()
+    .map(|(i, line)| (i + 1, line.trim()))
+  {
+    if line.is_empty() {
+      continue;
+    }
+    let record = line
+      .strip_prefix(':')
+      .ok_or_else(|| invalid(FORMAT, format!("line {} does not start with ':'", number)))?;
+    let bytes = hex_bytes(FORMAT, record)?;
+    let [len, addr_hi, addr_lo, kind, rest @ ..] = bytes.as_slice() else {
+      return Err(invalid(FORMAT, format!("line {} is too short", number)));
+    };
+    if rest.len() != *len as usize + 1 {
+      return Err(invalid(
+        FORMAT,
+        format!("line {} has the wrong length", number),
+      ));
+    }
+    if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+      return Err(invalid(
+        FORMAT,
+        format!("checksum error on line {}", number),
+      ));
+    }
+    let data = &rest[..*len as usize];
+    let offset = u16::from_be_bytes([*addr_hi, *addr_lo]) as u32;
+    match (kind, data) {
+      (0x00, _) => push_data(&mut segments, upper.wrapping_add(offset), data),
+      (0x01, _) => return Ok(segments),
+      (0x02, [hi, lo]) => upper = (u16::from_be_bytes([*hi, *lo]) as u32) << 4,
+      (0x04, [hi, lo]) => upper = (u16::from_be_bytes([*hi, *lo]) as u32) << 16,
+      // 起始地址记录与烧录内容无关
+      (0x03 | 0x05, _) => {}
+      _ => {
+        return Err(invalid(
+          FORMAT,
+          format!("unsupported record type {:02x} on line {}", kind, number),
+        ));
+      }
+    }
+  }
+  Err(invalid(FORMAT, "missing end of file record"))
+}
+
+fn parse_srecord(text: &str) -> Result<Vec<Segment>, Error> {
+  const FORMAT: &str = "S-record";
+  let mut segments = Vec::new();
+  for (number, line) in text
+    .lines()
+    .enumerate()
+    .map(|(i, line)| (i + 1, line.trim()))
+  {
+    if line.is_empty() {
+      continue;
+    }
+    let (kind, record) = match line.as_bytes() {
+      [b'S', kind @ b'0'..=b'9', ..] => (kind - b'0', &line[2..]),
+      _ => {
+        return Err(invalid(
+          FORMAT,
+          format!("line {} does not start with S0-S9", number),
+        ));
+      }
+    };
+    let bytes = hex_bytes(FORMAT, record)?;
+    let Some((count, rest)) = bytes.split_first() else {
+      return Err(invalid(FORMAT, format!("line {} is too short", number)));
+    };
+    if rest.len() != *count as usize {
+      return Err(invalid(
+        FORMAT,
+        format!("line {} has the wrong length", number),
+      ));
+    }
+    if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+      return Err(invalid(
+        FORMAT,
+        format!("checksum error on line {}", number),
+      ));
+    }
+    let address_len = match kind {
+      1 => 2,
+      2 => 3,
+      3 => 4,
+      7..=9 => return Ok(segments),
+      // 头部和计数记录不含数据
+      _ => continue,
+    };
+    let body = &rest[..rest.len() - 1];
+    if body.len() < address_len {
+      return Err(invalid(FORMAT, format!("line {} is too short", number)));
+    }
+    let (address, data) = body.split_at(address_len);
+    let address = address
+      .iter()
+      .fold(0u32, |address, b| (address << 8) | *b as u32);
+    push_data(&mut segments, address, data);
+  }
+  // 没有结束记录的文件也很常见，按已读内容处理
+  Ok(segments)
+}
+
+fn parse_elf(data: &[u8]) -> Result<Vec<Segment>, Error> {
+  const FORMAT: &str = "ELF";
+  const PT_LOAD: u32 = 1;
+  let truncated = || invalid(FORMAT, "file is truncated");
+  let bytes = |offset: usize, len: usize| data.get(offset..offset + len).ok_or_else(truncated);
+  let is_64 = match data.get(4) {
+    Some(1) => false,
+    Some(2) => true,
+    _ => return Err(invalid(FORMAT, "unknown class")),
+  };
+  if data.get(5) != Some(&1) {
+    return Err(invalid(FORMAT, "only little endian files are supported"));
+  }
+  let word = |offset: usize| -> Result<u64, Error> {
+    Ok(if is_64 {
+      u64::from_le_bytes(bytes(offset, 8)?.try_into().unwrap())
+    } else {
+      u32::from_le_bytes(bytes(offset, 4)?.try_into().unwrap()) as u64
+    })
+  };
+  let half = |offset: usize| -> Result<usize, Error> {
+    Ok(u16::from_le_bytes(bytes(offset, 2)?.try_into().unwrap()) as usize)
+  };
+  let u32_at = |offset: usize| -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(bytes(offset, 4)?.try_into().unwrap()))
+  };
+
+  let (ph_offset, ph_size, ph_count) = if is_64 {
+    (word(0x20)? as usize, half(0x36)?, half(0x38)?)
+  } else {
+    (word(0x1c)? as usize, half(0x2a)?, half(0x2c)?)
+  };
+  let mut segments = Vec::new();
+  for index in 0..ph_count {
+    let header = ph_offset + index * ph_size;
+    if u32_at(header)? != PT_LOAD {
+      continue;
+    }
+    // 使用物理地址（LMA），初始化数据段在 flash 中的位置与运行地址不同
+    let (offset, paddr, file_size) = if is_64 {
+      (
+        word(header + 0x08)?,
+        word(header + 0x18)?,
+        word(header + 0x20)?,
+      )
+    } else {
+      (
+        word(header + 0x04)?,
+        word(header + 0x0c)?,
+        word(header + 0x10)?,
+      )
+    };
+    if file_size == 0 {
+      continue;
+    }
+    let address = u32::try_from(paddr)
+      .map_err(|_| invalid(FORMAT, format!("address {:#x} too large", paddr)))?;
+    segments.push(Segment {
+      address,
+      data: bytes(offset as usize, file_size as usize)?.to_vec(),
+    });
+  }
+  Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn flattens_intel_hex_and_srecord_with_padding() {
+    let hex = "\
+:020000040800F2
+:0400000001020304F2
+:02000800AABB91
+:04000005080001BD31
+:00000001FF
+";
+    let image = FlatImage::load(hex.as_bytes(), None, 0x00).unwrap();
+    assert_eq!(image.load_address, Some(0x0800_0000));
+    assert_eq!(image.data, [1, 2, 3, 4, 0, 0, 0, 0, 0xaa, 0xbb]);
+
+    let srec = "\
+S00600004844521B
+S3090800000001020304E4
+S30708000008AABB83
+S70508000000F2
+";
+    assert_eq!(FlatImage::load(srec.as_bytes(), None, 0x00).unwrap(), image);
+    let padded = FlatImage::load(srec.as_bytes(), Some(0x07ff_fffe), DEFAULT_PAD_BYTE).unwrap();
+    assert_eq!(padded.data[..4], [0xff, 0xff, 1, 2]);
+
+    let corrupt = hex.replace(":02000800AABB91", ":02000800AABB92");
+    assert_eq!(
+      FlatImage::load(corrupt.as_bytes(), None, 0)
+        .unwrap_err()
+        .message,
+      "Invalid Intel HEX: checksum error on line 3"
+    );
+  }
+
+  // 最小的 ELF32：一个 PT_LOAD 段和一个不加载的段
+  fn elf32(paddr: u32, payload: &[u8]) -> Vec<u8> {
+    let mut elf = vec![0u8; 0x34 + 2 * 0x20];
+    elf[..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1]);
+    elf[0x1c..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+    elf[0x2a..0x2c].copy_from_slice(&0x20u16.to_le_bytes());
+    elf[0x2c..0x2e].copy_from_slice(&2u16.to_le_bytes());
+    let load = 0x34;
+    elf[load..load + 4].copy_from_slice(&1u32.to_le_bytes());
+    elf[load + 0x04..load + 0x08].copy_from_slice(&(0x74u32).to_le_bytes());
+    elf[load + 0x08..load + 0x0c].copy_from_slice(&0x2000_0000u32.to_le_bytes());
+    elf[load + 0x0c..load + 0x10].copy_from_slice(&paddr.to_le_bytes());
+    elf[load + 0x10..load + 0x14].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    let note = 0x54;
+    elf[note..note + 4].copy_from_slice(&4u32.to_le_bytes());
+    elf.extend_from_slice(payload);
+    elf
+  }
+
+  #[test]
+  fn loads_elf_segments_at_physical_address() {
+    let elf = elf32(0x0800_4000, &[9, 8, 7]);
+    let image = FlatImage::load(&elf, None, DEFAULT_PAD_BYTE).unwrap();
+    assert_eq!(image.load_address, Some(0x0800_4000));
+    assert_eq!(image.data, [9, 8, 7]);
+
+    assert_eq!(
+      FlatImage::load(&elf[..0x60], None, 0).unwrap_err().message,
+      "Invalid ELF: file is truncated"
+    );
+  }
+
+  #[test]
+  fn checks_image_against_flash_layout() {
+    let layout = FlashLayout {
+      start: 0x0800_4000,
+      size: 0x100,
+    };
+    layout.check(Some(0x0800_4000), 0x100).unwrap();
+    layout.check(None, 16).unwrap();
+    assert_eq!(
+      layout.check(Some(0x0800_0000), 16).unwrap_err().message,
+      "Image starts at 0x08000000, device flash starts at 0x08004000"
+    );
+    assert_eq!(
+      layout.check(Some(0x0800_4000), 0x101).unwrap_err().kind,
+      ErrorKind::Unsupported
+    );
+  }
+}
@@ -1,7 +1,22 @@
+pub mod image;
+pub mod package;
+pub mod progress;
 pub mod sample;
+pub mod signing;
 
 use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::error::Error;
+
+// 应用与命令行都经 prepare 和 start_prepared 分步升级，以便在进入引导程序前完成检查
+#[allow(dead_code)]
 #[async_trait]
 pub trait Ota {
-  async fn start_ota(&mut self, app_handle: tauri::AppHandle) -> Result<(), String>;
+  /// Flashes `image`, reporting progress in percent through `on_progress`.
+  async fn start_ota(
+    &mut self,
+    image: Arc<Vec<u8>>,
+    on_progress: &(dyn Fn(u32) + Send + Sync),
+  ) -> Result<(), Error>;
 }
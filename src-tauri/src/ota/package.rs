@@ -0,0 +1,289 @@
+//! Firmware package: a JSON manifest followed by the raw image.
+//!
+//! Layout, integers little endian:
+//!
+//! | bytes | content                          |
+//! |-------|----------------------------------|
+//! | 4     | [`PACKAGE_MAGIC`]                |
+//! | 4     | manifest length `n`              |
+//! | n     | [`Manifest`] as UTF-8 JSON       |
+//! | rest  | image, `Manifest::image_size` long |
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::sample::DFU_PAGE_LEN;
+use crate::commands::identify::DeviceInfo;
+use crate::error::{Error, ErrorKind};
+
+pub const PACKAGE_MAGIC: [u8; 4] = *b"CFWP";
+/// Length of one block signature, as carried in the DFU block header.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Which devices a package is built for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareTarget {
+  /// Must equal the model the device reports in `info`.
+  pub model: String,
+  /// Must equal the hardware revision the device reports.
+  pub hardware: String,
+  /// Version of the firmware in the package.
+  pub version: String,
+  /// Oldest bootloader that can flash the image.
+  pub min_bootloader: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+  #[serde(flatten)]
+  pub target: FirmwareTarget,
+  pub image_size: usize,
+  /// SHA-256 of the image, hex encoded.
+  pub image_sha256: String,
+  /// Hex encoded signature of every `DFU_PAGE_LEN` block of the image.
+  pub signatures: Vec<String>,
+  /// Flash address of the first image byte, if the image was built from an
+  /// addressed format.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub load_address: Option<u32>,
+}
+
+/// A parsed and integrity checked firmware package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwarePackage {
+  pub manifest: Manifest,
+  pub image: Vec<u8>,
+  pub signatures: Vec<[u8; SIGNATURE_LEN]>,
+}
+
+fn invalid(message: impl std::fmt::Display) -> Error {
+  Error::invalid_data(format!("Invalid firmware package: {}", message))
+}
+
+// 按数字逐段比较版本号，如 1.10.0 > 1.9.3
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+  version
+    .trim_start_matches('v')
+    .split('.')
+    .map(|part| part.parse().ok())
+    .collect()
+}
+
+impl FirmwarePackage {
+  /// Packs `image` with its block signatures.
+  pub fn new(target: FirmwareTarget, image: Vec<u8>, signatures: Vec<[u8; SIGNATURE_LEN]>) -> Self {
+    FirmwarePackage {
+      manifest: Manifest {
+        target,
+        image_size: image.len(),
+        image_sha256: hex::encode(Sha256::digest(&image)),
+        signatures: signatures.iter().map(hex::encode).collect(),
+        load_address: None,
+      },
+      image,
+      signatures,
+    }
+  }
+
+  pub fn with_load_address(mut self, load_address: Option<u32>) -> Self {
+    self.manifest.load_address = load_address;
+    self
+  }
+
+  pub fn block_count(&self) -> usize {
+    self.image.len().div_ceil(DFU_PAGE_LEN)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let manifest = serde_json::to_vec(&self.manifest).unwrap_or_default();
+    let mut bytes = Vec::with_capacity(8 + manifest.len() + self.image.len());
+    bytes.extend_from_slice(&PACKAGE_MAGIC);
+    bytes.extend_from_slice(&(manifest.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&manifest);
+    bytes.extend_from_slice(&self.image);
+    bytes
+  }
+
+  /// Parses `data` and checks the image against the manifest's size, hash
+  /// and signature count.
+  pub fn parse(data: &[u8]) -> Result<Self, Error> {
+    let Some((magic, rest)) = data.split_first_chunk::<4>() else {
+      return Err(invalid("file too short"));
+    };
+    if *magic != PACKAGE_MAGIC {
+      return Err(invalid("not a firmware package"));
+    }
+    let Some((len, rest)) = rest.split_first_chunk::<4>() else {
+      return Err(invalid("file too short"));
+    };
+    let len = u32::from_le_bytes(*len) as usize;
+    if rest.len() < len {
+      return Err(invalid("manifest truncated"));
+    }
+    let (manifest, image) = rest.split_at(len);
+    let manifest: Manifest = serde_json::from_slice(manifest).map_err(invalid)?;
+
+    if image.is_empty() || image.len() != manifest.image_size {
+      return Err(invalid(format!(
+        "image is {} bytes, manifest says {}",
+        image.len(),
+        manifest.image_size
+      )));
+    }
+    if hex::encode(Sha256::digest(image)) != manifest.image_sha256.to_lowercase() {
+      return Err(invalid("image hash mismatch"));
+    }
+    let signatures = manifest
+      .signatures
+      .iter()
+      .map(|signature| {
+        hex::decode(signature)
+          .ok()
+          .and_then(|bytes| <[u8; SIGNATURE_LEN]>::try_from(bytes).ok())
+          .ok_or_else(|| invalid("malformed block signature"))
+      })
+      .collect::<Result<Vec<_>, Error>>()?;
+    let package = FirmwarePackage {
+      manifest,
+      image: image.to_vec(),
+      signatures,
+    };
+    if package.signatures.len() != package.block_count() {
+      return Err(invalid(format!(
+        "{} signatures for {} blocks",
+        package.signatures.len(),
+        package.block_count()
+      )));
+    }
+    Ok(package)
+  }
+
+  /// Refuses packages built for another model, hardware revision, flash
+  /// area or a newer bootloader than `device` has.
+  pub fn check_target(&self, device: &DeviceInfo) -> Result<(), Error> {
+    let target = &self.manifest.target;
+    let mismatch = |what: &str, package: &str, device: &str| {
+      Error::new(
+        ErrorKind::Unsupported,
+        format!(
+          "Firmware is for {} {}, device has {}",
+          what, package, device
+        ),
+      )
+    };
+    if target.model != device.model {
+      return Err(mismatch("model", &target.model, &device.model));
+    }
+    if target.hardware != device.hardware {
+      return Err(mismatch("hardware", &target.hardware, &device.hardware));
+    }
+    if let Some(flash) = &device.flash {
+      flash.check(self.manifest.load_address, self.image.len())?;
+    }
+    let Some(bootloader) = &device.bootloader else {
+      log::warn!("Device does not report its bootloader, skipping version check");
+      return Ok(());
+    };
+    let required = parse_version(&target.min_bootloader)
+      .ok_or_else(|| invalid(format!("bad version {}", target.min_bootloader)))?;
+    match parse_version(bootloader) {
+      Some(current) if current >= required => Ok(()),
+      _ => Err(mismatch(
+        "bootloader",
+        &format!(">= {}", target.min_bootloader),
+        bootloader,
+      )),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn target() -> FirmwareTarget {
+    FirmwareTarget {
+      model: "SIM-VALVE".to_string(),
+      hardware: "sim".to_string(),
+      version: "1.5.0".to_string(),
+      min_bootloader: "1.2.0".to_string(),
+    }
+  }
+
+  fn package(image_len: usize) -> FirmwarePackage {
+    let image = vec![0x42; image_len];
+    let signatures = (0..image_len.div_ceil(DFU_PAGE_LEN))
+      .map(|i| [i as u8; SIGNATURE_LEN])
+      .collect();
+    FirmwarePackage::new(target(), image, signatures)
+  }
+
+  fn device(model: &str, bootloader: Option<&str>) -> DeviceInfo {
+    serde_json::from_value(serde_json::json!({
+      "model": model,
+      "hardware": "sim",
+      "firmware": "1.4.0",
+      "bootloader": bootloader,
+    }))
+    .unwrap()
+  }
+
+  #[test]
+  fn round_trips_and_rejects_tampering() {
+    let package = package(DFU_PAGE_LEN + 1);
+    let bytes = package.to_bytes();
+    assert_eq!(FirmwarePackage::parse(&bytes).unwrap(), package);
+
+    let mut tampered = bytes.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(
+      FirmwarePackage::parse(&tampered).unwrap_err().message,
+      "Invalid firmware package: image hash mismatch"
+    );
+    assert_eq!(
+      FirmwarePackage::parse(&bytes[..bytes.len() - 1])
+        .unwrap_err()
+        .message,
+      format!(
+        "Invalid firmware package: image is {} bytes, manifest says {}",
+        DFU_PAGE_LEN,
+        DFU_PAGE_LEN + 1
+      )
+    );
+
+    let mut unsigned = package.clone();
+    unsigned.manifest.signatures.pop();
+    assert_eq!(
+      FirmwarePackage::parse(&unsigned.to_bytes())
+        .unwrap_err()
+        .message,
+      "Invalid firmware package: 1 signatures for 2 blocks"
+    );
+    assert!(FirmwarePackage::parse(b"\x7fELF....").is_err());
+  }
+
+  #[test]
+  fn checks_model_and_bootloader_version() {
+    let package = package(16);
+    package
+      .check_target(&device("SIM-VALVE", Some("1.10.0")))
+      .unwrap();
+    package.check_target(&device("SIM-VALVE", None)).unwrap();
+
+    let error = package
+      .check_target(&device("SIM-CHANNEL", Some("1.2.0")))
+      .unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Unsupported);
+    assert_eq!(
+      error.message,
+      "Firmware is for model SIM-VALVE, device has SIM-CHANNEL"
+    );
+    assert_eq!(
+      package
+        .check_target(&device("SIM-VALVE", Some("1.1.9")))
+        .unwrap_err()
+        .message,
+      "Firmware is for bootloader >= 1.2.0, device has 1.1.9"
+    );
+  }
+}
@@ -0,0 +1,121 @@
+//! How far an interrupted OTA got, kept on disk so a later attempt, even
+//! after an app restart, only sends the blocks that are missing.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::commands::identify::DeviceInfo;
+use crate::error::{Error, ErrorKind};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OtaProgress {
+  /// SHA-256 of the flat image being sent, hex encoded.
+  pub image_sha256: String,
+  pub total_blocks: usize,
+  /// Blocks the bootloader confirmed as written.
+  pub confirmed_blocks: usize,
+}
+
+/// Unfinished updates by device.
+pub struct OtaProgressStore {
+  entries: RwLock<HashMap<String, OtaProgress>>,
+  path: Option<PathBuf>,
+}
+
+/// Key for a device in the store: its serial number if known, since the
+/// address of a serial port or BLE device can change between attempts.
+pub fn device_key(info: Option<&DeviceInfo>, address: &str) -> String {
+  info
+    .and_then(|info| info.serial.clone())
+    .unwrap_or_else(|| address.to_string())
+}
+
+impl OtaProgressStore {
+  pub fn new() -> Self {
+    OtaProgressStore {
+      entries: RwLock::new(HashMap::new()),
+      path: None,
+    }
+  }
+
+  /// Loads the store from `path`, which is also where it is saved.
+  pub fn load(path: PathBuf) -> Self {
+    let entries = match std::fs::read(&path) {
+      Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+        log::error!("Ignoring invalid OTA progress in {:?}: {}", path, e);
+        HashMap::new()
+      }),
+      Err(_) => HashMap::new(),
+    };
+    OtaProgressStore {
+      entries: RwLock::new(entries),
+      path: Some(path),
+    }
+  }
+
+  pub fn get(&self, device: &str) -> Option<OtaProgress> {
+    self.entries.read().unwrap().get(device).cloned()
+  }
+
+  pub fn set(&self, device: &str, progress: OtaProgress) -> Result<(), Error> {
+    self
+      .entries
+      .write()
+      .unwrap()
+      .insert(device.to_string(), progress);
+    self.persist()
+  }
+
+  pub fn remove(&self, device: &str) -> Result<(), Error> {
+    if self.entries.write().unwrap().remove(device).is_none() {
+      return Ok(());
+    }
+    self.persist()
+  }
+
+  fn persist(&self) -> Result<(), Error> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+    let io_error =
+      |e: std::io::Error| Error::new(ErrorKind::Io, format!("Failed to save OTA progress: {}", e));
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let json = serde_json::to_vec(&*self.entries.read().unwrap())
+      .map_err(|e| Error::invalid_data(format!("Serialization failed: {}", e)))?;
+    std::fs::write(path, json).map_err(io_error)
+  }
+}
+
+impl Default for OtaProgressStore {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn survives_reload_until_removed() {
+    let path = std::env::temp_dir().join(format!("ota-progress-{}.json", std::process::id()));
+    let progress = OtaProgress {
+      image_sha256: "ab".repeat(32),
+      total_blocks: 5,
+      confirmed_blocks: 3,
+    };
+    OtaProgressStore::load(path.clone())
+      .set("SN42", progress.clone())
+      .unwrap();
+
+    let store = OtaProgressStore::load(path.clone());
+    assert_eq!(store.get("SN42"), Some(progress));
+    store.remove("SN42").unwrap();
+    assert_eq!(OtaProgressStore::load(path.clone()).get("SN42"), None);
+    std::fs::remove_file(&path).ok();
+  }
+}
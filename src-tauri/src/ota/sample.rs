@@ -1,16 +1,26 @@
+use crate::commands::identify::{Capability, DeviceInfo};
+use crate::error::{Error, ErrorKind};
+use crate::ota::image::{DEFAULT_PAD_BYTE, FlatImage};
+use crate::ota::package::{FirmwarePackage, PACKAGE_MAGIC, SIGNATURE_LEN};
+use crate::ota::progress::{OtaProgress, OtaProgressStore};
+use crate::ota::signing::verify_package;
 use crate::{ota::Ota, transfer::Transfer};
 use async_trait::async_trait;
+use ed25519_dalek::VerifyingKey;
 use log::{debug, error, info, warn};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{io::Read, sync::Arc};
-use tauri::Emitter;
-use tauri_plugin_dialog::DialogExt;
-use tauri_plugin_fs::{FsExt, OpenOptions};
 use tokio::{sync::watch, time::Instant};
 
 pub const DFU_PAGE_LEN: usize = 2048;
 pub const DFU_PREAMBLE: [u8; 4] = [0xAA, 0x55, 0xAA, 0x55];
 pub const DFU_ACK_PATTERN: u32 = 0x12345678; // 示例ACK模式，实际应根据MCU协议定义
+pub const UPDATE_CMD: &str = "update\r\n";
+// 续传请求的回复：Header 状态字节加小端的已写入块数
+const RESUME_REPORT_LEN: usize = 1 + 4;
+// 固件收到 update 后重启进入 bootloader 所需的时间
+pub const BOOTLOADER_START: Duration = Duration::from_secs(1);
 
 // DFU状态枚举，对应Mermaid图
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -74,6 +84,12 @@ impl FirmwareBlockHeader {
   }
 }
 
+/// Flat image bytes with the signature of each block, ready to send.
+pub struct PreparedImage {
+  pub data: Vec<u8>,
+  pub signatures: Vec<[u8; SIGNATURE_LEN]>,
+}
+
 pub struct SampleOta {
   transfer: Arc<dyn Transfer>,
   state: DFUState,
@@ -82,6 +98,16 @@ pub struct SampleOta {
   current_chunk_index: usize,
   mcu_state_sender: watch::Sender<McuDfuState>,
   mcu_state_receiver: watch::Receiver<McuDfuState>,
+  device: Option<DeviceInfo>,
+  public_key: Option<VerifyingKey>,
+  pad_byte: u8,
+  signatures: Vec<[u8; SIGNATURE_LEN]>,
+  progress: Option<(Arc<OtaProgressStore>, String)>,
+  image_hash: [u8; 32],
+  resume: bool,
+  /// Blocks the store recorded as confirmed for this image, if it has a record.
+  confirmed_blocks: Option<usize>,
+  reported_block: Arc<Mutex<Option<u32>>>,
 }
 
 impl SampleOta {
@@ -95,19 +121,93 @@ impl SampleOta {
       current_chunk_index: 0,
       mcu_state_sender: tx,
       mcu_state_receiver: rx,
+      device: None,
+      public_key: None,
+      pad_byte: DEFAULT_PAD_BYTE,
+      signatures: Vec::new(),
+      progress: None,
+      image_hash: [0; 32],
+      resume: false,
+      confirmed_blocks: None,
+      reported_block: Arc::new(Mutex::new(None)),
     }
   }
 
+  /// Checks packages against `device` before flashing. Without it any
+  /// model is accepted.
+  pub fn with_device(mut self, device: Option<DeviceInfo>) -> Self {
+    self.device = device;
+    self
+  }
+
+  /// Records confirmed blocks for `device` in `store` so an interrupted
+  /// update of the same image can be resumed later. Resuming needs a device
+  /// that reports the `ota_resume` capability.
+  pub fn with_progress(mut self, store: Arc<OtaProgressStore>, device: String) -> Self {
+    self.progress = Some((store, device));
+    self
+  }
+
+  /// Fills gaps between the segments of addressed images with `pad_byte`.
+  pub fn with_pad_byte(mut self, pad_byte: u8) -> Self {
+    self.pad_byte = pad_byte;
+    self
+  }
+
+  /// Starts at the preamble, for a device the caller already sent the
+  /// `update` command to.
+  pub fn in_bootloader(mut self) -> Self {
+    self.state = DFUState::SendPreamble;
+    self
+  }
+
+  /// Refuses packages whose blocks are not signed with `key`.
+  ///
+  /// Unpackaged images have no signatures and are refused as well.
+  pub fn with_public_key(mut self, key: Option<VerifyingKey>) -> Self {
+    self.public_key = key;
+    self
+  }
+
+  // 将MCU通知的状态字节转发给状态机
+  pub fn mcu_state_callback(&self) -> Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static> {
+    let mcu_state_sender = self.mcu_state_sender.clone();
+    let reported_block = self.reported_block.clone();
+    let resume = self.resume;
+    Arc::new(move |data: Vec<u8>| {
+      // 只有续传请求的回复是 Header 状态字节后跟已写入的块数，需在状态生效前记下
+      if resume && data.len() == RESUME_REPORT_LEN && data[0] == McuDfuState::Header as u8 {
+        let block = u32::from_le_bytes(data[1..].try_into().unwrap());
+        *reported_block.lock().unwrap() = Some(block);
+      } else if data.len() > 1 {
+        warn!("Ignoring {} trailing bytes of MCU state", data.len() - 1);
+      }
+      if let Some(&state_byte) = data.first() {
+        if let Ok(mcu_state) = McuDfuState::try_from(state_byte) {
+          let _ = mcu_state_sender.send(mcu_state);
+          debug!("Received MCU state: {:?}", mcu_state);
+        } else {
+          error!("Failed to parse MCU state byte: {}", state_byte);
+        }
+      } else {
+        warn!("Received empty data from MCU notify.");
+      }
+    })
+  }
+
   pub async fn ota_process(
     &mut self,
     file_data: Arc<Vec<u8>>,
     total_blocks: usize,
-  ) -> Result<(bool, bool), String> {
+  ) -> Result<(bool, bool), Error> {
     let last_state = self.state;
     let mcu_target_state = *self.mcu_state_receiver.borrow();
     let mut progress_percentage_need_calculate = false;
 
-    if mcu_target_state == self.mcu_state && self.mcu_state != McuDfuState::Idle {
+    if mcu_target_state == self.mcu_state
+      && self.mcu_state != McuDfuState::Idle
+      && self.mcu_state != McuDfuState::Fault
+    {
       tokio::time::sleep(Duration::from_millis(10)).await;
       return Ok((false, false));
     }
@@ -120,11 +220,16 @@ impl SampleOta {
         .transfer
         .send(&DFU_ACK_PATTERN.to_le_bytes())
         .await
-        .map_err(|e| format!("OTA Ack failed: {:?}", e))?;
+        .map_err(|e| e.context("OTA Ack failed"))?;
       //if error happened, retry next time will come here again
       self.mcu_state = mcu_target_state;
     }
 
+    if mcu_target_state == McuDfuState::Fault {
+      //fault is reported without ack
+      self.mcu_state = McuDfuState::Fault;
+    }
+
     if self.mcu_state == McuDfuState::Fault {
       //mcu has been reboot, no way to retransfer
       self.state = DFUState::Fault;
@@ -134,11 +239,11 @@ impl SampleOta {
         info!("State: Start -> Sending OTA command");
         self
           .transfer
-          .send("update\r\n".as_bytes())
+          .send(UPDATE_CMD.as_bytes())
           .await
-          .map_err(|e| format!("OTA send failed: {:?}", e))?;
+          .map_err(|e| e.context("OTA send failed"))?;
         self.state = DFUState::SendPreamble;
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::time::sleep(BOOTLOADER_START).await;
       }
       DFUState::SendPreamble => {
         if self.mcu_state == McuDfuState::Idle {
@@ -146,7 +251,7 @@ impl SampleOta {
             .transfer
             .send(&DFU_PREAMBLE)
             .await
-            .map_err(|e| format!("OTA send failed: {:?}", e))?;
+            .map_err(|e| e.context("OTA send failed"))?;
           info!("State: SendPreamble -> Preamble sent, waiting for MCU response");
           self.state = DFUState::SendTotalBlocks;
         }
@@ -154,12 +259,15 @@ impl SampleOta {
       DFUState::SendTotalBlocks => {
         if self.mcu_state == McuDfuState::Prepare {
           info!("State: SendTotalBlocks -> MCU Ready for Total Blocks");
-          let total_blocks_bytes = (total_blocks as u32).to_le_bytes();
+          let mut total_blocks_bytes = (total_blocks as u32).to_le_bytes().to_vec();
+          if self.resume {
+            total_blocks_bytes.extend_from_slice(&self.image_hash);
+          }
           self
             .transfer
             .send(&total_blocks_bytes)
             .await
-            .map_err(|e| format!("OTA send failed: {:?}", e))?;
+            .map_err(|e| e.context("OTA send failed"))?;
           info!(
             "State: SendTotalBlocks -> Sending Total Blocks: {}",
             total_blocks
@@ -170,9 +278,19 @@ impl SampleOta {
       DFUState::SendBlockHeader => {
         if self.mcu_state == McuDfuState::Header {
           info!("State: SendBlockHeader -> MCU Ready for Block Header");
-          // 模拟块头数据，实际应从固件文件中解析
+          let reported = self.reported_block.lock().unwrap().take();
+          if let Some(block) = reported.map(|block| block as usize) {
+            self.check_reported_block(block, total_blocks)?;
+            info!("Bootloader has {} blocks, resuming from there", block);
+            self.current_block_index = block;
+          }
+          // 直接调用 run 的未打包镜像没有签名，块头签名全零
           let block_header = FirmwareBlockHeader {
-            signature: [0u8; 64], // 示例签名
+            signature: self
+              .signatures
+              .get(self.current_block_index)
+              .copied()
+              .unwrap_or([0u8; SIGNATURE_LEN]),
             block_size: (file_data.len() - self.current_block_index * DFU_PAGE_LEN)
               .min(DFU_PAGE_LEN) as u32, // 实际块大小
           };
@@ -181,7 +299,7 @@ impl SampleOta {
             .transfer
             .send(&block_header_bytes)
             .await
-            .map_err(|e| format!("OTA send failed: {:?}", e))?;
+            .map_err(|e| e.context("OTA send failed"))?;
           info!(
             "State: SendBlockHeader -> Sending Block {} Header",
             self.current_block_index
@@ -205,13 +323,18 @@ impl SampleOta {
             block_data.len(),
             chunks.len()
           );
+          // 块数据量大，走无应答写入；控制帧仍使用应答写入
           for chunk in &chunks[self.current_chunk_index..] {
-            self.transfer.send(*chunk).await.map_err(|e| {
-              format!(
-                "OTA chunk {} send failed: {:?}",
-                self.current_chunk_index, e
-              )
-            })?;
+            self
+              .transfer
+              .send_without_response(chunk)
+              .await
+              .map_err(|e| {
+                e.context(format!(
+                  "OTA chunk {} send failed",
+                  self.current_chunk_index
+                ))
+              })?;
             info!(
               "Sent chunk {} of size: {}",
               self.current_chunk_index,
@@ -243,6 +366,7 @@ impl SampleOta {
             self.current_block_index
           );
           self.current_block_index += 1;
+          self.save_progress(total_blocks);
           if self.current_block_index >= total_blocks {
             self.state = DFUState::Complete;
           } else {
@@ -257,49 +381,150 @@ impl SampleOta {
         }
       }
       DFUState::Fault => {
-        return Err("OTA process failed".to_string());
+        return Err(Error::new(ErrorKind::DeviceFault, "OTA process failed"));
       }
     }
-    return Ok((
+    Ok((
       self.state != last_state, // true if state changed, false otherwise
       progress_percentage_need_calculate,
-    ));
+    ))
   }
-}
 
-#[async_trait]
-impl Ota for SampleOta {
-  async fn start_ota(&mut self, app_handle: tauri::AppHandle) -> Result<(), String> {
-    // Apps can fully manage entries within this directory with std::fs.
-    let file_path = app_handle.dialog().file().blocking_pick_file().unwrap();
-    let mut opt = OpenOptions::new();
-    opt.read(true);
-    debug!("Starting OTA for file: {:?}", file_path);
-    let file_data = Arc::new(
-      app_handle
-        .fs()
-        .open(file_path, opt)
-        .map_err(|e| format!("Failed to read file: {}", e))?
-        .bytes()
-        .collect::<Result<Vec<u8>, std::io::Error>>()
-        .map_err(|e| format!("Failed to read file bytes: {}", e))?,
+  // 块头不带序号，引导程序会从它报告的块继续写，无法改从别处开始，报告不可信时只能中止。
+  // 写入通知丢失时引导程序可能比记录多写一块
+  fn check_reported_block(&self, block: usize, total_blocks: usize) -> Result<(), Error> {
+    let confirmed = self.confirmed_blocks.unwrap_or(0);
+    if block >= total_blocks {
+      return Err(Error::invalid_data(format!(
+        "Bootloader reported {} written blocks of {}",
+        block, total_blocks
+      )));
+    }
+    if self.confirmed_blocks.is_some() && block > confirmed + 1 {
+      // 记录已落后于设备（例如由其他主机续传过），删除后下次以引导程序为准
+      if let Some((store, device)) = &self.progress
+        && let Err(e) = store.remove(device)
+      {
+        warn!("{}", e);
+      }
+      return Err(Error::invalid_data(format!(
+        "Bootloader reported {} written blocks, but only {} of {} were confirmed",
+        block, confirmed, total_blocks
+      )));
+    }
+    Ok(())
+  }
+
+  // 记录失败不影响本次升级，只是下次无法续传
+  fn save_progress(&self, total_blocks: usize) {
+    let Some((store, device)) = &self.progress else {
+      return;
+    };
+    let result = if self.current_block_index >= total_blocks {
+      store.remove(device)
+    } else {
+      store.set(
+        device,
+        OtaProgress {
+          image_sha256: hex::encode(self.image_hash),
+          total_blocks,
+          confirmed_blocks: self.current_block_index,
+        },
+      )
+    };
+    if let Err(e) = result {
+      warn!("{}", e);
+    }
+  }
+
+  /// Checks `image` against the trusted key and the device and turns it
+  /// into the bytes to flash, without touching the link.
+  ///
+  /// Run it before the device is sent to its bootloader, so a rejected image
+  /// does not leave it waiting there.
+  pub fn prepare(&self, image: &[u8]) -> Result<PreparedImage, Error> {
+    if !image.starts_with(&PACKAGE_MAGIC) {
+      return self.prepare_unpackaged(image);
+    }
+    let package = FirmwarePackage::parse(image)?;
+    match &self.public_key {
+      Some(key) => verify_package(&package, key)?,
+      None => warn!("No firmware key configured, flashing unverified package"),
+    }
+    if let Some(device) = &self.device {
+      package.check_target(device)?;
+    }
+    info!(
+      "Starting OTA to {} {} ({} bytes)",
+      package.manifest.target.model,
+      package.manifest.target.version,
+      package.image.len()
     );
+    Ok(PreparedImage {
+      data: package.image,
+      signatures: package.signatures,
+    })
+  }
 
-    let mcu_state_sender_clone = self.mcu_state_sender.clone();
-    let total_blocks = (file_data.len() + DFU_PAGE_LEN - 1) / DFU_PAGE_LEN;
+  // 开发时直接烧录构建产物（bin/hex/srec/elf），没有签名，块头签名全零
+  fn prepare_unpackaged(&self, data: &[u8]) -> Result<PreparedImage, Error> {
+    if self.public_key.is_some() {
+      return Err(Error::invalid_data(
+        "Unsigned images are refused while a firmware key is configured",
+      ));
+    }
+    let flash = self.device.as_ref().and_then(|device| device.flash);
+    let image = FlatImage::load(data, flash.map(|flash| flash.start), self.pad_byte)?;
+    if let Some(flash) = flash {
+      flash.check(image.load_address, image.data.len())?;
+    }
+    warn!("Flashing unsigned image of {} bytes", image.data.len());
+    Ok(PreparedImage {
+      data: image.data,
+      signatures: Vec::new(),
+    })
+  }
 
-    let subscribe_callback = Arc::new(move |data: Vec<u8>| {
-      if let Some(&state_byte) = data.first() {
-        if let Ok(mcu_state) = McuDfuState::try_from(state_byte) {
-          let _ = mcu_state_sender_clone.send(mcu_state);
-          debug!("Received MCU state: {:?}", mcu_state);
-        } else {
-          error!("Failed to parse MCU state byte: {}", state_byte);
-        }
-      } else {
-        warn!("Received empty data from MCU notify.");
-      }
-    });
+  /// Flashes an image that `prepare` already accepted.
+  pub async fn start_prepared(
+    &mut self,
+    image: PreparedImage,
+    on_progress: &(dyn Fn(u32) + Send + Sync),
+  ) -> Result<(), Error> {
+    self.signatures = image.signatures;
+    self.run(Arc::new(image.data), on_progress).await
+  }
+
+  // 驱动状态机直至完成，进度百分比通过 on_progress 回调报告
+  pub async fn run(
+    &mut self,
+    file_data: Arc<Vec<u8>>,
+    on_progress: impl Fn(u32) + Send,
+  ) -> Result<(), Error> {
+    let total_blocks = file_data.len().div_ceil(DFU_PAGE_LEN);
+    self.image_hash = Sha256::digest(file_data.as_slice()).into();
+    // 引导程序支持续传时握手附带镜像哈希，由它报告已写入的块
+    self.resume = self
+      .device
+      .as_ref()
+      .is_some_and(|device| device.supports(Capability::OtaResume));
+    // 同一镜像的记录用于校验引导程序报告的块数
+    self.confirmed_blocks = self
+      .progress
+      .as_ref()
+      .and_then(|(store, device)| store.get(device))
+      .filter(|progress| {
+        progress.image_sha256 == hex::encode(self.image_hash)
+          && progress.total_blocks == total_blocks
+      })
+      .map(|progress| {
+        info!(
+          "Previous OTA of this image stopped after {} of {} blocks",
+          progress.confirmed_blocks, progress.total_blocks
+        );
+        progress.confirmed_blocks
+      });
+    let subscribe_callback = self.mcu_state_callback();
 
     self.transfer.unsubscribe().await.ok();
 
@@ -307,7 +532,7 @@ impl Ota for SampleOta {
       .transfer
       .subscribe(subscribe_callback.clone())
       .await
-      .map_err(|e| format!("Failed to subscribe to OTA: {}", e))?;
+      .map_err(|e| e.context("Failed to subscribe to OTA"))?;
 
     debug!("Notify callback set for transfer");
 
@@ -318,19 +543,12 @@ impl Ota for SampleOta {
 
       match ota_process_result {
         Ok((state_changed, progress_needs_calculate)) => {
-          if self.state == DFUState::Fault {
-            error!("OTA process failed due to DFUState::Fault.");
-            app_handle
-              .emit("ota_error", "OTA process failed by MCU fault")
-              .map_err(|e| format!("Failed to emit OTA error: {}", e))?;
-            break Err("OTA process failed".to_string());
-          }
           if state_changed {
             last_state_change_time = Instant::now();
           } else {
             // 检查是否超过60秒没有状态更改
             if last_state_change_time.elapsed() > Duration::from_secs(60) {
-              break Err(format!("OTA process Timeout"));
+              break Err(Error::timeout("OTA process Timeout"));
             }
           }
 
@@ -338,9 +556,7 @@ impl Ota for SampleOta {
             // 计算进度条
             let progress_percentage =
               ((self.current_block_index as f64 / total_blocks as f64) * 100.0) as u32;
-            app_handle
-              .emit("ota_progress", progress_percentage)
-              .map_err(|e| format!("Failed to emit OTA progress: {}", e))?;
+            on_progress(progress_percentage);
           }
 
           if self.state == DFUState::Complete && self.mcu_state == McuDfuState::Final {
@@ -348,12 +564,21 @@ impl Ota for SampleOta {
             break Ok(());
           }
         }
+        Err(_) if self.state == DFUState::Fault => {
+          //mcu has been reboot, retrying is pointless
+          error!("OTA process failed due to DFUState::Fault.");
+          break Err(Error::new(
+            ErrorKind::DeviceFault,
+            "OTA process failed by MCU fault",
+          ));
+        }
         Err(e) => {
           error!("OTA process error: {}", e);
           if retry > 0 {
             retry -= 1;
             warn!("Retrying OTA process, attempts left: {}", retry);
-            if self.transfer.is_actived().await.ok().unwrap() {
+            // 查询失败时按链路已断开处理
+            if self.transfer.is_actived().await.unwrap_or(false) {
               self.transfer.unsubscribe().await.ok();
               warn!("Unsubscribed from OTA, retrying...");
             }
@@ -361,26 +586,23 @@ impl Ota for SampleOta {
               .transfer
               .deactivate()
               .await
-              .map_err(|e| format!("Failed to deactivate transfer: {}", e))?;
+              .map_err(|e| e.context("Failed to deactivate transfer"))?;
             self
               .transfer
               .activate()
               .await
-              .map_err(|e| format!("Failed to activate transfer: {}", e))?;
+              .map_err(|e| e.context("Failed to activate transfer"))?;
             warn!("Re-activating transfer for OTA...");
             self
               .transfer
               .subscribe(subscribe_callback.clone())
               .await
-              .map_err(|e| format!("Failed to re-subscribe to OTA: {}", e))?;
+              .map_err(|e| e.context("Failed to re-subscribe to OTA"))?;
             warn!("Re-subscribed to OTA, retrying...");
             continue; // 继续循环重试
           } else {
             error!("All retries exhausted, OTA process failed.");
-            app_handle
-              .emit("ota_error", "OTA process failed by retries exhausted")
-              .map_err(|e| format!("Failed to emit OTA error: {}", e))?;
-            break Err(e);
+            break Err(e.context("OTA process failed by retries exhausted"));
           }
         }
       }
@@ -394,3 +616,207 @@ impl Ota for SampleOta {
     ota_result
   }
 }
+
+#[async_trait]
+impl Ota for SampleOta {
+  async fn start_ota(
+    &mut self,
+    image: Arc<Vec<u8>>,
+    on_progress: &(dyn Fn(u32) + Send + Sync),
+  ) -> Result<(), Error> {
+    let image = self.prepare(&image)?;
+    self.start_prepared(image, on_progress).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transfer::mock::MockTransfer;
+
+  struct Harness {
+    mock: Arc<MockTransfer>,
+    ota: SampleOta,
+    image: Arc<Vec<u8>>,
+    total_blocks: usize,
+  }
+
+  impl Harness {
+    fn new(image_len: usize, mtu: usize) -> Self {
+      let mock = Arc::new(MockTransfer::new().with_mtu(mtu));
+      let image: Vec<u8> = (0..image_len).map(|i| (i % 251) as u8).collect();
+      Harness {
+        ota: SampleOta::new(mock.clone()),
+        total_blocks: image.len().div_ceil(DFU_PAGE_LEN),
+        image: Arc::new(image),
+        mock,
+      }
+    }
+
+    // 通过订阅回调注入MCU状态，然后运行一次状态机，返回本次写出的数据
+    async fn step(&mut self, mcu: Option<McuDfuState>) -> Vec<Vec<u8>> {
+      if let Some(state) = mcu {
+        self
+          .mock
+          .subscribe(self.ota.mcu_state_callback())
+          .await
+          .unwrap();
+        self.mock.notify(vec![state as u8]).await;
+      }
+      self.mock.clear_writes();
+      self
+        .ota
+        .ota_process(self.image.clone(), self.total_blocks)
+        .await
+        .unwrap();
+      self.mock.writes()
+    }
+  }
+
+  fn ack() -> Vec<u8> {
+    DFU_ACK_PATTERN.to_le_bytes().to_vec()
+  }
+
+  fn header(block_size: u32) -> Vec<u8> {
+    FirmwareBlockHeader {
+      signature: [0u8; 64],
+      block_size,
+    }
+    .to_bytes()
+  }
+
+  #[tokio::test]
+  async fn walks_every_dfu_state_for_two_blocks() {
+    let mut h = Harness::new(DFU_PAGE_LEN + 100, 200);
+
+    assert_eq!(h.step(None).await, vec![b"update\r\n".to_vec()]);
+    assert_eq!(h.step(None).await, vec![DFU_PREAMBLE.to_vec()]);
+    assert_eq!(
+      h.step(Some(McuDfuState::Prepare)).await,
+      vec![ack(), 2u32.to_le_bytes().to_vec()]
+    );
+
+    assert_eq!(
+      h.step(Some(McuDfuState::Header)).await,
+      vec![ack(), header(DFU_PAGE_LEN as u32)]
+    );
+    let writes = h.step(Some(McuDfuState::Data)).await;
+    assert_eq!(writes[0], ack());
+    assert!(writes[1..].iter().all(|chunk| chunk.len() <= 200));
+    assert_eq!(writes[1..].concat(), h.image[..DFU_PAGE_LEN]);
+    assert_eq!(h.step(Some(McuDfuState::Verify)).await, vec![ack()]);
+    assert_eq!(h.step(Some(McuDfuState::Write)).await, vec![ack()]);
+    assert_eq!(h.ota.current_block_index, 1);
+    assert_eq!(h.ota.state, DFUState::SendBlockHeader);
+
+    assert_eq!(
+      h.step(Some(McuDfuState::Header)).await,
+      vec![ack(), header(100)]
+    );
+    assert_eq!(
+      h.step(Some(McuDfuState::Data)).await,
+      vec![ack(), h.image[DFU_PAGE_LEN..].to_vec()]
+    );
+    h.step(Some(McuDfuState::Verify)).await;
+    h.step(Some(McuDfuState::Write)).await;
+    assert_eq!(h.ota.state, DFUState::Complete);
+
+    assert_eq!(h.step(Some(McuDfuState::Final)).await, vec![ack()]);
+    assert_eq!(h.ota.mcu_state, McuDfuState::Final);
+  }
+
+  #[tokio::test]
+  async fn only_block_data_skips_link_acknowledgement() {
+    let mut h = Harness::new(DFU_PAGE_LEN, 200);
+    h.step(None).await;
+    assert_eq!(h.mock.unacknowledged_writes(), 0);
+    h.step(None).await;
+    h.step(Some(McuDfuState::Prepare)).await;
+    h.step(Some(McuDfuState::Header)).await;
+    assert_eq!(h.mock.unacknowledged_writes(), 0);
+
+    let writes = h.step(Some(McuDfuState::Data)).await;
+
+    assert_eq!(h.mock.unacknowledged_writes(), writes.len() - 1);
+    assert_eq!(h.step(Some(McuDfuState::Verify)).await, vec![ack()]);
+    assert_eq!(h.mock.unacknowledged_writes(), 0);
+  }
+
+  #[tokio::test]
+  async fn waits_while_mcu_state_is_unchanged() {
+    let mut h = Harness::new(16, 200);
+    h.step(None).await;
+    h.step(None).await;
+    h.step(Some(McuDfuState::Prepare)).await;
+    h.mock.clear_writes();
+
+    let result = h
+      .ota
+      .ota_process(h.image.clone(), h.total_blocks)
+      .await
+      .unwrap();
+
+    assert_eq!(result, (false, false));
+    assert!(h.mock.writes().is_empty());
+  }
+
+  #[tokio::test]
+  async fn failed_ack_is_retried_on_next_pass() {
+    let mut h = Harness::new(16, 200);
+    h.step(None).await;
+    h.step(None).await;
+    h.mock.subscribe(h.ota.mcu_state_callback()).await.unwrap();
+    h.mock.notify(vec![McuDfuState::Prepare as u8]).await;
+    h.mock.fail_next_sends(1);
+
+    let result = h.ota.ota_process(h.image.clone(), h.total_blocks).await;
+
+    assert!(result.unwrap_err().message.starts_with("OTA Ack failed"));
+    assert_eq!(h.ota.mcu_state, McuDfuState::Idle);
+    assert_eq!(h.step(None).await, vec![ack(), 1u32.to_le_bytes().to_vec()]);
+  }
+
+  #[tokio::test]
+  async fn block_report_needs_resume_request_and_header_state() {
+    let mut report = vec![McuDfuState::Header as u8];
+    report.extend_from_slice(&2u32.to_le_bytes());
+    let mut h = Harness::new(16, 200);
+    h.mock.subscribe(h.ota.mcu_state_callback()).await.unwrap();
+    h.mock.notify(report.clone()).await;
+    assert_eq!(*h.ota.reported_block.lock().unwrap(), None);
+
+    h.ota.resume = true;
+    h.mock.subscribe(h.ota.mcu_state_callback()).await.unwrap();
+    let mut wrong_state = report.clone();
+    wrong_state[0] = McuDfuState::Data as u8;
+    h.mock.notify(wrong_state).await;
+    h.mock.notify([report.as_slice(), &[0]].concat()).await;
+    assert_eq!(*h.ota.reported_block.lock().unwrap(), None);
+
+    h.mock.notify(report).await;
+    assert_eq!(*h.ota.reported_block.lock().unwrap(), Some(2));
+  }
+
+  #[test]
+  fn reported_block_is_checked_against_recorded_progress() {
+    let store = Arc::new(OtaProgressStore::new());
+    let progress = OtaProgress {
+      image_sha256: String::new(),
+      total_blocks: 5,
+      confirmed_blocks: 1,
+    };
+    store.set("SN1", progress).unwrap();
+    let mut ota =
+      SampleOta::new(Arc::new(MockTransfer::new())).with_progress(store.clone(), "SN1".into());
+
+    assert!(ota.check_reported_block(4, 5).is_ok());
+    assert!(ota.check_reported_block(5, 5).is_err());
+
+    ota.confirmed_blocks = Some(1);
+    assert!(ota.check_reported_block(2, 5).is_ok());
+    let error = ota.check_reported_block(3, 5).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::InvalidData);
+    // 过期的记录被删除，下次以引导程序为准
+    assert_eq!(store.get("SN1"), None);
+  }
+}
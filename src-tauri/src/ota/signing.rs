@@ -0,0 +1,163 @@
+//! Ed25519 block signatures for firmware packages.
+//!
+//! Every `DFU_PAGE_LEN` block is signed on its own so the bootloader can
+//! check a block before writing it. The signed message is the block index
+//! as a little endian `u32` followed by the block data, which keeps valid
+//! blocks from being reordered or moved between positions.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::path::Path;
+#[cfg(feature = "gui")]
+use std::path::PathBuf;
+
+use super::package::{FirmwarePackage, FirmwareTarget, SIGNATURE_LEN};
+use super::sample::DFU_PAGE_LEN;
+use crate::error::{Error, ErrorKind};
+
+fn block_message(index: usize, block: &[u8]) -> Vec<u8> {
+  let mut message = Vec::with_capacity(4 + block.len());
+  message.extend_from_slice(&(index as u32).to_le_bytes());
+  message.extend_from_slice(block);
+  message
+}
+
+// 密钥文件可以是 32 字节原始数据，也可以是 64 个十六进制字符
+fn read_key(path: &Path) -> Result<[u8; 32], Error> {
+  let data = std::fs::read(path).map_err(|e| {
+    Error::new(
+      ErrorKind::Io,
+      format!("Failed to read key {}: {}", path.display(), e),
+    )
+  })?;
+  let bytes = match std::str::from_utf8(&data) {
+    Ok(text) if text.trim().len() == 64 => hex::decode(text.trim()).ok(),
+    _ => Some(data),
+  };
+  bytes
+    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+    .ok_or_else(|| Error::invalid_data(format!("{} is not a 32-byte Ed25519 key", path.display())))
+}
+
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, Error> {
+  Ok(SigningKey::from_bytes(&read_key(path)?))
+}
+
+pub fn load_verifying_key(path: &Path) -> Result<VerifyingKey, Error> {
+  VerifyingKey::from_bytes(&read_key(path)?)
+    .map_err(|e| Error::invalid_data(format!("Invalid public key {}: {}", path.display(), e)))
+}
+
+/// Builds a package for `image` signed block by block with `key`.
+pub fn sign_package(target: FirmwareTarget, image: Vec<u8>, key: &SigningKey) -> FirmwarePackage {
+  let signatures: Vec<[u8; SIGNATURE_LEN]> = image
+    .chunks(DFU_PAGE_LEN)
+    .enumerate()
+    .map(|(index, block)| key.sign(&block_message(index, block)).to_bytes())
+    .collect();
+  FirmwarePackage::new(target, image, signatures)
+}
+
+/// Checks every block signature of `package` against `key`.
+pub fn verify_package(package: &FirmwarePackage, key: &VerifyingKey) -> Result<(), Error> {
+  for (index, (block, signature)) in package
+    .image
+    .chunks(DFU_PAGE_LEN)
+    .zip(&package.signatures)
+    .enumerate()
+  {
+    key
+      .verify(
+        &block_message(index, block),
+        &Signature::from_bytes(signature),
+      )
+      .map_err(|_| {
+        Error::invalid_data(format!(
+          "Block {} is not signed by the trusted firmware key",
+          index
+        ))
+      })?;
+  }
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+/// The public key firmware packages must be signed with.
+///
+/// Read from the app config dir; without it packages are flashed
+/// unverified, which only suits development setups.
+pub struct TrustedKey(Result<Option<VerifyingKey>, Error>);
+
+#[cfg(feature = "gui")]
+impl TrustedKey {
+  pub fn none() -> Self {
+    TrustedKey(Ok(None))
+  }
+
+  pub fn load(path: PathBuf) -> Self {
+    if !path.exists() {
+      log::warn!(
+        "No firmware key at {:?}, OTA packages will not be verified",
+        path
+      );
+      return Self::none();
+    }
+    TrustedKey(load_verifying_key(&path).map(Some))
+  }
+
+  /// Fails if a key is configured but unreadable: falling back to no
+  /// verification would let a broken key file disable the check.
+  pub fn key(&self) -> Result<Option<VerifyingKey>, Error> {
+    self.0.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn target() -> FirmwareTarget {
+    FirmwareTarget {
+      model: "SIM-VALVE".to_string(),
+      hardware: "sim".to_string(),
+      version: "2.0.0".to_string(),
+      min_bootloader: "1.0.0".to_string(),
+    }
+  }
+
+  #[test]
+  fn signed_package_verifies_only_with_its_key() {
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let image: Vec<u8> = (0..DFU_PAGE_LEN * 2 + 9).map(|i| i as u8).collect();
+    let package = sign_package(target(), image, &key);
+
+    let parsed = FirmwarePackage::parse(&package.to_bytes()).unwrap();
+    verify_package(&parsed, &key.verifying_key()).unwrap();
+
+    let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+    assert_eq!(
+      verify_package(&parsed, &other).unwrap_err().message,
+      "Block 0 is not signed by the trusted firmware key"
+    );
+  }
+
+  #[test]
+  fn rejects_tampered_and_reordered_blocks() {
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let image = vec![0xab; DFU_PAGE_LEN * 2];
+    let package = sign_package(target(), image, &key);
+
+    // 换成同一密钥为其他镜像签的合法块也不行
+    let mut tampered = sign_package(target(), vec![0xcd; DFU_PAGE_LEN * 2], &key);
+    tampered.image[..DFU_PAGE_LEN].copy_from_slice(&package.image[..DFU_PAGE_LEN]);
+    assert_eq!(
+      verify_package(&tampered, &key.verifying_key())
+        .unwrap_err()
+        .message,
+      "Block 0 is not signed by the trusted firmware key"
+    );
+
+    let mut swapped = package.clone();
+    swapped.signatures.swap(0, 1);
+    assert!(verify_package(&swapped, &key.verifying_key()).is_err());
+  }
+}
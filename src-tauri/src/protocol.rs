@@ -0,0 +1,194 @@
+use crate::error::Error;
+
+/// Start-of-frame marker of the binary protocol.
+pub const SOF: u8 = 0xa5;
+// SOF、类型、序号、两字节长度
+const HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 2;
+const MAX_PAYLOAD_LEN: usize = 1024;
+
+/// Wire protocol spoken on the current link.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+  /// `"cmd args\r\n"` out, raw notifications in, acks recognised by their
+  /// first two bytes.
+  #[default]
+  Text,
+  /// CRC16 protected frames, see [`Frame`].
+  Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameType {
+  /// Host to device: command text without the line ending.
+  Command = 0x01,
+  /// Device to host: `CMD_OK`/`CMD_ERR` as u16 LE, then any reply data.
+  Ack = 0x02,
+  /// Device to host: stream data.
+  Data = 0x03,
+}
+
+impl TryFrom<u8> for FrameType {
+  type Error = Error;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0x01 => Ok(FrameType::Command),
+      0x02 => Ok(FrameType::Ack),
+      0x03 => Ok(FrameType::Data),
+      other => Err(Error::invalid_data(format!(
+        "Unknown frame type {:#04x}",
+        other
+      ))),
+    }
+  }
+}
+
+/// One binary protocol frame.
+///
+/// On the wire: SOF, type, sequence, payload length (u16 LE), payload and a
+/// CRC16/CCITT-FALSE (u16 LE) over everything from type to payload. An ack
+/// carries the sequence of the command it answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+  pub kind: FrameType,
+  pub sequence: u8,
+  pub payload: Vec<u8>,
+}
+
+impl Frame {
+  pub fn new(kind: FrameType, sequence: u8, payload: impl Into<Vec<u8>>) -> Self {
+    Frame {
+      kind,
+      sequence,
+      payload: payload.into(),
+    }
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len() + CRC_LEN);
+    bytes.push(SOF);
+    bytes.push(self.kind as u8);
+    bytes.push(self.sequence);
+    bytes.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&self.payload);
+    let crc = crc16(&bytes[1..]);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    bytes
+  }
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+  let mut crc: u16 = 0xffff;
+  for byte in data {
+    crc ^= (*byte as u16) << 8;
+    for _ in 0..8 {
+      crc = if crc & 0x8000 != 0 {
+        (crc << 1) ^ 0x1021
+      } else {
+        crc << 1
+      };
+    }
+  }
+  crc
+}
+
+/// Extracts frames from a byte stream, whatever the notification boundaries.
+///
+/// Bytes before a SOF and frames failing their CRC are skipped; decoding
+/// resumes at the next SOF.
+#[derive(Default)]
+pub struct FrameDecoder {
+  buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+  pub fn push(&mut self, data: &[u8]) -> Vec<Frame> {
+    self.buffer.extend_from_slice(data);
+    let mut frames = Vec::new();
+    loop {
+      match self.buffer.iter().position(|b| *b == SOF) {
+        Some(start) => {
+          self.buffer.drain(..start);
+        }
+        None => {
+          self.buffer.clear();
+          break;
+        }
+      }
+      if self.buffer.len() < HEADER_LEN {
+        break;
+      }
+      let len = u16::from_le_bytes([self.buffer[3], self.buffer[4]]) as usize;
+      if len > MAX_PAYLOAD_LEN {
+        self.buffer.drain(..1);
+        continue;
+      }
+      let total = HEADER_LEN + len + CRC_LEN;
+      if self.buffer.len() < total {
+        break;
+      }
+      match decode(&self.buffer[..total]) {
+        Ok(frame) => {
+          frames.push(frame);
+          self.buffer.drain(..total);
+        }
+        Err(e) => {
+          log::warn!("Skipping corrupt frame: {}", e);
+          self.buffer.drain(..1);
+        }
+      }
+    }
+    frames
+  }
+
+  pub fn reset(&mut self) {
+    self.buffer.clear();
+  }
+}
+
+fn decode(bytes: &[u8]) -> Result<Frame, Error> {
+  let (body, crc) = bytes.split_at(bytes.len() - CRC_LEN);
+  let expected = u16::from_le_bytes([crc[0], crc[1]]);
+  let actual = crc16(&body[1..]);
+  if expected != actual {
+    return Err(Error::invalid_data(format!(
+      "CRC mismatch: expected {:#06x}, got {:#06x}",
+      expected, actual
+    )));
+  }
+  Ok(Frame {
+    kind: FrameType::try_from(body[1])?,
+    sequence: body[2],
+    payload: body[HEADER_LEN..].to_vec(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn crc_matches_ccitt_false_check_value() {
+    assert_eq!(crc16(b"123456789"), 0x29b1);
+  }
+
+  #[test]
+  fn decodes_across_notifications_and_skips_corruption() {
+    let ack = Frame::new(FrameType::Ack, 7, vec![0xfe, 0xca, b'{', b'}']);
+    let data = Frame::new(FrameType::Data, 0, vec![1, 2, 3]);
+    let mut corrupt = Frame::new(FrameType::Data, 1, vec![9]).encode();
+    corrupt[5] ^= 0xff;
+
+    let mut stream = vec![0x00, 0x13];
+    stream.extend(corrupt);
+    stream.extend(ack.encode());
+    stream.extend(data.encode());
+
+    let mut decoder = FrameDecoder::default();
+    let (head, tail) = stream.split_at(12);
+    assert!(decoder.push(head).is_empty());
+    assert_eq!(decoder.push(tail), vec![ack, data]);
+  }
+}
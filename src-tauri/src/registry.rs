@@ -0,0 +1,160 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::commands::queue::QueueDepth;
+use crate::error::{Error, ErrorKind};
+use crate::session::DeviceSession;
+
+type QueueListener = Arc<dyn Fn(&str, QueueDepth) + Send + Sync + 'static>;
+
+/// Event payload tagged with the device it came from.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceEvent<T> {
+  pub address: String,
+  #[serde(flatten)]
+  pub data: T,
+}
+
+impl<T> DeviceEvent<T> {
+  pub fn new(address: &str, data: T) -> Self {
+    DeviceEvent {
+      address: address.to_string(),
+      data,
+    }
+  }
+}
+
+/// Sessions of all open links, keyed by device address.
+///
+/// Each link has its own session, so commands for different devices queue
+/// and route independently.
+#[derive(Default)]
+pub struct DeviceRegistry {
+  sessions: RwLock<HashMap<String, Arc<DeviceSession>>>,
+  queue_listener: RwLock<Option<QueueListener>>,
+}
+
+impl DeviceRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Reports queue depth changes of every session, with its address.
+  pub fn set_queue_listener(&self, listener: QueueListener) {
+    *self.queue_listener.write().unwrap() = Some(listener);
+  }
+
+  pub fn session(&self, address: &str) -> Result<Arc<DeviceSession>, Error> {
+    self
+      .sessions
+      .read()
+      .unwrap()
+      .get(address)
+      .cloned()
+      .ok_or_else(|| {
+        Error::new(
+          ErrorKind::NotConnected,
+          format!("Device {} not connected", address),
+        )
+      })
+  }
+
+  /// The session for `address`, created if the device has none yet.
+  pub fn open(&self, address: &str) -> Arc<DeviceSession> {
+    let mut sessions = self.sessions.write().unwrap();
+    if let Some(session) = sessions.get(address) {
+      return session.clone();
+    }
+    let session = Arc::new(DeviceSession::new());
+    if let Some(listener) = self.queue_listener.read().unwrap().clone() {
+      let address = address.to_string();
+      session
+        .queue()
+        .set_listener(Arc::new(move |depth| listener(&address, depth)));
+    }
+    sessions.insert(address.to_string(), session.clone());
+    session
+  }
+
+  /// Detaches and forgets the session for `address`, if any.
+  pub async fn close(&self, address: &str) -> Result<(), Error> {
+    let session = self.sessions.write().unwrap().remove(address);
+    match session {
+      Some(session) => session.detach().await,
+      None => Ok(()),
+    }
+  }
+
+  /// Forgets `session` if it is still the one registered for `address`.
+  pub fn forget(&self, address: &str, session: &Arc<DeviceSession>) {
+    let mut sessions = self.sessions.write().unwrap();
+    if sessions
+      .get(address)
+      .is_some_and(|current| Arc::ptr_eq(current, session))
+    {
+      sessions.remove(address);
+    }
+  }
+
+  pub fn addresses(&self) -> Vec<String> {
+    let mut addresses: Vec<String> = self.sessions.read().unwrap().keys().cloned().collect();
+    addresses.sort();
+    addresses
+  }
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn connected_devices(
+  devices: tauri::State<'_, DeviceRegistry>,
+) -> Result<Vec<String>, Error> {
+  Ok(devices.addresses())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transfer::mock::MockTransfer;
+
+  #[tokio::test]
+  async fn devices_get_separate_sessions() {
+    let registry = DeviceRegistry::new();
+    let first = Arc::new(MockTransfer::new());
+    let second = Arc::new(MockTransfer::new());
+    registry.open("A").attach(first.clone()).await.unwrap();
+    registry.open("B").attach(second.clone()).await.unwrap();
+
+    registry
+      .session("A")
+      .unwrap()
+      .transfer()
+      .unwrap()
+      .send(b"a")
+      .await
+      .unwrap();
+    registry.close("B").await.unwrap();
+
+    assert_eq!(first.writes(), vec![b"a".to_vec()]);
+    assert!(second.writes().is_empty());
+    assert!(!second.is_subscribed());
+    assert_eq!(registry.addresses(), vec!["A".to_string()]);
+    assert_eq!(
+      registry.session("B").err().unwrap().message,
+      "Device B not connected"
+    );
+  }
+
+  #[test]
+  fn events_carry_the_source_address() {
+    #[derive(Serialize)]
+    struct Sample {
+      value: u32,
+    }
+
+    assert_eq!(
+      serde_json::to_value(DeviceEvent::new("A", Sample { value: 1 })).unwrap(),
+      serde_json::json!({ "address": "A", "value": 1 })
+    );
+  }
+}
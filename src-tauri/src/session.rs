@@ -0,0 +1,585 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::oneshot;
+
+use crate::commands::identify::{Capability, DeviceInfo, LEGACY_PROTOCOL_VERSION};
+use crate::commands::queue::CommandQueue;
+use crate::commands::{CMD_ERR, CMD_OK};
+use crate::error::{Error, ErrorKind};
+use crate::protocol::{Frame, FrameDecoder, FrameType, Protocol};
+use crate::transfer::Transfer;
+#[cfg(feature = "gui")]
+use crate::transfer::framing::Framing;
+use crate::transfer::framing::Reassembler;
+
+pub type FrameHandler = Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>;
+
+struct PendingRequest {
+  ack: oneshot::Sender<u16>,
+  handler: Option<FrameHandler>,
+  sequence: Option<u8>,
+}
+
+struct Stream {
+  // 只在重连后重新开启数据流时读取
+  #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+  command: String,
+  handler: FrameHandler,
+}
+
+// 单一订阅收到的帧在这里分发给等待中的请求、数据流或独占使用者
+#[derive(Default)]
+struct FrameRouter {
+  pending: Mutex<Option<PendingRequest>>,
+  streams: Mutex<HashMap<String, Stream>>,
+  exclusive: Mutex<Option<FrameHandler>>,
+  monitor: Mutex<Option<FrameHandler>>,
+  reassembler: Mutex<Reassembler>,
+  protocol: Mutex<Protocol>,
+  decoder: Mutex<FrameDecoder>,
+}
+
+impl FrameRouter {
+  fn route(&self, data: Vec<u8>) {
+    if let Some(monitor) = self.monitor.lock().unwrap().clone() {
+      monitor(data.clone());
+    }
+    if let Some(handler) = self.exclusive.lock().unwrap().clone() {
+      handler(data);
+      return;
+    }
+
+    if *self.protocol.lock().unwrap() == Protocol::Binary {
+      let frames = self.decoder.lock().unwrap().push(&data);
+      for frame in frames {
+        self.dispatch_binary(frame);
+      }
+      return;
+    }
+
+    let frames = self.reassembler.lock().unwrap().push(data);
+    for frame in frames {
+      self.dispatch_text(frame);
+    }
+  }
+
+  fn dispatch_text(&self, data: Vec<u8>) {
+    if let [b0, b1, rest @ ..] = data.as_slice() {
+      let response_code = u16::from_le_bytes([*b0, *b1]);
+      if response_code == CMD_OK || response_code == CMD_ERR {
+        self.complete(response_code, rest, None);
+        return;
+      }
+    }
+    self.dispatch_data(data);
+  }
+
+  fn dispatch_binary(&self, frame: Frame) {
+    match (frame.kind, frame.payload.as_slice()) {
+      (FrameType::Ack, [b0, b1, rest @ ..]) => {
+        self.complete(u16::from_le_bytes([*b0, *b1]), rest, Some(frame.sequence))
+      }
+      (FrameType::Data, _) => self.dispatch_data(frame.payload),
+      (kind, _) => log::warn!("Dropping unexpected {:?} frame", kind),
+    }
+  }
+
+  fn complete(&self, response_code: u16, rest: &[u8], sequence: Option<u8>) {
+    let mut pending = self.pending.lock().unwrap();
+    // 二进制协议下序号不符的是已超时请求的迟到应答，原始请求不带序号则不比较
+    let expected = pending.as_ref().map(|p| p.sequence);
+    if sequence.is_some() && expected != Some(None) && expected != Some(sequence) {
+      log::warn!(
+        "Dropping response {:#06x} for sequence {:?}",
+        response_code,
+        sequence
+      );
+      return;
+    }
+    match pending.take() {
+      Some(pending) => {
+        // 先交付附带的数据，再唤醒请求，保证命令返回前数据已处理
+        if !rest.is_empty()
+          && let Some(handler) = pending.handler
+        {
+          handler(rest.to_vec());
+        }
+        let _ = pending.ack.send(response_code);
+      }
+      None => log::warn!("Dropping unsolicited response {:#06x}", response_code),
+    }
+  }
+
+  fn dispatch_data(&self, data: Vec<u8>) {
+    let streams: Vec<FrameHandler> = self
+      .streams
+      .lock()
+      .unwrap()
+      .values()
+      .map(|stream| stream.handler.clone())
+      .collect();
+    if !streams.is_empty() {
+      for handler in streams {
+        handler(data.clone());
+      }
+    } else if let Some(handler) = self
+      .pending
+      .lock()
+      .unwrap()
+      .as_ref()
+      .and_then(|pending| pending.handler.clone())
+    {
+      handler(data);
+    } else {
+      log::debug!("Dropping {} bytes with no receiver", data.len());
+    }
+  }
+
+  // 数据流登记保留，链路恢复后据此重新开启
+  fn reset(&self) {
+    self.pending.lock().unwrap().take();
+    self.exclusive.lock().unwrap().take();
+    self.reassembler.lock().unwrap().reset();
+    self.decoder.lock().unwrap().reset();
+    // 新连接重新协商协议
+    *self.protocol.lock().unwrap() = Protocol::Text;
+  }
+}
+
+/// Connection state shared by all commands.
+///
+/// Owns the active link and keeps one notification subscription alive for
+/// its whole lifetime, dispatching incoming frames to whichever request or
+/// stream is waiting instead of resubscribing per command.
+pub struct DeviceSession {
+  transfer: RwLock<Option<Arc<dyn Transfer>>>,
+  router: Arc<FrameRouter>,
+  queue: CommandQueue,
+  sequence: AtomicU8,
+  epoch: AtomicU64,
+  info: RwLock<Option<DeviceInfo>>,
+  // 存活的独占视图数量，OTA 期间不为零
+  exclusive_views: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "gui")]
+/// A link that dropped without being detached, kept so it can be restored.
+pub struct LostLink {
+  pub transfer: Arc<dyn Transfer>,
+  epoch: u64,
+}
+
+impl Default for DeviceSession {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl DeviceSession {
+  pub fn new() -> Self {
+    DeviceSession {
+      transfer: RwLock::new(None),
+      router: Arc::new(FrameRouter::default()),
+      queue: CommandQueue::new(),
+      sequence: AtomicU8::new(0),
+      epoch: AtomicU64::new(0),
+      info: RwLock::new(None),
+      exclusive_views: Arc::new(AtomicUsize::new(0)),
+    }
+  }
+
+  fn router_callback(&self) -> FrameHandler {
+    let router = self.router.clone();
+    Arc::new(move |data: Vec<u8>| router.route(data))
+  }
+
+  pub async fn attach(&self, transfer: Arc<dyn Transfer>) -> Result<(), Error> {
+    self.detach().await.ok();
+    transfer.unsubscribe().await.ok();
+    transfer
+      .subscribe(self.router_callback())
+      .await
+      .map_err(|e| e.context("Failed to subscribe"))?;
+    *self.router.reassembler.lock().unwrap() = Reassembler::new(transfer.framing());
+    *self.transfer.write().unwrap() = Some(transfer);
+    Ok(())
+  }
+
+  pub async fn detach(&self) -> Result<(), Error> {
+    let transfer = self.transfer.write().unwrap().take();
+    self.epoch.fetch_add(1, Ordering::SeqCst);
+    self.router.reset();
+    self.router.streams.lock().unwrap().clear();
+    self.router.monitor.lock().unwrap().take();
+    self.info.write().unwrap().take();
+    match transfer {
+      Some(transfer) => transfer
+        .unsubscribe()
+        .await
+        .map_err(|e| e.context("Failed to unsubscribe")),
+      None => Ok(()),
+    }
+  }
+
+  #[cfg(feature = "gui")]
+  /// Forgets a link that dropped on its own, keeping active streams
+  /// registered. Returns `None` if it was already detached on purpose, or
+  /// while an exclusive view such as an OTA owns the link and reconnects it
+  /// itself.
+  pub fn link_lost(&self) -> Option<LostLink> {
+    // OTA 重试会主动断开再连接，此时清理会话或启动重连都会打断升级
+    if self.exclusive_views.load(Ordering::SeqCst) > 0 {
+      return None;
+    }
+    // 链路已断开，订阅已随之失效，只清理会话状态
+    let transfer = self.transfer.write().unwrap().take()?;
+    self.router.reset();
+    Some(LostLink {
+      transfer,
+      epoch: self.epoch.load(Ordering::SeqCst),
+    })
+  }
+
+  #[cfg(feature = "gui")]
+  /// Whether nothing has been attached or detached since `lost` dropped.
+  pub fn is_current(&self, lost: &LostLink) -> bool {
+    self.epoch.load(Ordering::SeqCst) == lost.epoch
+  }
+
+  #[cfg(feature = "gui")]
+  /// Re-attaches a reconnected link, unless the session moved on meanwhile.
+  pub async fn restore(&self, lost: &LostLink) -> Result<(), Error> {
+    if !self.is_current(lost) {
+      return Err(Error::new(ErrorKind::Cancelled, "Link was replaced"));
+    }
+    lost
+      .transfer
+      .subscribe(self.router_callback())
+      .await
+      .map_err(|e| e.context("Failed to subscribe"))?;
+    *self.transfer.write().unwrap() = Some(lost.transfer.clone());
+    Ok(())
+  }
+
+  #[cfg(feature = "gui")]
+  /// Drops the streams of a link that could not be restored.
+  pub fn abandon(&self, lost: &LostLink) {
+    if self.is_current(lost) {
+      self.router.streams.lock().unwrap().clear();
+    }
+  }
+
+  pub fn transfer(&self) -> Result<Arc<dyn Transfer>, Error> {
+    self
+      .transfer
+      .read()
+      .unwrap()
+      .clone()
+      .ok_or_else(Error::not_connected)
+  }
+
+  pub fn queue(&self) -> &CommandQueue {
+    &self.queue
+  }
+
+  #[cfg(feature = "gui")]
+  pub fn framing(&self) -> Framing {
+    self.router.reassembler.lock().unwrap().framing().clone()
+  }
+
+  #[cfg(feature = "gui")]
+  /// Changes how notifications are split into messages, dropping any
+  /// partially received message.
+  pub fn set_framing(&self, framing: Framing) -> Result<(), Error> {
+    framing.validate()?;
+    *self.router.reassembler.lock().unwrap() = Reassembler::new(framing);
+    Ok(())
+  }
+
+  pub fn protocol(&self) -> Protocol {
+    *self.router.protocol.lock().unwrap()
+  }
+
+  pub fn set_protocol(&self, protocol: Protocol) {
+    self.router.decoder.lock().unwrap().reset();
+    *self.router.protocol.lock().unwrap() = protocol;
+  }
+
+  /// What the device reported at connect, `None` if it was not identified.
+  pub fn info(&self) -> Option<DeviceInfo> {
+    self.info.read().unwrap().clone()
+  }
+
+  pub fn set_info(&self, info: Option<DeviceInfo>) {
+    *self.info.write().unwrap() = info;
+  }
+
+  /// Payload layout version to decode device data with.
+  pub fn protocol_version(&self) -> u8 {
+    self
+      .info
+      .read()
+      .unwrap()
+      .as_ref()
+      .map_or(LEGACY_PROTOCOL_VERSION, DeviceInfo::negotiated_version)
+  }
+
+  /// Fails unless the device supports `capability`. Devices that could not
+  /// be identified are assumed to support everything.
+  pub fn require(&self, capability: Capability) -> Result<(), Error> {
+    match self.info.read().unwrap().as_ref() {
+      Some(info) if !info.supports(capability) => Err(Error::new(
+        ErrorKind::Unsupported,
+        format!("{} does not support {:?} commands", info.model, capability),
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  /// Registers `command` as the request awaiting an ack and returns the
+  /// bytes to send for it in the current protocol.
+  pub(crate) fn begin_request(
+    &self,
+    command: &str,
+    handler: Option<FrameHandler>,
+  ) -> (Vec<u8>, oneshot::Receiver<u16>) {
+    let (request, sequence) = match self.protocol() {
+      Protocol::Text => (command.as_bytes().to_vec(), None),
+      Protocol::Binary => {
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst);
+        let frame = Frame::new(FrameType::Command, sequence, command.trim_end());
+        (frame.encode(), Some(sequence))
+      }
+    };
+    let (ack, rx) = oneshot::channel();
+    *self.router.pending.lock().unwrap() = Some(PendingRequest {
+      ack,
+      handler,
+      sequence,
+    });
+    (request, rx)
+  }
+
+  /// Registers a request whose bytes the caller sends unchanged, accepting
+  /// whichever ack arrives next.
+  pub(crate) fn begin_raw_request(&self) -> oneshot::Receiver<u16> {
+    let (ack, rx) = oneshot::channel();
+    *self.router.pending.lock().unwrap() = Some(PendingRequest {
+      ack,
+      handler: None,
+      sequence: None,
+    });
+    rx
+  }
+
+  pub(crate) fn end_request(&self) {
+    self.router.pending.lock().unwrap().take();
+  }
+
+  /// Routes data frames to `handler` until removed. `command` is what
+  /// started the stream, re-sent when the link is restored.
+  pub fn add_stream(&self, name: &str, command: &str, handler: FrameHandler) {
+    self.router.streams.lock().unwrap().insert(
+      name.to_string(),
+      Stream {
+        command: command.to_string(),
+        handler,
+      },
+    );
+  }
+
+  /// Passes every notification to `monitor` before it is routed, until
+  /// cleared or the session is detached.
+  pub fn set_monitor(&self, monitor: Option<FrameHandler>) {
+    *self.router.monitor.lock().unwrap() = monitor;
+  }
+
+  pub fn remove_stream(&self, name: &str) {
+    self.router.streams.lock().unwrap().remove(name);
+  }
+
+  #[cfg(feature = "gui")]
+  /// Name and start command of every registered stream.
+  pub fn streams(&self) -> Vec<(String, String)> {
+    self
+      .router
+      .streams
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(name, stream)| (name.clone(), stream.command.clone()))
+      .collect()
+  }
+
+  /// A `Transfer` view whose subscription receives every frame unparsed,
+  /// for raw protocols such as OTA that bypass request/response routing.
+  ///
+  /// While the view is alive, a dropped link is left to its owner instead of
+  /// being reconnected by the session.
+  pub fn exclusive(&self) -> Result<Arc<dyn Transfer>, Error> {
+    let inner = self.transfer()?;
+    self.exclusive_views.fetch_add(1, Ordering::SeqCst);
+    Ok(Arc::new(ExclusiveTransfer {
+      inner,
+      router: self.router.clone(),
+      views: self.exclusive_views.clone(),
+    }))
+  }
+}
+
+struct ExclusiveTransfer {
+  inner: Arc<dyn Transfer>,
+  router: Arc<FrameRouter>,
+  views: Arc<AtomicUsize>,
+}
+
+impl Drop for ExclusiveTransfer {
+  fn drop(&mut self) {
+    self.views.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+#[async_trait]
+impl Transfer for ExclusiveTransfer {
+  fn get_mtu(&self) -> usize {
+    self.inner.get_mtu()
+  }
+
+  async fn activate(&self) -> Result<(), Error> {
+    self.inner.activate().await?;
+    // 重连后底层订阅已失效，重新挂上分发器
+    let router = self.router.clone();
+    self
+      .inner
+      .subscribe(Arc::new(move |data: Vec<u8>| router.route(data)))
+      .await
+  }
+
+  async fn deactivate(&self) -> Result<(), Error> {
+    self.inner.deactivate().await
+  }
+
+  async fn is_actived(&self) -> Result<bool, Error> {
+    self.inner.is_actived().await
+  }
+
+  async fn send(&self, data: &[u8]) -> Result<(), Error> {
+    self.inner.send(data).await
+  }
+
+  async fn send_without_response(&self, data: &[u8]) -> Result<(), Error> {
+    self.inner.send_without_response(data).await
+  }
+
+  async fn read(&self) -> Result<Vec<u8>, Error> {
+    self.inner.read().await
+  }
+
+  async fn subscribe(
+    &self,
+    callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+  ) -> Result<(), Error> {
+    *self.router.exclusive.lock().unwrap() = Some(callback);
+    Ok(())
+  }
+
+  async fn unsubscribe(&self) -> Result<(), Error> {
+    self.router.exclusive.lock().unwrap().take();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transfer::framing::Framing;
+  use crate::transfer::mock::{MockTransfer, collector, received};
+
+  #[tokio::test]
+  async fn subscribes_once_for_the_session() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = DeviceSession::new();
+
+    session.attach(mock.clone()).await.unwrap();
+    assert!(mock.is_subscribed());
+
+    session.detach().await.unwrap();
+    assert!(!mock.is_subscribed());
+    assert!(session.transfer().is_err());
+  }
+
+  #[tokio::test]
+  async fn ack_wakes_request_while_data_goes_to_streams() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    let (mut stream_rx, stream) = collector();
+    let (mut request_rx, request) = collector();
+    session.add_stream("valve_info", "valve_info 1\r\n", stream);
+
+    let (_, ack) = session.begin_request("ping\r\n", Some(request));
+    mock.notify(vec![1, 2, 3, 4]).await;
+    mock.notify(CMD_OK.to_le_bytes().to_vec()).await;
+
+    assert_eq!(received(&mut stream_rx).await, vec![1, 2, 3, 4]);
+    assert_eq!(ack.await.unwrap(), CMD_OK);
+    assert!(request_rx.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn exclusive_view_bypasses_routing() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    let (mut stream_rx, stream) = collector();
+    let (mut raw_rx, raw) = collector();
+    session.add_stream("valve_info", "valve_info 1\r\n", stream);
+
+    let exclusive = session.exclusive().unwrap();
+    exclusive.subscribe(raw).await.unwrap();
+    mock.notify(CMD_OK.to_le_bytes().to_vec()).await;
+    assert_eq!(received(&mut raw_rx).await, CMD_OK.to_le_bytes().to_vec());
+
+    exclusive.unsubscribe().await.unwrap();
+    mock.notify(vec![7]).await;
+    assert_eq!(received(&mut stream_rx).await, vec![7]);
+  }
+
+  #[tokio::test]
+  async fn binary_frames_bypass_text_framing() {
+    let mock = Arc::new(MockTransfer::new().with_framing(Framing::Delimited {
+      delimiter: b"\r\n".to_vec(),
+    }));
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+    session.set_protocol(Protocol::Binary);
+    let (mut rx, callback) = collector();
+
+    let (_, ack) = session.begin_request("config_read", Some(callback));
+    // 负载中的 \r\n 不应把帧截断
+    let mut payload = CMD_OK.to_le_bytes().to_vec();
+    payload.extend_from_slice(b"a\r\nb");
+    mock
+      .notify(Frame::new(FrameType::Ack, 0, payload).encode())
+      .await;
+
+    assert_eq!(ack.await.unwrap(), CMD_OK);
+    assert_eq!(received(&mut rx).await, b"a\r\nb".to_vec());
+  }
+
+  #[cfg(feature = "gui")]
+  #[tokio::test]
+  async fn link_loss_fails_pending_request() {
+    let mock = Arc::new(MockTransfer::new());
+    let session = DeviceSession::new();
+    session.attach(mock.clone()).await.unwrap();
+
+    let (_, ack) = session.begin_request("ping\r\n", None);
+    session.link_lost();
+
+    assert!(ack.await.is_err());
+    assert_eq!(session.transfer().err(), Some(Error::not_connected()));
+  }
+}
@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Company identifier our firmware advertises its manufacturer data under.
+///
+/// 0xFFFF is reserved by the Bluetooth SIG for internal use, which is what
+/// the devices ship with.
+pub const VENDOR_COMPANY_ID: u16 = 0xffff;
+// RSSI 变化小于该值时不重复上报
+const RSSI_STEP: i16 = 3;
+const RSSI_SAMPLES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+  Valve,
+  Channel,
+  Airpressure,
+  Unknown,
+}
+
+impl From<u8> for DeviceKind {
+  fn from(value: u8) -> Self {
+    match value {
+      0x01 => DeviceKind::Valve,
+      0x02 => DeviceKind::Channel,
+      0x03 => DeviceKind::Airpressure,
+      _ => DeviceKind::Unknown,
+    }
+  }
+}
+
+/// What a device tells about itself in its manufacturer data: a kind byte,
+/// the firmware version as three bytes and optionally its serial number.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Advertisement {
+  pub kind: DeviceKind,
+  pub firmware: String,
+  pub serial: Option<String>,
+}
+
+impl Advertisement {
+  pub fn parse(manufacturer_data: &HashMap<u16, Vec<u8>>) -> Option<Self> {
+    let data = manufacturer_data.get(&VENDOR_COMPANY_ID)?;
+    let [kind, major, minor, patch, serial @ ..] = data.as_slice() else {
+      return None;
+    };
+    let serial = (!serial.is_empty()).then(|| match std::str::from_utf8(serial) {
+      Ok(text) if text.chars().all(|c| c.is_ascii_graphic()) => text.to_string(),
+      _ => serial.iter().map(|b| format!("{:02X}", b)).collect(),
+    });
+    Some(Advertisement {
+      kind: DeviceKind::from(*kind),
+      firmware: format!("{}.{}.{}", major, minor, patch),
+      serial,
+    })
+  }
+}
+
+/// Which advertisements a scan reports. Every field that is set must match.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ScanOptions {
+  pub name_prefix: Option<String>,
+  pub service: Option<Uuid>,
+}
+
+impl ScanOptions {
+  pub fn matches(&self, device: &tauri_plugin_blec::models::BleDevice) -> bool {
+    self
+      .name_prefix
+      .as_ref()
+      .is_none_or(|prefix| device.name.starts_with(prefix.as_str()))
+      && self
+        .service
+        .is_none_or(|service| device.services.contains(&service))
+  }
+}
+
+/// A nearby device as shown in the scan list.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanUpdate {
+  pub name: String,
+  pub address: String,
+  pub rssi: Option<i16>,
+  /// Mean of the recent RSSI samples, steadier for sorting by distance.
+  pub rssi_avg: Option<i16>,
+  pub advertisement: Option<Advertisement>,
+}
+
+struct Seen {
+  update: ScanUpdate,
+  samples: Vec<i16>,
+  reported_at: Instant,
+}
+
+/// De-duplicates the repeated device lists a scan delivers.
+///
+/// A device is reported when first seen, when its name or advertisement
+/// changes, when its RSSI moves noticeably, and otherwise at most once per
+/// `refresh` so the UI can age out devices that went away.
+pub struct ScanTracker {
+  filter: ScanOptions,
+  refresh: Duration,
+  seen: HashMap<String, Seen>,
+}
+
+impl ScanTracker {
+  pub fn new(filter: ScanOptions, refresh: Duration) -> Self {
+    ScanTracker {
+      filter,
+      refresh,
+      seen: HashMap::new(),
+    }
+  }
+
+  pub fn update(&mut self, devices: &[tauri_plugin_blec::models::BleDevice]) -> Vec<ScanUpdate> {
+    let now = Instant::now();
+    let mut updates = Vec::new();
+    for device in devices.iter().filter(|device| self.filter.matches(device)) {
+      let advertisement = Advertisement::parse(&device.manufacturer_data);
+      let first = !self.seen.contains_key(&device.address);
+      let seen = self
+        .seen
+        .entry(device.address.clone())
+        .or_insert_with(|| Seen {
+          update: ScanUpdate {
+            name: String::new(),
+            address: device.address.clone(),
+            rssi: None,
+            rssi_avg: None,
+            advertisement: None,
+          },
+          samples: Vec::new(),
+          reported_at: now,
+        });
+      if let Some(rssi) = device.rssi {
+        if seen.samples.len() == RSSI_SAMPLES {
+          seen.samples.remove(0);
+        }
+        seen.samples.push(rssi);
+      }
+      let rssi_moved = match (seen.update.rssi, device.rssi) {
+        (Some(before), Some(now)) => (before - now).abs() >= RSSI_STEP,
+        (before, now) => before != now,
+      };
+      let changed = first
+        || rssi_moved
+        || seen.update.name != device.name
+        || seen.update.advertisement != advertisement
+        || now.duration_since(seen.reported_at) >= self.refresh;
+      if changed {
+        seen.update.name = device.name.clone();
+        seen.update.rssi = device.rssi;
+        seen.update.rssi_avg = average(&seen.samples);
+        seen.update.advertisement = advertisement;
+        seen.reported_at = now;
+        updates.push(seen.update.clone());
+      }
+    }
+    updates
+  }
+}
+
+fn average(samples: &[i16]) -> Option<i16> {
+  if samples.is_empty() {
+    return None;
+  }
+  let sum: i32 = samples.iter().map(|&sample| sample as i32).sum();
+  Some((sum / samples.len() as i32) as i16)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tauri_plugin_blec::models::BleDevice;
+
+  fn device(name: &str, rssi: i16, manufacturer: Option<Vec<u8>>) -> BleDevice {
+    BleDevice {
+      address: "00:11:22:33:44:55".to_string(),
+      name: name.to_string(),
+      is_connected: false,
+      manufacturer_data: manufacturer
+        .map(|data| HashMap::from([(VENDOR_COMPANY_ID, data)]))
+        .unwrap_or_default(),
+      service_data: HashMap::new(),
+      services: Vec::new(),
+      rssi: Some(rssi),
+    }
+  }
+
+  #[test]
+  fn parses_kind_version_and_serial() {
+    let data = HashMap::from([(VENDOR_COMPANY_ID, b"\x03\x01\x02\x0aSN42".to_vec())]);
+    assert_eq!(
+      Advertisement::parse(&data),
+      Some(Advertisement {
+        kind: DeviceKind::Airpressure,
+        firmware: "1.2.10".to_string(),
+        serial: Some("SN42".to_string()),
+      })
+    );
+
+    let binary = HashMap::from([(VENDOR_COMPANY_ID, vec![1, 0, 9, 0, 0xbe, 0xef])]);
+    let parsed = Advertisement::parse(&binary).unwrap();
+    assert_eq!(parsed.kind, DeviceKind::Valve);
+    assert_eq!(parsed.serial.as_deref(), Some("BEEF"));
+
+    assert_eq!(
+      Advertisement::parse(&HashMap::from([(0x0059, vec![1, 2, 3, 4])])),
+      None
+    );
+  }
+
+  #[test]
+  fn reports_new_devices_and_noticeable_changes_only() {
+    let mut tracker = ScanTracker::new(
+      ScanOptions {
+        name_prefix: Some("CYG".to_string()),
+        service: None,
+      },
+      Duration::from_secs(60),
+    );
+    let valve = Some(vec![1, 1, 0, 0]);
+
+    let first = tracker.update(&[
+      device("CYG-V1", -60, valve.clone()),
+      device("Phone", -40, None),
+    ]);
+    assert_eq!(first.len(), 1);
+    assert_eq!(
+      first[0].advertisement.as_ref().unwrap().kind,
+      DeviceKind::Valve
+    );
+
+    assert!(
+      tracker
+        .update(&[device("CYG-V1", -61, valve.clone())])
+        .is_empty()
+    );
+    let moved = tracker.update(&[device("CYG-V1", -70, valve.clone())]);
+    assert_eq!(moved[0].rssi, Some(-70));
+    assert_eq!(moved[0].rssi_avg, Some(-63));
+    assert_eq!(
+      tracker
+        .update(&[device("CYG-V1", -70, Some(vec![1, 1, 1, 0]))])
+        .len(),
+      1
+    );
+  }
+}
@@ -1,16 +1,21 @@
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tauri::Emitter;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{Emitter, Manager, State};
+use tauri_plugin_blec::models::WriteType;
 use uuid::Uuid;
 
-use super::Transfer;
-
-const READ_CHARACTERISTIC_UUID: Uuid = uuid::uuid!("0000ffe1-0000-1000-8000-00805f9b34fb");
-const WRITE_CHARACTERISTIC_UUID: Uuid = uuid::uuid!("0000ffe2-0000-1000-8000-00805f9b34fb");
-
-const BLE_MTU: usize = 247 - 3;
+use super::advertisement::{ScanOptions, ScanTracker, ScanUpdate};
+use super::flow::CreditWindow;
+use super::profile::ProfileRegistry;
+use super::{ATT_PAYLOAD_CANDIDATES, Transfer, send_fragmented};
+use crate::commands::handshake::handshake;
+use crate::commands::reconnect::link_lost;
+use crate::error::{Error, ErrorKind};
+use crate::registry::DeviceRegistry;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BleDevice {
@@ -19,140 +24,398 @@ pub struct BleDevice {
   isconnected: bool,
 }
 
+impl BleDevice {
+  pub fn new(name: impl Into<String>, address: impl Into<String>, isconnected: bool) -> Self {
+    BleDevice {
+      name: name.into(),
+      address: address.into(),
+      isconnected,
+    }
+  }
+
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  pub fn connected(mut self, isconnected: bool) -> Self {
+    self.isconnected = isconnected;
+    self
+  }
+}
+
+type DisconnectHandler = Arc<dyn Fn() + Send + Sync + 'static>;
+
+fn on_disconnect(handler: Option<DisconnectHandler>) -> tauri_plugin_blec::OnDisconnectHandler {
+  match handler {
+    Some(handler) => tauri_plugin_blec::OnDisconnectHandler::Sync(Box::new(move || handler())),
+    None => tauri_plugin_blec::OnDisconnectHandler::None,
+  }
+}
+
+// 连续无应答写入的初始与最大数量
+const WRITE_WINDOW: usize = 8;
+const WRITE_WINDOW_MAX: usize = 32;
+
+// ATT 默认 MTU 23 下的单次写入上限，任何对端都能接收
+const ATT_DEFAULT_PAYLOAD: usize = 23 - 3;
+
 pub struct BleTransfer {
   handler: &'static tauri_plugin_blec::Handler,
   mac: String,
-  mtu: usize,
+  mtu: AtomicUsize,
+  // 已被应答写入证实可用的写入长度，无应答写入超过它可能被对端截断而不报错
+  confirmed: AtomicUsize,
+  notify: Uuid,
+  write: Uuid,
+  window: CreditWindow,
+  on_disconnect: Option<DisconnectHandler>,
 }
 
 impl BleTransfer {
-  pub async fn new() -> Result<Self, String> {
-    let handler =
-      tauri_plugin_blec::get_handler().map_err(|e| format!("BLE handler unavailable: {:?}", e))?;
-    let mac = handler
+  pub async fn new(profiles: &ProfileRegistry) -> Result<Self, Error> {
+    let handler = tauri_plugin_blec::get_handler().map_err(|e| {
+      Error::new(
+        ErrorKind::Unavailable,
+        format!("BLE handler unavailable: {:?}", e),
+      )
+    })?;
+    let device = handler
       .connected_device()
       .await
-      .map_err(|e| format!("Failed to get connected device: {:?}", e))?
-      .address;
+      .map_err(|_| Error::not_connected())?;
+    let characteristics: Vec<Uuid> = handler
+      .discover_services(&device.address)
+      .await
+      .map_err(|e| Error::transport(format!("BLE service discovery failed: {:?}", e)))?
+      .iter()
+      .flat_map(|service| service.characteristics.iter().map(|c| c.uuid))
+      .collect();
+    let profile = profiles.resolve(&device, &characteristics).ok_or_else(|| {
+      Error::new(
+        ErrorKind::Unavailable,
+        format!("No GATT profile matches device {}", device.name),
+      )
+    })?;
+    log::info!("Using GATT profile {} for {}", profile.name, device.address);
 
     Ok(BleTransfer {
       handler,
-      mac,
-      mtu: BLE_MTU,
+      mac: device.address,
+      mtu: AtomicUsize::new(ATT_PAYLOAD_CANDIDATES[0]),
+      confirmed: AtomicUsize::new(ATT_DEFAULT_PAYLOAD),
+      notify: profile.notify,
+      write: profile.write,
+      window: CreditWindow::new(WRITE_WINDOW, WRITE_WINDOW_MAX),
+      on_disconnect: None,
     })
   }
+
+  /// Runs `handler` whenever the link drops, including after reconnects.
+  pub fn with_disconnect_handler(mut self, handler: DisconnectHandler) -> Self {
+    self.on_disconnect = Some(handler);
+    self
+  }
+
+  async fn write_chunk(
+    &self,
+    chunk: &[u8],
+    write_type: WriteType,
+  ) -> Result<(), tauri_plugin_blec::Error> {
+    self.handler.send_data(self.write, chunk, write_type).await
+  }
+
+  // 应答写入被拒会报告错误，由 send_fragmented 降低 MTU；成功则证实该长度可用
+  async fn write_acknowledged(&self, chunk: &[u8]) -> Result<(), Error> {
+    self
+      .write_chunk(chunk, WriteType::WithResponse)
+      .await
+      .map_err(|e| Error::transport(format!("BLE send failed: {:?}", e)))?;
+    self.confirmed.fetch_max(chunk.len(), Ordering::SeqCst);
+    Ok(())
+  }
 }
 
 #[async_trait]
 impl Transfer for BleTransfer {
   fn get_mtu(&self) -> usize {
-    self.mtu
+    self.mtu.load(Ordering::SeqCst)
   }
 
-  async fn activate(&self) -> Result<(), String> {
+  async fn activate(&self) -> Result<(), Error> {
     self
       .handler
-      .connect(
-        self.mac.as_str(),
-        tauri_plugin_blec::OnDisconnectHandler::None,
-      )
+      .connect(self.mac.as_str(), on_disconnect(self.on_disconnect.clone()))
       .await
-      .map_err(|e| format!("BLE connection failed: {:?}", e))?;
+      .map_err(|e| Error::transport(format!("BLE connection failed: {:?}", e)))?;
+    // 重连后可能协商出不同的 MTU，从最大值重新探测
+    self.mtu.store(ATT_PAYLOAD_CANDIDATES[0], Ordering::SeqCst);
+    self.confirmed.store(ATT_DEFAULT_PAYLOAD, Ordering::SeqCst);
     Ok(())
   }
 
-  async fn deactivate(&self) -> Result<(), String> {
+  async fn deactivate(&self) -> Result<(), Error> {
     self
       .handler
       .disconnect()
       .await
-      .map_err(|e| format!("BLE deactivation failed: {:?}", e))
+      .map_err(|e| Error::transport(format!("BLE deactivation failed: {:?}", e)))
   }
 
-  async fn is_actived(&self) -> Result<bool, String> {
+  async fn is_actived(&self) -> Result<bool, Error> {
     Ok(self.handler.is_connected())
   }
 
-  async fn send(&self, data: &[u8]) -> Result<(), String> {
-    if data.len() <= self.mtu {
-      self
-        .handler
-        .send_data(
-          WRITE_CHARACTERISTIC_UUID,
-          data,
-          tauri_plugin_blec::models::WriteType::WithResponse,
-        )
-        .await
-        .map_err(|e| format!("BLE send failed: {:?}", e))?;
-      return Ok(());
-    } else {
-      return Err(format!(
-        "Data size {} exceeds BLE MTU limit of {}",
-        data.len(),
-        self.mtu
-      ));
-    }
+  async fn send(&self, data: &[u8]) -> Result<(), Error> {
+    send_fragmented(data, &self.mtu, |chunk| async move {
+      self.write_acknowledged(&chunk).await
+    })
+    .await
   }
 
-  async fn read(&self) -> Result<Vec<u8>, String> {
+  /// Writes without response only in pieces an acknowledged write already
+  /// proved the peer accepts. Longer pieces go with response first, which
+  /// probes the MTU.
+  async fn send_without_response(&self, data: &[u8]) -> Result<(), Error> {
+    send_fragmented(data, &self.mtu, |chunk| async move {
+      if chunk.len() <= self.confirmed.load(Ordering::SeqCst) && self.window.try_take() {
+        match self.write_chunk(&chunk, WriteType::WithoutResponse).await {
+          Ok(()) => return Ok(()),
+          Err(e) => {
+            // 无应答写入被拒，说明对端缓冲区已满，改为应答写入等待其追上
+            self.window.congested();
+            log::debug!(
+              "BLE write without response rejected, window {}: {:?}",
+              self.window.window(),
+              e
+            );
+          }
+        }
+      }
+      self.write_acknowledged(&chunk).await?;
+      self.window.acknowledged();
+      Ok(())
+    })
+    .await
+  }
+
+  async fn read(&self) -> Result<Vec<u8>, Error> {
     self
       .handler
-      .recv_data(READ_CHARACTERISTIC_UUID)
+      .recv_data(self.notify)
       .await
-      .map_err(|e| format!("BLE read failed: {:?}", e))
+      .map_err(|e| Error::transport(format!("BLE read failed: {:?}", e)))
   }
 
   async fn subscribe(
     &self,
     callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
-  ) -> Result<(), String> {
+  ) -> Result<(), Error> {
     self
       .handler
-      .subscribe(READ_CHARACTERISTIC_UUID, move |data| {
+      .subscribe(self.notify, move |data| {
         callback(data);
       })
       .await
-      .map_err(|e| format!("BLE subscribe failed: {:?}", e))
+      .map_err(|e| Error::transport(format!("BLE subscribe failed: {:?}", e)))
   }
 
-  async fn unsubscribe(&self) -> Result<(), String> {
+  async fn unsubscribe(&self) -> Result<(), Error> {
     self
       .handler
-      .unsubscribe(READ_CHARACTERISTIC_UUID)
+      .unsubscribe(self.notify)
       .await
-      .map_err(|e| format!("BLE unsubscribe failed: {:?}", e))
+      .map_err(|e| Error::transport(format!("BLE unsubscribe failed: {:?}", e)))
+  }
+}
+
+// 信号稳定的设备也按该间隔重新上报，便于前端清理已离开的设备
+const SCAN_REFRESH: Duration = Duration::from_secs(2);
+
+pub(crate) fn ble_handler() -> Result<&'static tauri_plugin_blec::Handler, Error> {
+  tauri_plugin_blec::get_handler()
+    .map_err(|e| Error::new(ErrorKind::Unavailable, format!("BLE unavailable: {:?}", e)))
+}
+
+// 最近一次扫描的编号，旧扫描收尾时不能停掉已开始的新扫描
+static SCAN_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// 扫描启动失败或转发任务结束（包括被中止）时，停掉仍属于本次的扫描，
+// 避免适配器停留在扫描状态导致下一次扫描失败
+struct ScanGuard {
+  generation: u64,
+}
+
+impl ScanGuard {
+  fn is_current(generation: u64) -> bool {
+    SCAN_GENERATION.load(Ordering::SeqCst) == generation
   }
 }
 
+impl Drop for ScanGuard {
+  fn drop(&mut self) {
+    let generation = self.generation;
+    if !Self::is_current(generation) {
+      return;
+    }
+    tauri::async_runtime::spawn(async move {
+      if let Ok(handler) = ble_handler()
+        && ScanGuard::is_current(generation)
+        && let Err(e) = handler.stop_scan().await
+      {
+        log::debug!("Stopping finished scan failed: {:?}", e);
+      }
+    });
+  }
+}
+
+/// Scans for `timeout_ms`, passing each nearby device matching `options`
+/// to `on_update` whenever something about it changes.
+///
+/// The returned task ends together with the scan.
+pub(crate) async fn start_scan(
+  options: ScanOptions,
+  timeout_ms: u64,
+  mut on_update: impl FnMut(ScanUpdate) + Send + 'static,
+) -> Result<tauri::async_runtime::JoinHandle<()>, Error> {
+  let handler = ble_handler()?;
+  let filter = match options.service {
+    Some(service) => tauri_plugin_blec::models::ScanFilter::Service(service),
+    None => tauri_plugin_blec::models::ScanFilter::None,
+  };
+  let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+  let guard = ScanGuard {
+    generation: SCAN_GENERATION.fetch_add(1, Ordering::SeqCst) + 1,
+  };
+  handler
+    .discover(Some(tx), timeout_ms, filter)
+    .await
+    .map_err(|e| Error::transport(format!("BLE scan failed: {:?}", e)))?;
+
+  let mut tracker = ScanTracker::new(options, SCAN_REFRESH);
+  Ok(tauri::async_runtime::spawn(async move {
+    let _guard = guard;
+    // 扫描结束或被停止时插件释放发送端，循环随之退出
+    while let Some(devices) = rx.recv().await {
+      tracker
+        .update(&devices)
+        .into_iter()
+        .for_each(&mut on_update);
+    }
+  }))
+}
+
+/// Scans for `timeout_ms` and emits a `scan_update` for every nearby device
+/// matching `options` whenever something about it changes.
 #[tauri::command]
-pub async fn connect(app_handle: tauri::AppHandle, device: BleDevice) -> Result<(), String> {
-  let handler =
-    tauri_plugin_blec::get_handler().map_err(|e| format!("BLE unavailable: {:?}", e))?;
+pub async fn scan(
+  app_handle: tauri::AppHandle,
+  options: ScanOptions,
+  timeout_ms: u64,
+) -> Result<(), Error> {
+  start_scan(options, timeout_ms, move |update| {
+    let _ = app_handle.emit("scan_update", update);
+  })
+  .await
+  .map(|_| ())
+}
+
+#[tauri::command]
+pub async fn stop_scan() -> Result<(), Error> {
+  ble_handler()?
+    .stop_scan()
+    .await
+    .map_err(|e| Error::transport(format!("BLE stop scan failed: {:?}", e)))
+}
+
+// BLE 插件只维护一条连接，多设备需要借助串口或网关
+static BLE_LINK: Mutex<Option<String>> = Mutex::new(None);
+
+// 只有 BLE 连接属于 device 时才清除记录并返回 true
+fn release_link(link: &Mutex<Option<String>>, device: &str) -> bool {
+  let mut link = link.lock().unwrap();
+  if link.as_deref() != Some(device) {
+    return false;
+  }
+  link.take();
+  true
+}
+
+/// Connects `device` over BLE.
+///
+/// The BLE plugin drives a single link, so only one BLE device can be
+/// connected at a time; connecting another while it is up fails with
+/// `ErrorKind::Busy` instead of replacing it. More devices can be attached
+/// over serial or a TCP gateway.
+#[tauri::command]
+pub async fn connect(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  device: BleDevice,
+) -> Result<(), Error> {
+  let handler = ble_handler()?;
+
+  let previous = BLE_LINK.lock().unwrap().clone();
+  if let Some(previous) = previous
+    && previous != device.address
+  {
+    if handler.is_connected() {
+      return Err(Error::new(
+        ErrorKind::Busy,
+        format!(
+          "BLE device {} is already connected, only one BLE link is supported",
+          previous
+        ),
+      ));
+    }
+    // 上一个 BLE 设备已断开且仍在重连，放弃它
+    devices.close(&previous).await.ok();
+  }
 
   const MAX_RETRIES: usize = 3;
   const RETRY_DELAY_MS: u64 = 1000;
 
   let mut retry_count = 0;
   let mut last_error = String::new();
+  let lost: DisconnectHandler = Arc::new({
+    let app_handle = app_handle.clone();
+    let device = device.clone();
+    move || link_lost(&app_handle, device.clone())
+  });
 
   while retry_count < MAX_RETRIES {
     let mut _device = device.clone();
 
     match handler
-      .connect(
-        &device.address,
-        tauri_plugin_blec::OnDisconnectHandler::Async(Box::pin({
-          let app_handle = app_handle.clone();
-          let mut device = device.clone();
-          async move {
-            device.isconnected = false;
-            let _ = app_handle.emit("ble_status", device);
-          }
-        })),
-      )
+      .connect(&device.address, on_disconnect(Some(lost.clone())))
       .await
     {
       Ok(_) => {
+        let attached = async {
+          let transfer = BleTransfer::new(&app_handle.state::<ProfileRegistry>())
+            .await?
+            .with_disconnect_handler(lost.clone());
+          let session = devices.open(&device.address);
+          session.attach(Arc::new(transfer)).await?;
+          Ok::<_, Error>(session)
+        }
+        .await;
+        let session = match attached {
+          Ok(session) => session,
+          Err(e) => {
+            // 链路已建立但无法使用，断开它，否则下次连接会失败或复用残留链路
+            devices.close(&device.address).await.ok();
+            if let Err(disconnect_error) = handler.disconnect().await {
+              log::warn!(
+                "Failed to drop unusable BLE link {}: {:?}",
+                device.address,
+                disconnect_error
+              );
+            }
+            return Err(e);
+          }
+        };
+        handshake(&session).await;
+        *BLE_LINK.lock().unwrap() = Some(device.address.clone());
         _device.isconnected = true;
         let _ = app_handle.emit("ble_status", _device);
         return Ok(());
@@ -168,14 +431,38 @@ pub async fn connect(app_handle: tauri::AppHandle, device: BleDevice) -> Result<
     }
   }
 
-  Err(format!("{} after {} retries", last_error, MAX_RETRIES))
+  Err(Error::transport(format!(
+    "{} after {} retries",
+    last_error, MAX_RETRIES
+  )))
 }
 
 #[tauri::command]
-pub async fn disconnect() -> Result<(), String> {
-  tauri_plugin_blec::get_handler()
-    .map_err(|e| format!("BLE unavailable: {:?}", e))?
+pub async fn disconnect(devices: State<'_, DeviceRegistry>, device: String) -> Result<(), Error> {
+  devices.close(&device).await.ok();
+  // 关闭串口或网关设备时，不能断开属于其他设备的 BLE 连接
+  if !release_link(&BLE_LINK, &device) {
+    return Ok(());
+  }
+  ble_handler()?
     .disconnect()
-    .map_err(|e| format!("BLE disconnect failed: {:?}", e))
+    .map_err(|e| Error::transport(format!("BLE disconnect failed: {:?}", e)))
     .await
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_the_owner_releases_the_ble_link() {
+    let link = Mutex::new(Some("AA:BB".to_string()));
+
+    assert!(!release_link(&link, "/dev/ttyUSB0"));
+    assert_eq!(link.lock().unwrap().as_deref(), Some("AA:BB"));
+
+    assert!(release_link(&link, "AA:BB"));
+    assert_eq!(*link.lock().unwrap(), None);
+    assert!(!release_link(&link, "AA:BB"));
+  }
+}
@@ -0,0 +1,473 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+use super::{Transfer, dispatch_notification};
+use crate::error::Error;
+use crate::ota::sample::{DFU_ACK_PATTERN, DFU_PAGE_LEN, DFU_PREAMBLE, McuDfuState};
+
+type NotifyCallback = Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>;
+
+const BLOCK_HEADER_LEN: usize = 64 + 4;
+// 续传请求：块数后跟镜像 SHA-256
+const RESUME_REQUEST_LEN: usize = 4 + 32;
+
+// 在指定块注入的故障，块号从0开始，在收到该块的块头时触发
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BootFault {
+  /// Report `McuDfuState::Fault` instead of accepting the block header.
+  /// Fires once, like a reset clearing a transient fault.
+  Fault { block: usize },
+  /// Stop answering anything from the block header on.
+  Stall { block: usize },
+  /// Lose the state notification that answers the block header.
+  DropNotification { block: usize },
+}
+
+impl BootFault {
+  fn block(&self) -> usize {
+    match self {
+      BootFault::Fault { block }
+      | BootFault::Stall { block }
+      | BootFault::DropNotification { block } => *block,
+    }
+  }
+}
+
+struct BootState {
+  dfu: McuDfuState,
+  awaiting_ack: bool,
+  stalled: bool,
+  completed: bool,
+  total_blocks: usize,
+  block: usize,
+  block_size: usize,
+  buffer: Vec<u8>,
+  flash: Vec<u8>,
+  image_len: usize,
+  signatures: Vec<[u8; 64]>,
+  faults: Vec<BootFault>,
+  // 最近一次带哈希启动的镜像，以及其中已写入的块数
+  image_hash: Option<[u8; 32]>,
+  written_blocks: usize,
+  resume_report: Option<u32>,
+}
+
+impl BootState {
+  fn new() -> Self {
+    BootState {
+      dfu: McuDfuState::Idle,
+      awaiting_ack: false,
+      stalled: false,
+      completed: false,
+      total_blocks: 0,
+      block: 0,
+      block_size: 0,
+      buffer: Vec::new(),
+      flash: Vec::new(),
+      image_len: 0,
+      signatures: Vec::new(),
+      faults: Vec::new(),
+      image_hash: None,
+      written_blocks: 0,
+      resume_report: None,
+    }
+  }
+
+  // 返回需要通知的状态，None 表示不回复
+  fn receive(&mut self, data: &[u8]) -> Option<McuDfuState> {
+    if self.stalled {
+      return None;
+    }
+    if self.awaiting_ack {
+      if data != DFU_ACK_PATTERN.to_le_bytes() {
+        log::warn!(
+          "Bootloader expected ACK in {:?}, got {} bytes",
+          self.dfu,
+          data.len()
+        );
+        return None;
+      }
+      self.awaiting_ack = false;
+      return match self.dfu {
+        McuDfuState::Verify => {
+          let offset = self.block * DFU_PAGE_LEN;
+          self.flash[offset..offset + self.buffer.len()].copy_from_slice(&self.buffer);
+          self.image_len = offset + self.buffer.len();
+          Some(McuDfuState::Write)
+        }
+        McuDfuState::Write => {
+          self.block += 1;
+          self.written_blocks = self.block;
+          if self.block < self.total_blocks {
+            Some(McuDfuState::Header)
+          } else {
+            Some(McuDfuState::Final)
+          }
+        }
+        McuDfuState::Final => {
+          self.completed = true;
+          None
+        }
+        _ => None,
+      };
+    }
+
+    match self.dfu {
+      // 故障后 MCU 复位，重新等待前导码，已写入的 flash 保留
+      McuDfuState::Idle | McuDfuState::Fault if data == DFU_PREAMBLE => Some(McuDfuState::Prepare),
+      McuDfuState::Prepare if data.len() == 4 || data.len() == RESUME_REQUEST_LEN => {
+        let total_blocks = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if total_blocks == 0 {
+          return Some(McuDfuState::Fault);
+        }
+        let hash: Option<[u8; 32]> = data[4..].try_into().ok();
+        let resumable = hash.is_some()
+          && hash == self.image_hash
+          && total_blocks == self.total_blocks
+          && self.written_blocks < total_blocks;
+        if !resumable {
+          self.total_blocks = total_blocks;
+          self.flash = vec![0xFF; total_blocks * DFU_PAGE_LEN];
+          self.image_len = 0;
+          self.signatures.clear();
+          self.written_blocks = 0;
+        }
+        self.image_hash = hash;
+        self.block = self.written_blocks;
+        self.signatures.truncate(self.written_blocks);
+        // 只有续传请求才回复块数，旧的握手保持单字节状态
+        self.resume_report = hash.map(|_| self.written_blocks as u32);
+        Some(McuDfuState::Header)
+      }
+      McuDfuState::Header if data.len() == BLOCK_HEADER_LEN => {
+        let fault = self
+          .faults
+          .iter()
+          .find(|f| f.block() == self.block)
+          .copied();
+        match fault {
+          Some(fault @ BootFault::Fault { .. }) => {
+            self.faults.retain(|f| *f != fault);
+            return Some(McuDfuState::Fault);
+          }
+          Some(BootFault::Stall { .. }) => {
+            self.stalled = true;
+            return None;
+          }
+          _ => {}
+        }
+        let mut signature = [0u8; 64];
+        signature.copy_from_slice(&data[..64]);
+        self.signatures.push(signature);
+        self.block_size = u32::from_le_bytes([data[64], data[65], data[66], data[67]]) as usize;
+        self.buffer.clear();
+        if self.block_size == 0 || self.block_size > DFU_PAGE_LEN {
+          return Some(McuDfuState::Fault);
+        }
+        Some(McuDfuState::Data)
+      }
+      McuDfuState::Data => {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() > self.block_size {
+          Some(McuDfuState::Fault)
+        } else if self.buffer.len() == self.block_size {
+          Some(McuDfuState::Verify)
+        } else {
+          None
+        }
+      }
+      _ => {
+        log::debug!("Bootloader ignored {} bytes in {:?}", data.len(), self.dfu);
+        None
+      }
+    }
+  }
+
+  // 进入新状态，故障状态无需应答
+  fn enter(&mut self, state: McuDfuState) -> bool {
+    self.dfu = state;
+    self.awaiting_ack = state != McuDfuState::Fault;
+    let drop = state == McuDfuState::Data
+      && self
+        .faults
+        .contains(&BootFault::DropNotification { block: self.block });
+    if drop {
+      self
+        .faults
+        .retain(|f| *f != BootFault::DropNotification { block: self.block });
+      log::warn!("Bootloader dropped {:?} notification", state);
+    }
+    !drop
+  }
+}
+
+/// Simulated MCU bootloader behind `Transfer` for the `SampleOta` DFU protocol.
+///
+/// It consumes the preamble, total block count, block headers and chunked
+/// data, answers each step with its `McuDfuState` and waits for
+/// `DFU_ACK_PATTERN` before moving on. Accepted blocks are written into a
+/// virtual flash buffer that can be compared with the input image.
+///
+/// A block count followed by the image hash asks to resume: if the hash
+/// matches the last image, the `Header` notification carries the number of
+/// blocks already written and the transfer continues from there.
+pub struct SimBootloader {
+  mtu: usize,
+  state: Mutex<BootState>,
+  callback: Mutex<Option<NotifyCallback>>,
+}
+
+impl SimBootloader {
+  pub fn new(mtu: usize) -> Self {
+    SimBootloader {
+      mtu,
+      state: Mutex::new(BootState::new()),
+      callback: Mutex::new(None),
+    }
+  }
+
+  pub fn inject(&self, fault: BootFault) {
+    self.state.lock().unwrap().faults.push(fault);
+  }
+
+  /// Bytes written to the virtual flash so far.
+  pub fn flashed(&self) -> Vec<u8> {
+    let state = self.state.lock().unwrap();
+    state.flash[..state.image_len].to_vec()
+  }
+
+  pub fn signatures(&self) -> Vec<[u8; 64]> {
+    self.state.lock().unwrap().signatures.clone()
+  }
+
+  pub fn is_completed(&self) -> bool {
+    self.state.lock().unwrap().completed
+  }
+
+  #[cfg(test)]
+  pub fn state(&self) -> McuDfuState {
+    self.state.lock().unwrap().dfu
+  }
+
+  /// Re-sends the current state, like the bootloader's periodic status report.
+  #[cfg(test)]
+  pub async fn resend_state(&self) {
+    let state = self.state.lock().unwrap().dfu;
+    self.notify_raw(vec![state as u8]).await;
+  }
+
+  async fn notify_raw(&self, data: Vec<u8>) {
+    let callback = self.callback.lock().unwrap().clone();
+    if let Some(cb) = callback {
+      dispatch_notification(cb, data).await;
+    }
+  }
+}
+
+#[async_trait]
+impl Transfer for SimBootloader {
+  fn get_mtu(&self) -> usize {
+    self.mtu
+  }
+
+  async fn activate(&self) -> Result<(), Error> {
+    Ok(())
+  }
+
+  async fn deactivate(&self) -> Result<(), Error> {
+    *self.callback.lock().unwrap() = None;
+    Ok(())
+  }
+
+  async fn is_actived(&self) -> Result<bool, Error> {
+    Ok(true)
+  }
+
+  async fn send(&self, data: &[u8]) -> Result<(), Error> {
+    if data.len() > self.mtu {
+      return Err(Error::mtu_exceeded(data.len(), self.mtu));
+    }
+    let reply = {
+      let mut state = self.state.lock().unwrap();
+      match state.receive(data) {
+        Some(next) if state.enter(next) => {
+          let mut reply = vec![next as u8];
+          if let Some(block) = state.resume_report.take() {
+            reply.extend_from_slice(&block.to_le_bytes());
+          }
+          Some(reply)
+        }
+        _ => None,
+      }
+    };
+    if let Some(reply) = reply {
+      self.notify_raw(reply).await;
+    }
+    Ok(())
+  }
+
+  async fn read(&self) -> Result<Vec<u8>, Error> {
+    Ok(vec![self.state.lock().unwrap().dfu as u8])
+  }
+
+  async fn subscribe(
+    &self,
+    callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+  ) -> Result<(), Error> {
+    *self.callback.lock().unwrap() = Some(callback);
+    Ok(())
+  }
+
+  async fn unsubscribe(&self) -> Result<(), Error> {
+    *self.callback.lock().unwrap() = None;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::ErrorKind;
+  use crate::ota::sample::SampleOta;
+  use std::time::Duration;
+
+  fn image(len: usize) -> Arc<Vec<u8>> {
+    Arc::new((0..len).map(|i| (i * 7 % 256) as u8).collect())
+  }
+
+  #[tokio::test]
+  async fn flashed_bytes_equal_input_image() {
+    let bootloader = Arc::new(SimBootloader::new(180));
+    let firmware = image(DFU_PAGE_LEN * 2 + 321);
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let sink = progress.clone();
+
+    SampleOta::new(bootloader.clone())
+      .run(firmware.clone(), move |p| sink.lock().unwrap().push(p))
+      .await
+      .unwrap();
+
+    assert!(bootloader.is_completed());
+    assert_eq!(bootloader.flashed(), *firmware);
+    assert_eq!(bootloader.signatures().len(), 3);
+    assert_eq!(progress.lock().unwrap().last(), Some(&100));
+  }
+
+  #[tokio::test]
+  async fn package_signatures_fill_block_headers() {
+    use crate::ota::Ota;
+    use crate::ota::package::{FirmwarePackage, FirmwareTarget};
+
+    let bootloader = Arc::new(SimBootloader::new(180));
+    let target = FirmwareTarget {
+      model: "SIM-VALVE".to_string(),
+      hardware: "sim".to_string(),
+      version: "2.0.0".to_string(),
+      min_bootloader: "1.0.0".to_string(),
+    };
+    let signatures = vec![[0x11; 64], [0x22; 64]];
+    let package =
+      FirmwarePackage::new(target, image(DFU_PAGE_LEN + 5).to_vec(), signatures.clone());
+
+    SampleOta::new(bootloader.clone())
+      .start_ota(Arc::new(package.to_bytes()), &|_| {})
+      .await
+      .unwrap();
+
+    assert_eq!(bootloader.flashed(), package.image);
+    assert_eq!(bootloader.signatures(), signatures);
+  }
+
+  #[tokio::test]
+  async fn fault_at_block_aborts_without_retry() {
+    let bootloader = Arc::new(SimBootloader::new(180));
+    bootloader.inject(BootFault::Fault { block: 1 });
+
+    let result = SampleOta::new(bootloader.clone())
+      .run(image(DFU_PAGE_LEN * 3), |_| {})
+      .await;
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, ErrorKind::DeviceFault);
+    assert_eq!(error.message, "OTA process failed by MCU fault");
+    assert_eq!(bootloader.flashed().len(), DFU_PAGE_LEN);
+  }
+
+  #[tokio::test]
+  async fn resumes_after_fault_from_last_written_block() {
+    use crate::ota::progress::OtaProgressStore;
+
+    let bootloader = Arc::new(SimBootloader::new(180));
+    bootloader.inject(BootFault::Fault { block: 2 });
+    let firmware = image(DFU_PAGE_LEN * 3 + 10);
+    let store = Arc::new(OtaProgressStore::new());
+    let device: crate::commands::identify::DeviceInfo = serde_json::from_value(serde_json::json!({
+      "model": "SIM-VALVE",
+      "hardware": "sim",
+      "firmware": "1.0.0",
+      "capabilities": ["ota", "ota_resume"],
+    }))
+    .unwrap();
+    let ota = || {
+      SampleOta::new(bootloader.clone())
+        .with_device(Some(device.clone()))
+        .with_progress(store.clone(), "SN1".to_string())
+    };
+
+    let result = ota().run(firmware.clone(), |_| {}).await;
+    assert_eq!(result.unwrap_err().kind, ErrorKind::DeviceFault);
+    assert_eq!(store.get("SN1").unwrap().confirmed_blocks, 2);
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let sink = progress.clone();
+    ota()
+      .run(firmware.clone(), move |p| sink.lock().unwrap().push(p))
+      .await
+      .unwrap();
+
+    assert_eq!(bootloader.flashed(), *firmware);
+    assert_eq!(bootloader.signatures().len(), 4);
+    // 续传只发送剩余两块
+    assert_eq!(*progress.lock().unwrap(), vec![75, 100]);
+    assert_eq!(store.get("SN1"), None);
+  }
+
+  #[tokio::test]
+  async fn stall_at_block_stops_progress() {
+    let bootloader = Arc::new(SimBootloader::new(180));
+    bootloader.inject(BootFault::Stall { block: 1 });
+    let mut ota = SampleOta::new(bootloader.clone());
+
+    let result = tokio::time::timeout(
+      Duration::from_secs(3),
+      ota.run(image(DFU_PAGE_LEN * 2), |_| {}),
+    )
+    .await;
+
+    assert!(result.is_err());
+    assert_eq!(bootloader.state(), McuDfuState::Header);
+    assert_eq!(bootloader.flashed().len(), DFU_PAGE_LEN);
+  }
+
+  #[tokio::test]
+  async fn dropped_notification_recovers_after_resend() {
+    let bootloader = Arc::new(SimBootloader::new(180));
+    bootloader.inject(BootFault::DropNotification { block: 0 });
+    let firmware = image(DFU_PAGE_LEN + 10);
+    let mut ota = SampleOta::new(bootloader.clone());
+    let flashing = {
+      let firmware = firmware.clone();
+      tokio::spawn(async move { ota.run(firmware, |_| {}).await })
+    };
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(bootloader.state(), McuDfuState::Data);
+    assert!(bootloader.flashed().is_empty());
+    bootloader.resend_state().await;
+
+    flashing.await.unwrap().unwrap();
+    assert_eq!(bootloader.flashed(), *firmware);
+  }
+}
@@ -0,0 +1,89 @@
+use std::sync::Mutex;
+
+struct WindowState {
+  credits: usize,
+  window: usize,
+}
+
+/// Credit window for writes without response.
+///
+/// Each unacknowledged write spends a credit. When the credits run out the
+/// next write must be acknowledged; the acknowledgement shows the peer has
+/// drained everything before it, refills the credits and widens the window by
+/// one. A rejected unacknowledged write means the peer's buffer is full: the
+/// window is halved and no credits are left until the next acknowledgement.
+pub struct CreditWindow {
+  state: Mutex<WindowState>,
+  max: usize,
+}
+
+impl CreditWindow {
+  pub fn new(window: usize, max: usize) -> Self {
+    let window = window.clamp(1, max.max(1));
+    CreditWindow {
+      state: Mutex::new(WindowState {
+        credits: window,
+        window,
+      }),
+      max: max.max(1),
+    }
+  }
+
+  /// Takes a credit, or returns `false` if this write has to be acknowledged.
+  pub fn try_take(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+    if state.credits == 0 {
+      return false;
+    }
+    state.credits -= 1;
+    true
+  }
+
+  pub fn acknowledged(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.window = (state.window + 1).min(self.max);
+    state.credits = state.window;
+  }
+
+  pub fn congested(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.window = (state.window / 2).max(1);
+    state.credits = 0;
+  }
+
+  pub fn window(&self) -> usize {
+    self.state.lock().unwrap().window
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn spent_window_requires_acknowledgement() {
+    let window = CreditWindow::new(2, 4);
+
+    assert!(window.try_take());
+    assert!(window.try_take());
+    assert!(!window.try_take());
+
+    window.acknowledged();
+    assert_eq!(window.window(), 3);
+    assert!((0..3).all(|_| window.try_take()));
+    assert!(!window.try_take());
+  }
+
+  #[test]
+  fn congestion_halves_window_until_acknowledged() {
+    let window = CreditWindow::new(8, 8);
+
+    window.congested();
+    assert_eq!(window.window(), 4);
+    assert!(!window.try_take());
+
+    window.acknowledged();
+    assert_eq!(window.window(), 5);
+    assert!(window.try_take());
+  }
+}
@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "gui")]
+use crate::error::Error;
+
+// 缓冲区超过此长度仍未凑成完整帧时丢弃，避免丢失分隔符后无限增长
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// How the device marks message boundaries in its notification stream.
+///
+/// Only text-protocol messages go through framing; binary frames carry their
+/// own length and CRC.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Framing {
+  /// Every notification is one complete message.
+  #[default]
+  Notification,
+  /// Messages end with `delimiter`, which is stripped.
+  Delimited { delimiter: Vec<u8> },
+  /// Messages start with their length as a u16 LE, which is stripped.
+  LengthPrefixed,
+}
+
+#[cfg(feature = "gui")]
+impl Framing {
+  pub fn validate(&self) -> Result<(), Error> {
+    match self {
+      Framing::Delimited { delimiter } if delimiter.is_empty() => {
+        Err(Error::invalid_data("Frame delimiter must not be empty"))
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Turns notifications into complete messages.
+///
+/// A message may span several notifications and one notification may carry
+/// several messages; partial data is buffered until the rest arrives.
+pub struct Reassembler {
+  framing: Framing,
+  buffer: Vec<u8>,
+}
+
+impl Reassembler {
+  pub fn new(framing: Framing) -> Self {
+    Reassembler {
+      framing,
+      buffer: Vec::new(),
+    }
+  }
+
+  #[cfg(feature = "gui")]
+  pub fn framing(&self) -> &Framing {
+    &self.framing
+  }
+
+  /// Buffers `data` and returns every message it completes, in order.
+  pub fn push(&mut self, data: Vec<u8>) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    match &self.framing {
+      Framing::Notification => frames.push(data),
+      Framing::Delimited { delimiter } => {
+        // 从上次可能截断分隔符的位置继续查找
+        let mut from = self.buffer.len().saturating_sub(delimiter.len() - 1);
+        self.buffer.extend_from_slice(&data);
+        while let Some(at) = find(&self.buffer[from..], delimiter) {
+          let end = from + at;
+          frames.push(self.buffer[..end].to_vec());
+          self.buffer.drain(..end + delimiter.len());
+          from = 0;
+        }
+      }
+      Framing::LengthPrefixed => {
+        self.buffer.extend_from_slice(&data);
+        while let [l0, l1, rest @ ..] = self.buffer.as_slice() {
+          let len = u16::from_le_bytes([*l0, *l1]) as usize;
+          if rest.len() < len {
+            break;
+          }
+          frames.push(rest[..len].to_vec());
+          self.buffer.drain(..2 + len);
+        }
+      }
+    }
+    if self.buffer.len() > MAX_FRAME_LEN {
+      log::warn!(
+        "Discarding {} bytes without a frame boundary",
+        self.buffer.len()
+      );
+      self.buffer.clear();
+    }
+    frames
+  }
+
+  /// Drops any partial message, e.g. after the link was lost.
+  pub fn reset(&mut self) {
+    self.buffer.clear();
+  }
+}
+
+impl Default for Reassembler {
+  fn default() -> Self {
+    Self::new(Framing::default())
+  }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack
+    .windows(needle.len())
+    .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn delimited_messages_span_and_share_notifications() {
+    let mut reassembler = Reassembler::new(Framing::Delimited {
+      delimiter: b"\r\n".to_vec(),
+    });
+
+    assert!(reassembler.push(b"{\"a\":".to_vec()).is_empty());
+    assert!(reassembler.push(b"1}\r".to_vec()).is_empty());
+    assert_eq!(
+      reassembler.push(b"\n{}\r\n{\"b\"".to_vec()),
+      vec![b"{\"a\":1}".to_vec(), b"{}".to_vec()]
+    );
+    assert_eq!(
+      reassembler.push(b":2}\r\n".to_vec()),
+      vec![b"{\"b\":2}".to_vec()]
+    );
+  }
+
+  #[test]
+  fn default_passes_unterminated_binary_through() {
+    let mut reassembler = Reassembler::default();
+    // 固件的 ACK 和 8 字节采样都没有结束符，采样里可能出现 0d 0a
+    let sample = vec![0x0d, 0x0a, 0, 0, 1, 0, 0, 0];
+
+    assert_eq!(reassembler.push(vec![0xfe, 0xca]), vec![vec![0xfe, 0xca]]);
+    assert_eq!(reassembler.push(sample.clone()), vec![sample]);
+  }
+
+  #[test]
+  fn length_prefix_may_be_split() {
+    let mut reassembler = Reassembler::new(Framing::LengthPrefixed);
+
+    assert!(reassembler.push(vec![3]).is_empty());
+    assert!(reassembler.push(vec![0, 1, 2]).is_empty());
+    assert_eq!(
+      reassembler.push(vec![3, 0, 0, 1, 0, 9]),
+      vec![vec![1, 2, 3], vec![], vec![9]]
+    );
+
+    reassembler.push(vec![5, 0, 1]);
+    reassembler.reset();
+    assert_eq!(reassembler.push(vec![1, 0, 7]), vec![vec![7]]);
+  }
+}
@@ -0,0 +1,240 @@
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::framing::Framing;
+use super::{Transfer, dispatch_notification};
+use crate::error::Error;
+
+type NotifyCallback = Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>;
+type Script = Vec<(Vec<u8>, Vec<Vec<u8>>)>;
+
+/// Builds a text-protocol reply: the little-endian `code` followed by `payload`.
+pub fn ack(code: u16, payload: &[u8]) -> Vec<u8> {
+  let mut frame = code.to_le_bytes().to_vec();
+  frame.extend_from_slice(payload);
+  frame
+}
+
+/// Returns a frame handler that forwards everything it is given to the receiver.
+pub fn collector() -> (mpsc::UnboundedReceiver<Vec<u8>>, NotifyCallback) {
+  let (tx, rx) = mpsc::unbounded_channel();
+  (
+    rx,
+    Arc::new(move |data: Vec<u8>| {
+      let _ = tx.send(data);
+    }),
+  )
+}
+
+/// Waits up to a second for the next frame a `collector` handler was given.
+///
+/// Handlers run after the ack wakes the request, so tests wait for the data
+/// instead of checking right away.
+pub async fn received(rx: &mut mpsc::UnboundedReceiver<Vec<u8>>) -> Vec<u8> {
+  tokio::time::timeout(Duration::from_secs(1), rx.recv())
+    .await
+    .expect("no frame was delivered")
+    .unwrap()
+}
+
+/// In-memory `Transfer` for exercising commands and OTA without a radio.
+///
+/// Outbound writes are recorded, notifications can be injected at any time and
+/// responses can be scripted per request, e.g. "on `ping\r\n` notify `0xcafe`".
+pub struct MockTransfer {
+  mtu: usize,
+  framing: Framing,
+  active: AtomicBool,
+  fail_sends: AtomicUsize,
+  fail_activations: AtomicUsize,
+  callback: Mutex<Option<NotifyCallback>>,
+  writes: Mutex<Vec<Vec<u8>>>,
+  unacknowledged: AtomicUsize,
+  script: Mutex<Script>,
+}
+
+impl Default for MockTransfer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl MockTransfer {
+  pub fn new() -> Self {
+    MockTransfer {
+      mtu: 244,
+      // 脚本里的每条通知都是完整消息
+      framing: Framing::Notification,
+      active: AtomicBool::new(true),
+      fail_sends: AtomicUsize::new(0),
+      fail_activations: AtomicUsize::new(0),
+      callback: Mutex::new(None),
+      writes: Mutex::new(Vec::new()),
+      unacknowledged: AtomicUsize::new(0),
+      script: Mutex::new(Vec::new()),
+    }
+  }
+
+  pub fn with_mtu(mut self, mtu: usize) -> Self {
+    self.mtu = mtu;
+    self
+  }
+
+  pub fn with_framing(mut self, framing: Framing) -> Self {
+    self.framing = framing;
+    self
+  }
+
+  /// Notifies every frame in `notifications` whenever `request` is written.
+  pub fn on(&self, request: impl AsRef<[u8]>, notifications: Vec<Vec<u8>>) -> &Self {
+    self
+      .script
+      .lock()
+      .unwrap()
+      .push((request.as_ref().to_vec(), notifications));
+    self
+  }
+
+  /// Makes the next `count` calls to `send` fail without recording the write.
+  pub fn fail_next_sends(&self, count: usize) {
+    self.fail_sends.store(count, Ordering::SeqCst);
+  }
+
+  #[cfg(feature = "gui")]
+  /// Makes the next `count` calls to `activate` fail, as if out of range.
+  pub fn fail_next_activations(&self, count: usize) {
+    self.fail_activations.store(count, Ordering::SeqCst);
+  }
+
+  pub fn writes(&self) -> Vec<Vec<u8>> {
+    self.writes.lock().unwrap().clone()
+  }
+
+  pub fn clear_writes(&self) {
+    self.writes.lock().unwrap().clear();
+    self.unacknowledged.store(0, Ordering::SeqCst);
+  }
+
+  /// How many of the recorded writes went through `send_without_response`.
+  pub fn unacknowledged_writes(&self) -> usize {
+    self.unacknowledged.load(Ordering::SeqCst)
+  }
+
+  pub fn is_subscribed(&self) -> bool {
+    self.callback.lock().unwrap().is_some()
+  }
+
+  /// Delivers a notification to the current subscriber, if any.
+  ///
+  /// Like the BLE handler, the callback runs on a blocking thread so it may use
+  /// `blocking_send`.
+  pub async fn notify(&self, data: impl Into<Vec<u8>>) {
+    let callback = self.callback.lock().unwrap().clone();
+    deliver(callback, data.into()).await;
+  }
+
+  fn responses_for(&self, data: &[u8]) -> Vec<Vec<u8>> {
+    self
+      .script
+      .lock()
+      .unwrap()
+      .iter()
+      .find(|(request, _)| request.as_slice() == data)
+      .map(|(_, notifications)| notifications.clone())
+      .unwrap_or_default()
+  }
+}
+
+async fn deliver(callback: Option<NotifyCallback>, data: Vec<u8>) {
+  if let Some(cb) = callback {
+    dispatch_notification(cb, data).await;
+  }
+}
+
+#[async_trait]
+impl Transfer for MockTransfer {
+  fn get_mtu(&self) -> usize {
+    self.mtu
+  }
+
+  fn framing(&self) -> Framing {
+    self.framing.clone()
+  }
+
+  async fn activate(&self) -> Result<(), Error> {
+    if self
+      .fail_activations
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+      .is_ok()
+    {
+      return Err(Error::transport("Mock activation failure"));
+    }
+    self.active.store(true, Ordering::SeqCst);
+    Ok(())
+  }
+
+  async fn deactivate(&self) -> Result<(), Error> {
+    self.active.store(false, Ordering::SeqCst);
+    *self.callback.lock().unwrap() = None;
+    Ok(())
+  }
+
+  async fn is_actived(&self) -> Result<bool, Error> {
+    Ok(self.active.load(Ordering::SeqCst))
+  }
+
+  async fn send(&self, data: &[u8]) -> Result<(), Error> {
+    if !self.active.load(Ordering::SeqCst) {
+      return Err(Error::not_connected());
+    }
+    if self
+      .fail_sends
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+      .is_ok()
+    {
+      return Err(Error::transport("Mock send failure"));
+    }
+    if data.len() > self.mtu {
+      return Err(Error::mtu_exceeded(data.len(), self.mtu));
+    }
+    self.writes.lock().unwrap().push(data.to_vec());
+
+    let notifications = self.responses_for(data);
+    if !notifications.is_empty() {
+      // 模拟设备异步回复，避免在发送路径上阻塞
+      let callback = self.callback.lock().unwrap().clone();
+      tokio::spawn(async move {
+        for notification in notifications {
+          deliver(callback.clone(), notification).await;
+        }
+      });
+    }
+    Ok(())
+  }
+
+  async fn send_without_response(&self, data: &[u8]) -> Result<(), Error> {
+    self.send(data).await?;
+    self.unacknowledged.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+  }
+
+  async fn read(&self) -> Result<Vec<u8>, Error> {
+    Ok(Vec::new())
+  }
+
+  async fn subscribe(
+    &self,
+    callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+  ) -> Result<(), Error> {
+    *self.callback.lock().unwrap() = Some(callback);
+    Ok(())
+  }
+
+  async fn unsubscribe(&self) -> Result<(), Error> {
+    *self.callback.lock().unwrap() = None;
+    Ok(())
+  }
+}
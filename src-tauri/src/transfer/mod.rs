@@ -1,19 +1,160 @@
 use async_trait::async_trait;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::Error;
+use framing::Framing;
 
 #[async_trait]
 pub trait Transfer: Send + Sync {
   fn get_mtu(&self) -> usize;
-  async fn activate(&self) -> Result<(), String>;
-  async fn deactivate(&self) -> Result<(), String>;
-  async fn is_actived(&self) -> Result<bool, String>;
-  async fn send(&self, data: &[u8]) -> Result<(), String>;
-  async fn read(&self) -> Result<Vec<u8>, String>;
+  /// How text messages arrive over this link when nothing else is configured.
+  fn framing(&self) -> Framing {
+    Framing::default()
+  }
+  async fn activate(&self) -> Result<(), Error>;
+  async fn deactivate(&self) -> Result<(), Error>;
+  async fn is_actived(&self) -> Result<bool, Error>;
+  async fn send(&self, data: &[u8]) -> Result<(), Error>;
+  /// Bulk write that may skip the link-layer acknowledgement. Links without
+  /// such a mode fall back to `send`.
+  async fn send_without_response(&self, data: &[u8]) -> Result<(), Error> {
+    self.send(data).await
+  }
+  #[allow(dead_code)]
+  async fn read(&self) -> Result<Vec<u8>, Error>;
   async fn subscribe(
     &self,
     callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
-  ) -> Result<(), String>;
-  async fn unsubscribe(&self) -> Result<(), String>;
+  ) -> Result<(), Error>;
+  async fn unsubscribe(&self) -> Result<(), Error>;
+}
+
+// 与 BLE handler 一致，通知回调在阻塞线程中执行，回调内可使用 blocking_send
+pub(crate) async fn dispatch_notification(
+  callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+  data: Vec<u8>,
+) {
+  if let Err(e) = tokio::task::spawn_blocking(move || callback(data)).await {
+    log::error!("Notify callback panicked: {}", e);
+  }
 }
 
+// 常见 ATT MTU 对应的单次写入上限：247（数据长度扩展）、185（iOS）、23（协议最小值）
+pub(crate) const ATT_PAYLOAD_CANDIDATES: [usize; 3] = [247 - 3, 185 - 3, 23 - 3];
+
+/// Writes `data` in pieces of at most `mtu` bytes.
+///
+/// A rejected piece lowers `mtu` to the next smaller ATT candidate and is
+/// retried, so an over-estimated MTU is corrected by the first failed write
+/// and stays corrected for later sends.
+pub(crate) async fn send_fragmented<F>(
+  data: &[u8],
+  mtu: &AtomicUsize,
+  mut write: impl FnMut(Vec<u8>) -> F,
+) -> Result<(), Error>
+where
+  F: Future<Output = Result<(), Error>>,
+{
+  let mut offset = 0;
+  while offset < data.len() {
+    let current = mtu.load(Ordering::SeqCst);
+    let end = (offset + current).min(data.len());
+    match write(data[offset..end].to_vec()).await {
+      Ok(()) => offset = end,
+      Err(e) => match ATT_PAYLOAD_CANDIDATES.iter().find(|&&c| c < current) {
+        Some(&smaller) if end - offset > smaller => {
+          log::warn!(
+            "Write of {} bytes rejected ({}), lowering MTU to {}",
+            end - offset,
+            e,
+            smaller
+          );
+          mtu.store(smaller, Ordering::SeqCst);
+        }
+        _ => return Err(e),
+      },
+    }
+  }
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+pub mod advertisement;
+#[cfg(feature = "gui")]
 pub mod ble;
+pub mod bootloader;
+#[cfg(feature = "gui")]
+pub mod flow;
+pub mod framing;
+#[cfg(test)]
+pub mod mock;
+#[cfg(feature = "gui")]
+pub mod profile;
+pub mod serial;
+pub mod sim;
+#[cfg(feature = "gui")]
+pub mod tcp;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  #[tokio::test]
+  async fn fragments_payloads_larger_than_mtu() {
+    let mtu = AtomicUsize::new(4);
+    let writes = Mutex::new(Vec::new());
+
+    send_fragmented(b"valve_info 1\r\n", &mtu, |chunk: Vec<u8>| async {
+      writes.lock().unwrap().push(chunk);
+      Ok(())
+    })
+    .await
+    .unwrap();
+
+    let writes = writes.into_inner().unwrap();
+    assert_eq!(writes.len(), 4);
+    assert_eq!(writes.concat(), b"valve_info 1\r\n");
+  }
+
+  #[tokio::test]
+  async fn rejected_write_lowers_mtu_and_retries() {
+    let mtu = AtomicUsize::new(ATT_PAYLOAD_CANDIDATES[0]);
+    let writes = Mutex::new(Vec::new());
+    let data = vec![0x5a; 300];
+
+    // 对端只接受 185 字节的 ATT MTU
+    send_fragmented(&data, &mtu, |chunk: Vec<u8>| {
+      let accepted = chunk.len() <= 185 - 3;
+      if accepted {
+        writes.lock().unwrap().push(chunk.len());
+      }
+      async move {
+        if accepted {
+          Ok(())
+        } else {
+          Err(Error::transport("Attribute length invalid"))
+        }
+      }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(mtu.load(Ordering::SeqCst), 185 - 3);
+    assert_eq!(writes.into_inner().unwrap(), vec![182, 118]);
+  }
+
+  #[tokio::test]
+  async fn failure_at_minimum_mtu_is_returned() {
+    let mtu = AtomicUsize::new(ATT_PAYLOAD_CANDIDATES[0]);
+
+    let result = send_fragmented(b"ping\r\n", &mtu, |_| async {
+      Err(Error::transport("BLE send failed"))
+    })
+    .await;
+
+    assert_eq!(result.unwrap_err().message, "BLE send failed");
+    assert_eq!(mtu.load(Ordering::SeqCst), ATT_PAYLOAD_CANDIDATES[0]);
+  }
+}
@@ -0,0 +1,298 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tauri::State;
+use uuid::Uuid;
+
+use crate::error::{Error, ErrorKind};
+
+/// Advertisement fields that identify a module. Every field that is set must
+/// match; a matcher with no fields set never matches by advertisement.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileMatch {
+  pub name_prefix: Option<String>,
+  pub service: Option<Uuid>,
+  pub manufacturer_id: Option<u16>,
+}
+
+impl ProfileMatch {
+  fn is_empty(&self) -> bool {
+    self.name_prefix.is_none() && self.service.is_none() && self.manufacturer_id.is_none()
+  }
+
+  fn matches(&self, device: &tauri_plugin_blec::models::BleDevice) -> bool {
+    !self.is_empty()
+      && self
+        .name_prefix
+        .as_ref()
+        .is_none_or(|prefix| device.name.starts_with(prefix.as_str()))
+      && self
+        .service
+        .is_none_or(|service| device.services.contains(&service))
+      && self
+        .manufacturer_id
+        .is_none_or(|id| device.manufacturer_data.contains_key(&id))
+  }
+}
+
+/// Where a UART-style module exposes its data channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GattProfile {
+  pub name: String,
+  pub service: Uuid,
+  pub notify: Uuid,
+  pub write: Uuid,
+  #[serde(default)]
+  pub matches: ProfileMatch,
+}
+
+impl GattProfile {
+  fn builtin(name: &str, service: Uuid, notify: Uuid, write: Uuid, matches: ProfileMatch) -> Self {
+    GattProfile {
+      name: name.to_string(),
+      service,
+      notify,
+      write,
+      matches,
+    }
+  }
+}
+
+fn builtin_profiles() -> Vec<GattProfile> {
+  vec![
+    // 原有模块：ffe1 通知，ffe2 写入
+    GattProfile::builtin(
+      "FFE0",
+      uuid::uuid!("0000ffe0-0000-1000-8000-00805f9b34fb"),
+      uuid::uuid!("0000ffe1-0000-1000-8000-00805f9b34fb"),
+      uuid::uuid!("0000ffe2-0000-1000-8000-00805f9b34fb"),
+      ProfileMatch::default(),
+    ),
+    GattProfile::builtin(
+      "Nordic UART",
+      uuid::uuid!("6e400001-b5a3-f393-e0a9-e50e24dcca9e"),
+      uuid::uuid!("6e400003-b5a3-f393-e0a9-e50e24dcca9e"),
+      uuid::uuid!("6e400002-b5a3-f393-e0a9-e50e24dcca9e"),
+      ProfileMatch {
+        service: Some(uuid::uuid!("6e400001-b5a3-f393-e0a9-e50e24dcca9e")),
+        ..Default::default()
+      },
+    ),
+    GattProfile::builtin(
+      "HM-10",
+      uuid::uuid!("0000ffe0-0000-1000-8000-00805f9b34fb"),
+      uuid::uuid!("0000ffe1-0000-1000-8000-00805f9b34fb"),
+      uuid::uuid!("0000ffe1-0000-1000-8000-00805f9b34fb"),
+      ProfileMatch {
+        name_prefix: Some("HMSoft".to_string()),
+        ..Default::default()
+      },
+    ),
+  ]
+}
+
+/// Built-in and user-defined GATT profiles, user profiles first.
+///
+/// User profiles are stored as JSON so new modules can be supported without
+/// rebuilding the app.
+pub struct ProfileRegistry {
+  custom: RwLock<Vec<GattProfile>>,
+  path: Option<PathBuf>,
+}
+
+impl ProfileRegistry {
+  pub fn new() -> Self {
+    ProfileRegistry {
+      custom: RwLock::new(Vec::new()),
+      path: None,
+    }
+  }
+
+  /// Loads user profiles from `path`, which is also where changes are saved.
+  pub fn load(path: PathBuf) -> Self {
+    let custom = match std::fs::read(&path) {
+      Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+        log::error!("Ignoring invalid GATT profiles in {:?}: {}", path, e);
+        Vec::new()
+      }),
+      Err(_) => Vec::new(),
+    };
+    ProfileRegistry {
+      custom: RwLock::new(custom),
+      path: Some(path),
+    }
+  }
+
+  pub fn profiles(&self) -> Vec<GattProfile> {
+    let mut profiles = self.custom.read().unwrap().clone();
+    profiles.extend(builtin_profiles());
+    profiles
+  }
+
+  /// Picks the profile for a connected device: first by its advertisement,
+  /// then by the first profile whose characteristics the device exposes.
+  pub fn resolve(
+    &self,
+    device: &tauri_plugin_blec::models::BleDevice,
+    characteristics: &[Uuid],
+  ) -> Option<GattProfile> {
+    let profiles = self.profiles();
+    profiles
+      .iter()
+      .find(|profile| profile.matches.matches(device))
+      .or_else(|| {
+        profiles.iter().find(|profile| {
+          characteristics.contains(&profile.notify) && characteristics.contains(&profile.write)
+        })
+      })
+      .cloned()
+  }
+
+  pub fn save(&self, profile: GattProfile) -> Result<(), Error> {
+    {
+      let mut custom = self.custom.write().unwrap();
+      custom.retain(|p| p.name != profile.name);
+      custom.insert(0, profile);
+    }
+    self.persist()
+  }
+
+  pub fn remove(&self, name: &str) -> Result<(), Error> {
+    self.custom.write().unwrap().retain(|p| p.name != name);
+    self.persist()
+  }
+
+  fn persist(&self) -> Result<(), Error> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+    let io_error = |e: std::io::Error| {
+      Error::new(
+        ErrorKind::Io,
+        format!("Failed to save GATT profiles: {}", e),
+      )
+    };
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let json = serde_json::to_vec_pretty(&*self.custom.read().unwrap())
+      .map_err(|e| Error::invalid_data(format!("Serialization failed: {}", e)))?;
+    std::fs::write(path, json).map_err(io_error)
+  }
+}
+
+impl Default for ProfileRegistry {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[tauri::command]
+pub async fn list_gatt_profiles(
+  profiles: State<'_, ProfileRegistry>,
+) -> Result<Vec<GattProfile>, Error> {
+  Ok(profiles.profiles())
+}
+
+#[tauri::command]
+pub async fn save_gatt_profile(
+  profiles: State<'_, ProfileRegistry>,
+  profile: GattProfile,
+) -> Result<(), Error> {
+  log::info!("Saving GATT profile: {:?}", profile);
+  profiles.save(profile)
+}
+
+#[tauri::command]
+pub async fn remove_gatt_profile(
+  profiles: State<'_, ProfileRegistry>,
+  name: String,
+) -> Result<(), Error> {
+  profiles.remove(&name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+  use tauri_plugin_blec::models::BleDevice;
+
+  fn device(name: &str, services: Vec<Uuid>, manufacturer_id: Option<u16>) -> BleDevice {
+    BleDevice {
+      address: "00:11:22:33:44:55".to_string(),
+      name: name.to_string(),
+      is_connected: true,
+      manufacturer_data: manufacturer_id
+        .map(|id| HashMap::from([(id, vec![1, 2])]))
+        .unwrap_or_default(),
+      service_data: HashMap::new(),
+      services,
+      rssi: None,
+    }
+  }
+
+  const NUS: Uuid = uuid::uuid!("6e400001-b5a3-f393-e0a9-e50e24dcca9e");
+  const FFE1: Uuid = uuid::uuid!("0000ffe1-0000-1000-8000-00805f9b34fb");
+  const FFE2: Uuid = uuid::uuid!("0000ffe2-0000-1000-8000-00805f9b34fb");
+
+  #[test]
+  fn advertised_service_selects_nordic_uart() {
+    let profile = ProfileRegistry::new()
+      .resolve(&device("Sensor", vec![NUS], None), &[])
+      .unwrap();
+
+    assert_eq!(profile.name, "Nordic UART");
+    assert_eq!(
+      profile.write,
+      uuid::uuid!("6e400002-b5a3-f393-e0a9-e50e24dcca9e")
+    );
+  }
+
+  #[test]
+  fn characteristics_fall_back_to_the_original_module() {
+    let registry = ProfileRegistry::new();
+
+    let profile = registry
+      .resolve(&device("CYG-01", Vec::new(), None), &[FFE1, FFE2])
+      .unwrap();
+    assert_eq!((profile.notify, profile.write), (FFE1, FFE2));
+
+    let hm10 = registry
+      .resolve(&device("HMSoft", Vec::new(), None), &[FFE1])
+      .unwrap();
+    assert_eq!((hm10.notify, hm10.write), (FFE1, FFE1));
+
+    assert!(
+      registry
+        .resolve(&device("Other", Vec::new(), None), &[])
+        .is_none()
+    );
+  }
+
+  #[test]
+  fn saved_profiles_take_precedence_and_persist() {
+    let path = std::env::temp_dir().join(format!("gatt-profiles-{}.json", std::process::id()));
+    let registry = ProfileRegistry::load(path.clone());
+    let custom = GattProfile {
+      name: "Vendor X".to_string(),
+      service: NUS,
+      notify: FFE1,
+      write: FFE2,
+      matches: ProfileMatch {
+        name_prefix: Some("VX".to_string()),
+        manufacturer_id: Some(0x0059),
+        ..Default::default()
+      },
+    };
+    registry.save(custom.clone()).unwrap();
+
+    let reloaded = ProfileRegistry::load(path.clone());
+    let resolved = reloaded.resolve(&device("VX-7", vec![NUS], Some(0x0059)), &[]);
+    let unmatched = reloaded.resolve(&device("VX-7", vec![NUS], None), &[]);
+    std::fs::remove_file(&path).ok();
+
+    assert_eq!(resolved, Some(custom));
+    assert_eq!(unmatched.unwrap().name, "Nordic UART");
+  }
+}
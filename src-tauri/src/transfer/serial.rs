@@ -0,0 +1,559 @@
+use serde::{Deserialize, Serialize};
+#[cfg(all(feature = "gui", unix))]
+use std::collections::HashMap;
+#[cfg(all(feature = "gui", unix))]
+use std::sync::{Arc, LazyLock, Mutex};
+#[cfg(feature = "gui")]
+use tauri::{Emitter, State};
+
+#[cfg(feature = "gui")]
+use super::Transfer;
+#[cfg(feature = "gui")]
+use super::ble::BleDevice;
+#[cfg(feature = "gui")]
+use crate::commands::handshake::handshake;
+#[cfg(feature = "gui")]
+use crate::commands::reconnect::link_lost;
+use crate::error::{Error, ErrorKind};
+#[cfg(feature = "gui")]
+use crate::registry::DeviceRegistry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+  None,
+  Even,
+  Odd,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SerialConfig {
+  pub port: String,
+  pub baud: u32,
+  /// 5 to 8 data bits per character.
+  pub data_bits: u8,
+  pub parity: Parity,
+  /// 1 or 2 stop bits.
+  pub stop_bits: u8,
+  /// Largest single write, kept equal to the BLE payload so OTA chunks match.
+  pub mtu: usize,
+  /// Silence on the line that ends one notification.
+  pub frame_gap_ms: u64,
+}
+
+impl Default for SerialConfig {
+  fn default() -> Self {
+    SerialConfig {
+      port: String::new(),
+      baud: 115200,
+      data_bits: 8,
+      parity: Parity::None,
+      stop_bits: 1,
+      mtu: 247 - 3,
+      frame_gap_ms: 5,
+    }
+  }
+}
+
+#[cfg(unix)]
+pub use unix::SerialTransfer;
+
+#[cfg(unix)]
+mod unix {
+  use async_trait::async_trait;
+  use nix::fcntl::{OFlag, open};
+  use nix::sys::stat::Mode;
+  use nix::sys::termios::{self, BaudRate, ControlFlags, SetArg};
+  use std::fs::File;
+  use std::io::{Read, Write};
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+  use tokio::io::unix::AsyncFd;
+  use tokio::task::JoinHandle;
+
+  use super::{Parity, SerialConfig};
+  use crate::error::{Error, ErrorKind};
+  use crate::transfer::{Transfer, dispatch_notification, send_fragmented};
+
+  type NotifyCallback = Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>;
+  type DisconnectHandler = Arc<dyn Fn() + Send + Sync + 'static>;
+
+  const READ_CHUNK: usize = 512;
+  const MAX_FRAME: usize = 4096;
+
+  fn baud_rate(baud: u32) -> Result<BaudRate, Error> {
+    Ok(match baud {
+      9600 => BaudRate::B9600,
+      19200 => BaudRate::B19200,
+      38400 => BaudRate::B38400,
+      57600 => BaudRate::B57600,
+      115200 => BaudRate::B115200,
+      230400 => BaudRate::B230400,
+      #[cfg(any(target_os = "linux", target_os = "android"))]
+      460800 => BaudRate::B460800,
+      #[cfg(any(target_os = "linux", target_os = "android"))]
+      921600 => BaudRate::B921600,
+      _ => {
+        return Err(Error::invalid_data(format!(
+          "Unsupported baud rate {}",
+          baud
+        )));
+      }
+    })
+  }
+
+  fn open_port(config: &SerialConfig) -> Result<File, Error> {
+    let fd = open(
+      config.port.as_str(),
+      OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK,
+      Mode::empty(),
+    )
+    .map_err(|e| {
+      Error::new(
+        ErrorKind::Unavailable,
+        format!("Failed to open {}: {}", config.port, e),
+      )
+    })?;
+
+    let termios_error = |e| Error::transport(format!("Failed to configure {}: {}", config.port, e));
+    let mut tio = termios::tcgetattr(&fd).map_err(termios_error)?;
+    termios::cfmakeraw(&mut tio);
+    termios::cfsetspeed(&mut tio, baud_rate(config.baud)?).map_err(termios_error)?;
+
+    let flags = &mut tio.control_flags;
+    flags.remove(ControlFlags::CSIZE | ControlFlags::PARENB | ControlFlags::PARODD);
+    flags.remove(ControlFlags::CSTOPB | ControlFlags::CRTSCTS);
+    flags.insert(ControlFlags::CLOCAL | ControlFlags::CREAD);
+    flags.insert(match config.data_bits {
+      5 => ControlFlags::CS5,
+      6 => ControlFlags::CS6,
+      7 => ControlFlags::CS7,
+      8 => ControlFlags::CS8,
+      bits => {
+        return Err(Error::invalid_data(format!(
+          "Unsupported data bits {}",
+          bits
+        )));
+      }
+    });
+    match config.parity {
+      Parity::None => {}
+      Parity::Even => flags.insert(ControlFlags::PARENB),
+      Parity::Odd => flags.insert(ControlFlags::PARENB | ControlFlags::PARODD),
+    }
+    match config.stop_bits {
+      1 => {}
+      2 => flags.insert(ControlFlags::CSTOPB),
+      bits => {
+        return Err(Error::invalid_data(format!(
+          "Unsupported stop bits {}",
+          bits
+        )));
+      }
+    }
+    termios::tcsetattr(&fd, SetArg::TCSANOW, &tio).map_err(termios_error)?;
+
+    Ok(File::from(fd))
+  }
+
+  async fn read_some(port: &AsyncFd<File>, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+      let mut guard = port.readable().await?;
+      match guard.try_io(|inner| {
+        let mut file = inner.get_ref();
+        file.read(buf)
+      }) {
+        Ok(result) => return result,
+        Err(_would_block) => continue,
+      }
+    }
+  }
+
+  async fn write_all(port: &AsyncFd<File>, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+      let mut guard = port.writable().await?;
+      match guard.try_io(|inner| {
+        let mut file = inner.get_ref();
+        file.write(data)
+      }) {
+        Ok(Ok(written)) => data = &data[written..],
+        Ok(Err(e)) => return Err(e),
+        Err(_would_block) => continue,
+      }
+    }
+    Ok(())
+  }
+
+  // 串口是字节流，以线路空闲作为一帧通知的结束
+  async fn read_frame(port: &AsyncFd<File>, gap: Duration) -> std::io::Result<Vec<u8>> {
+    let mut buf = [0u8; READ_CHUNK];
+    let len = read_some(port, &mut buf).await?;
+    if len == 0 {
+      return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut frame = buf[..len].to_vec();
+    while frame.len() < MAX_FRAME {
+      match tokio::time::timeout(gap, read_some(port, &mut buf)).await {
+        Ok(Ok(0)) | Err(_) => break,
+        Ok(Ok(len)) => frame.extend_from_slice(&buf[..len]),
+        Ok(Err(e)) => return Err(e),
+      }
+    }
+    Ok(frame)
+  }
+
+  /// `Transfer` over a USB-UART to the same MCU console the BLE link exposes.
+  ///
+  /// A reader task delivers incoming bytes as notifications, splitting the
+  /// stream wherever the line stays idle for `frame_gap_ms`.
+  pub struct SerialTransfer {
+    config: SerialConfig,
+    port: Arc<Mutex<Option<Arc<AsyncFd<File>>>>>,
+    callback: Arc<Mutex<Option<NotifyCallback>>>,
+    reader: Mutex<Option<JoinHandle<()>>>,
+    on_disconnect: Option<DisconnectHandler>,
+  }
+
+  impl SerialTransfer {
+    pub fn new(config: SerialConfig) -> Self {
+      SerialTransfer {
+        config,
+        port: Arc::new(Mutex::new(None)),
+        callback: Arc::new(Mutex::new(None)),
+        reader: Mutex::new(None),
+        on_disconnect: None,
+      }
+    }
+
+    #[cfg(feature = "gui")]
+    /// Called when the port goes away underneath an active link, e.g. the
+    /// adapter is unplugged.
+    pub fn with_disconnect_handler(mut self, handler: impl Fn() + Send + Sync + 'static) -> Self {
+      self.on_disconnect = Some(Arc::new(handler));
+      self
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn port_name(&self) -> &str {
+      &self.config.port
+    }
+
+    fn port(&self) -> Result<Arc<AsyncFd<File>>, Error> {
+      self
+        .port
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or_else(Error::not_connected)
+    }
+
+    fn spawn_reader(&self, port: Arc<AsyncFd<File>>) -> JoinHandle<()> {
+      let slot = self.port.clone();
+      let callback = self.callback.clone();
+      let on_disconnect = self.on_disconnect.clone();
+      let gap = Duration::from_millis(self.config.frame_gap_ms);
+      let name = self.config.port.clone();
+      tokio::spawn(async move {
+        loop {
+          match read_frame(&port, gap).await {
+            Ok(frame) => {
+              let cb = callback.lock().unwrap().clone();
+              if let Some(cb) = cb {
+                dispatch_notification(cb, frame).await;
+              }
+            }
+            Err(e) => {
+              log::warn!("Serial port {} closed: {}", name, e);
+              slot.lock().unwrap().take();
+              if let Some(handler) = on_disconnect {
+                handler();
+              }
+              break;
+            }
+          }
+        }
+      })
+    }
+  }
+
+  impl Drop for SerialTransfer {
+    fn drop(&mut self) {
+      if let Some(reader) = self.reader.lock().unwrap().take() {
+        reader.abort();
+      }
+    }
+  }
+
+  #[async_trait]
+  impl Transfer for SerialTransfer {
+    fn get_mtu(&self) -> usize {
+      self.config.mtu
+    }
+
+    async fn activate(&self) -> Result<(), Error> {
+      if self.port.lock().unwrap().is_some() {
+        return Ok(());
+      }
+      let port = Arc::new(
+        AsyncFd::new(open_port(&self.config)?)
+          .map_err(|e| Error::transport(format!("Failed to register serial port: {}", e)))?,
+      );
+      *self.port.lock().unwrap() = Some(port.clone());
+      let reader = self.spawn_reader(port);
+      if let Some(previous) = self.reader.lock().unwrap().replace(reader) {
+        previous.abort();
+      }
+      log::info!(
+        "Serial port {} opened at {} baud",
+        self.config.port,
+        self.config.baud
+      );
+      Ok(())
+    }
+
+    async fn deactivate(&self) -> Result<(), Error> {
+      if let Some(reader) = self.reader.lock().unwrap().take() {
+        reader.abort();
+      }
+      self.port.lock().unwrap().take();
+      *self.callback.lock().unwrap() = None;
+      Ok(())
+    }
+
+    async fn is_actived(&self) -> Result<bool, Error> {
+      Ok(self.port.lock().unwrap().is_some())
+    }
+
+    async fn send(&self, data: &[u8]) -> Result<(), Error> {
+      let port = self.port()?;
+      // 与 BLE 一致，超过 mtu 的数据分多次写入
+      send_fragmented(data, &AtomicUsize::new(self.config.mtu), |chunk| {
+        let port = port.clone();
+        async move {
+          write_all(&port, &chunk)
+            .await
+            .map_err(|e| Error::transport(format!("Serial send failed: {}", e)))
+        }
+      })
+      .await
+    }
+
+    async fn read(&self) -> Result<Vec<u8>, Error> {
+      Err(Error::new(
+        ErrorKind::Unavailable,
+        "Serial transfer delivers data through notifications only",
+      ))
+    }
+
+    async fn subscribe(
+      &self,
+      callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+    ) -> Result<(), Error> {
+      *self.callback.lock().unwrap() = Some(callback);
+      Ok(())
+    }
+
+    async fn unsubscribe(&self) -> Result<(), Error> {
+      *self.callback.lock().unwrap() = None;
+      Ok(())
+    }
+  }
+}
+
+// 已打开的串口连接，按端口索引，断开时无需依赖会话中的具体类型
+#[cfg(all(feature = "gui", unix))]
+static SERIAL_PORTS: LazyLock<Mutex<HashMap<String, Arc<SerialTransfer>>>> =
+  LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(feature = "gui")]
+fn serial_device(port: &str, isconnected: bool) -> BleDevice {
+  BleDevice::new(format!("Serial ({})", port), port, isconnected)
+}
+
+// 常见的 USB 转串口设备节点
+const PORT_PREFIXES: [&str; 5] = ["ttyUSB", "ttyACM", "ttyAMA", "cu.usbserial", "cu.usbmodem"];
+
+/// Returned by the serial commands where there is no serial backend, so
+/// the UI can say so instead of showing an empty port list.
+pub fn serial_unavailable() -> Error {
+  Error::new(
+    ErrorKind::Unavailable,
+    "Serial ports are not supported on this platform",
+  )
+}
+
+/// Lists USB-UART device nodes. Fails with `Unavailable` on platforms
+/// without serial support (only Unix-like systems have it).
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn list_serial_ports() -> Result<Vec<String>, Error> {
+  if cfg!(not(unix)) {
+    return Err(serial_unavailable());
+  }
+  let entries = match std::fs::read_dir("/dev") {
+    Ok(entries) => entries,
+    Err(_) => return Ok(Vec::new()),
+  };
+  let mut ports: Vec<String> = entries
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| entry.file_name().into_string().ok())
+    .filter(|name| PORT_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+    .map(|name| format!("/dev/{}", name))
+    .collect();
+  ports.sort();
+  Ok(ports)
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn connect_serial(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  config: SerialConfig,
+) -> Result<(), Error> {
+  #[cfg(unix)]
+  {
+    log::info!("Connecting serial port: {:?}", config);
+    let port = config.port.clone();
+    let serial = Arc::new(SerialTransfer::new(config).with_disconnect_handler({
+      let app_handle = app_handle.clone();
+      let port = port.clone();
+      move || link_lost(&app_handle, serial_device(&port, true))
+    }));
+    serial.activate().await?;
+    let previous = SERIAL_PORTS
+      .lock()
+      .unwrap()
+      .insert(port.clone(), serial.clone());
+    if let Some(previous) = previous {
+      previous.deactivate().await.ok();
+    }
+    let session = devices.open(&port);
+    session.attach(serial.clone()).await?;
+    handshake(&session).await;
+    let _ = app_handle.emit("ble_status", serial_device(serial.port_name(), true));
+    Ok(())
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = (app_handle, devices, config);
+    Err(serial_unavailable())
+  }
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn disconnect_serial(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let not_connected = Error::new(
+    ErrorKind::NotConnected,
+    format!("Serial port {} not connected", device),
+  );
+  #[cfg(unix)]
+  {
+    let previous = SERIAL_PORTS.lock().unwrap().remove(&device);
+    match previous {
+      Some(serial) => {
+        devices.close(&device).await.ok();
+        serial.deactivate().await?;
+        let _ = app_handle.emit("ble_status", serial_device(&device, false));
+        Ok(())
+      }
+      None => Err(not_connected),
+    }
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = (app_handle, devices);
+    Err(not_connected)
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use crate::commands::CMD_OK;
+  use crate::transfer::Transfer;
+  use nix::pty::openpty;
+  use nix::unistd::ttyname;
+  use std::fs::File;
+  use std::io::{Read, Write};
+  use std::os::fd::OwnedFd;
+  use std::sync::Arc;
+  use std::time::Duration;
+  use tokio::sync::mpsc;
+
+  // 伪终端的主端扮演 MCU，从端作为串口交给 SerialTransfer 打开
+  fn pty() -> (File, SerialConfig, OwnedFd) {
+    let pair = openpty(None, None).unwrap();
+    let config = SerialConfig {
+      port: ttyname(&pair.slave).unwrap().to_string_lossy().into_owned(),
+      ..Default::default()
+    };
+    (File::from(pair.master), config, pair.slave)
+  }
+
+  fn read_exact_blocking(mcu: &mut File, len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    mcu.read_exact(&mut buf).unwrap();
+    buf
+  }
+
+  #[tokio::test]
+  async fn sends_and_receives_over_pty() {
+    let (mut mcu, config, _slave) = pty();
+    let serial = SerialTransfer::new(config);
+    serial.activate().await.unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    serial
+      .subscribe(Arc::new(move |data| {
+        let _ = tx.send(data);
+      }))
+      .await
+      .unwrap();
+
+    serial.send(b"ping\r\n").await.unwrap();
+    let request = tokio::task::spawn_blocking(move || {
+      let request = read_exact_blocking(&mut mcu, 6);
+      mcu.write_all(&CMD_OK.to_le_bytes()).unwrap();
+      (request, mcu)
+    });
+    let (request, _mcu) = request.await.unwrap();
+
+    assert_eq!(request, b"ping\r\n");
+    let frame = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(frame, CMD_OK.to_le_bytes());
+  }
+
+  #[tokio::test]
+  async fn fragments_writes_above_mtu() {
+    let (mut mcu, config, _slave) = pty();
+    let serial = SerialTransfer::new(SerialConfig { mtu: 4, ..config });
+    serial.activate().await.unwrap();
+
+    serial.send(b"config_read\r\n").await.unwrap();
+    let request = tokio::task::spawn_blocking(move || read_exact_blocking(&mut mcu, 13));
+
+    assert_eq!(request.await.unwrap(), b"config_read\r\n");
+  }
+
+  #[tokio::test]
+  async fn unknown_port_is_unavailable() {
+    let serial = SerialTransfer::new(SerialConfig {
+      port: "/dev/does-not-exist".to_string(),
+      ..Default::default()
+    });
+
+    let error = serial.activate().await.unwrap_err();
+
+    assert_eq!(error.kind, ErrorKind::Unavailable);
+    assert!(!serial.is_actived().await.unwrap());
+  }
+}
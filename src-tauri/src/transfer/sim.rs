@@ -0,0 +1,645 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(feature = "gui")]
+use tauri::{Emitter, State};
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "gui")]
+use super::ble::BleDevice;
+use super::bootloader::{BootFault, SimBootloader};
+use super::{Transfer, dispatch_notification, send_fragmented};
+#[cfg(feature = "gui")]
+use crate::commands::handshake::handshake;
+use crate::commands::{CMD_ERR, CMD_OK};
+use crate::error::{Error, ErrorKind};
+#[cfg(feature = "gui")]
+use crate::registry::DeviceRegistry;
+
+type NotifyCallback = Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>;
+
+#[cfg(feature = "gui")]
+pub const SIM_ADDRESS: &str = "SIM:00:00:00:00:01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SimModel {
+  Valve,
+  Channel,
+  AirPressure,
+}
+
+// 针对某条命令的故障注入
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "command", rename_all = "snake_case")]
+pub enum SimFault {
+  /// Reply `CMD_ERR` to the command.
+  Reject(String),
+  /// Swallow the command without any reply.
+  Ignore(String),
+  /// Drop the link instead of replying; sends fail until `activate`.
+  Disconnect(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimConfig {
+  pub model: SimModel,
+  pub mtu: usize,
+  pub latency_ms: u64,
+  /// Probability in `0.0..=1.0` that a single notification is lost.
+  pub packet_loss: f64,
+  pub stream_interval_ms: u64,
+  pub seed: u64,
+  pub faults: Vec<SimFault>,
+  /// Faults for the bootloader entered through the `update` command.
+  pub boot_faults: Vec<BootFault>,
+}
+
+impl Default for SimConfig {
+  fn default() -> Self {
+    SimConfig {
+      model: SimModel::Valve,
+      mtu: 247 - 3,
+      latency_ms: 20,
+      packet_loss: 0.0,
+      stream_interval_ms: 100,
+      seed: 0x5eed,
+      faults: Vec::new(),
+      boot_faults: Vec::new(),
+    }
+  }
+}
+
+impl SimModel {
+  fn default_config(&self) -> Value {
+    match self {
+      SimModel::Valve => json!({ "model": "SIM-VALVE", "tick": 1000, "dir": false }),
+      SimModel::Channel => json!({ "model": "SIM-CHANNEL" }),
+      SimModel::AirPressure => json!({ "model": "SIM-AIRPRESSURE", "pressure": 101.3 }),
+    }
+  }
+
+  fn info(&self) -> Value {
+    let (model, capability) = match self {
+      SimModel::Valve => ("SIM-VALVE", "valve"),
+      SimModel::Channel => ("SIM-CHANNEL", "channel"),
+      SimModel::AirPressure => ("SIM-AIRPRESSURE", "airpressure"),
+    };
+    json!({
+      "model": model,
+      "hardware": "sim",
+      "firmware": env!("CARGO_PKG_VERSION"),
+      "serial": "SIM0001",
+      "bootloader": "1.0.0",
+      "flash": { "start": 0x0800_8000u32, "size": 0x0003_8000u32 },
+      "capabilities": [capability, "ota", "ota_resume"],
+    })
+  }
+}
+
+struct SimState {
+  active: bool,
+  callback: Option<NotifyCallback>,
+  config: Value,
+  faults: Vec<SimFault>,
+  rng: u64,
+  line: Vec<u8>,
+  streams: HashMap<&'static str, JoinHandle<()>>,
+  last_notify: Vec<u8>,
+  bootloader: Option<Arc<SimBootloader>>,
+}
+
+impl SimState {
+  // xorshift64，保证给定种子时丢包序列可复现
+  fn next_random(&mut self) -> f64 {
+    self.rng ^= self.rng << 13;
+    self.rng ^= self.rng >> 7;
+    self.rng ^= self.rng << 17;
+    (self.rng >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  fn stop_streams(&mut self) {
+    for (_, handle) in self.streams.drain() {
+      handle.abort();
+    }
+  }
+}
+
+/// Software stand-in for the valve/channel/airpressure MCU behind `Transfer`.
+///
+/// It speaks the same `"cmd args\r\n"` text protocol as the firmware, answers
+/// with `CMD_OK`/`CMD_ERR` frames and streams `ValveVal`/`AirPressureVal`
+/// samples, with configurable latency, packet loss and per-command faults.
+pub struct SimTransfer {
+  config: SimConfig,
+  state: Arc<Mutex<SimState>>,
+}
+
+impl SimTransfer {
+  pub fn new(config: SimConfig) -> Self {
+    let state = SimState {
+      active: true,
+      callback: None,
+      config: config.model.default_config(),
+      faults: config.faults.clone(),
+      rng: config.seed.max(1),
+      line: Vec::new(),
+      streams: HashMap::new(),
+      last_notify: Vec::new(),
+      bootloader: None,
+    };
+    SimTransfer {
+      config,
+      state: Arc::new(Mutex::new(state)),
+    }
+  }
+
+  #[cfg(feature = "gui")]
+  pub fn inject(&self, fault: SimFault) {
+    self.state.lock().unwrap().faults.push(fault);
+  }
+
+  #[cfg(feature = "gui")]
+  pub fn clear_faults(&self) {
+    self.state.lock().unwrap().faults.clear();
+  }
+
+  fn latency(&self) -> Duration {
+    Duration::from_millis(self.config.latency_ms)
+  }
+
+  fn ack(code: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = code.to_le_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  fn handle_line(&self, line: &str) -> Vec<Vec<u8>> {
+    let (verb, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+
+    let fault = self
+      .state
+      .lock()
+      .unwrap()
+      .faults
+      .iter()
+      .find(|fault| match fault {
+        SimFault::Reject(cmd) | SimFault::Ignore(cmd) | SimFault::Disconnect(cmd) => cmd == verb,
+      })
+      .cloned();
+    match fault {
+      Some(SimFault::Reject(_)) => return vec![Self::ack(CMD_ERR, &[])],
+      Some(SimFault::Ignore(_)) => return Vec::new(),
+      Some(SimFault::Disconnect(_)) => {
+        let mut state = self.state.lock().unwrap();
+        state.active = false;
+        state.callback = None;
+        state.stop_streams();
+        log::warn!("Simulator dropped link on command: {}", verb);
+        return Vec::new();
+      }
+      None => {}
+    }
+
+    match verb {
+      "ping" => vec![Self::ack(CMD_OK, &[])],
+      "info" => vec![Self::ack(
+        CMD_OK,
+        self.config.model.info().to_string().as_bytes(),
+      )],
+      "reboot" => {
+        self.state.lock().unwrap().stop_streams();
+        vec![Self::ack(CMD_OK, &[])]
+      }
+      "config_read" => {
+        let config = self.state.lock().unwrap().config.to_string();
+        vec![Self::ack(CMD_OK, config.as_bytes())]
+      }
+      "config_write" => match serde_json::from_str::<Value>(args) {
+        Ok(Value::Object(fields)) => {
+          let mut state = self.state.lock().unwrap();
+          if let Value::Object(config) = &mut state.config {
+            config.extend(fields);
+          }
+          vec![Self::ack(CMD_OK, &[])]
+        }
+        _ => {
+          log::warn!("Simulator rejected config_write payload: {}", args);
+          vec![Self::ack(CMD_ERR, &[])]
+        }
+      },
+      "update" => {
+        // 固件重启进入bootloader，之后的数据交给bootloader处理，不回复
+        let bootloader = SimBootloader::new(self.config.mtu);
+        for fault in &self.config.boot_faults {
+          bootloader.inject(*fault);
+        }
+        let mut state = self.state.lock().unwrap();
+        state.stop_streams();
+        state.bootloader = Some(Arc::new(bootloader));
+        Vec::new()
+      }
+      "config_refactory" => {
+        self.state.lock().unwrap().config = self.config.model.default_config();
+        vec![Self::ack(CMD_OK, &[])]
+      }
+      "valve_info" if self.config.model == SimModel::Valve => {
+        self.toggle_stream("valve_info", args)
+      }
+      "valve_tuning" if self.config.model == SimModel::Valve => {
+        self.toggle_stream("valve_tuning", args)
+      }
+      "airpressure_info" if self.config.model == SimModel::AirPressure => {
+        self.toggle_stream("airpressure_info", args)
+      }
+      _ => {
+        log::warn!("Simulator received unsupported command: {}", line);
+        vec![Self::ack(CMD_ERR, &[])]
+      }
+    }
+  }
+
+  // 处理一次写入：引导程序模式下交给引导程序，否则按行解析命令
+  async fn receive(&self, data: &[u8]) -> Result<(), Error> {
+    let bootloader = self.state.lock().unwrap().bootloader.clone();
+    if let Some(bootloader) = bootloader {
+      return self.send_to_bootloader(bootloader, data).await;
+    }
+    let lines = {
+      let mut state = self.state.lock().unwrap();
+      if !state.active {
+        return Err(Error::new(
+          ErrorKind::NotConnected,
+          "Simulated device disconnected",
+        ));
+      }
+      state.line.extend_from_slice(data);
+      let mut lines = Vec::new();
+      while let Some(pos) = state.line.windows(2).position(|w| w == b"\r\n") {
+        let line: Vec<u8> = state.line.drain(..pos + 2).collect();
+        lines.push(String::from_utf8_lossy(&line[..pos]).into_owned());
+      }
+      lines
+    };
+
+    let frames: Vec<Vec<u8>> = lines
+      .iter()
+      .flat_map(|line| self.handle_line(line))
+      .collect();
+    if !frames.is_empty() {
+      let state = self.state.clone();
+      let latency = self.latency();
+      let packet_loss = self.config.packet_loss;
+      tokio::spawn(async move {
+        tokio::time::sleep(latency).await;
+        for frame in frames {
+          deliver(&state, frame, packet_loss).await;
+        }
+      });
+    }
+    Ok(())
+  }
+
+  async fn send_to_bootloader(
+    &self,
+    bootloader: Arc<SimBootloader>,
+    data: &[u8],
+  ) -> Result<(), Error> {
+    let callback = self.state.lock().unwrap().callback.clone();
+    match callback {
+      Some(cb) => bootloader.subscribe(cb).await?,
+      None => bootloader.unsubscribe().await?,
+    }
+    bootloader.send(data).await?;
+    if bootloader.is_completed() {
+      log::info!(
+        "Simulator flashed {} bytes in {} blocks, leaving bootloader",
+        bootloader.flashed().len(),
+        bootloader.signatures().len()
+      );
+      self.state.lock().unwrap().bootloader = None;
+    }
+    Ok(())
+  }
+
+  fn toggle_stream(&self, verb: &'static str, args: &str) -> Vec<Vec<u8>> {
+    let mut state = self.state.lock().unwrap();
+    if let Some(handle) = state.streams.remove(verb) {
+      handle.abort();
+    }
+    match args {
+      "0" => {}
+      "1" => {
+        let handle = tokio::spawn(stream_samples(
+          self.state.clone(),
+          verb,
+          Duration::from_millis(self.config.stream_interval_ms),
+          self.config.packet_loss,
+        ));
+        state.streams.insert(verb, handle);
+      }
+      _ => return vec![Self::ack(CMD_ERR, &[])],
+    }
+    vec![Self::ack(CMD_OK, &[])]
+  }
+}
+
+// 与 commands::ValveVal / commands::AirPressureVal 的内存布局一致
+fn sample(verb: &str, n: u32, config: &Value) -> Vec<u8> {
+  match verb {
+    "airpressure_info" => {
+      let base = config["pressure"].as_f64().unwrap_or(101.3) * 10.0;
+      let jitter = (n % 7) as f64 - 3.0;
+      ((base + jitter) as u16).to_le_bytes().to_vec()
+    }
+    "valve_tuning" => {
+      let total_ticks = ((n % 40) as i32 - 20) * 5;
+      [total_ticks.to_le_bytes(), 2u32.to_le_bytes()].concat()
+    }
+    _ => {
+      let tick = config["tick"].as_i64().unwrap_or(1000) as i32;
+      let dir = if config["dir"].as_bool().unwrap_or(false) {
+        -1
+      } else {
+        1
+      };
+      let total_ticks = dir * (n as i32 % tick.max(1));
+      [total_ticks.to_le_bytes(), 1u32.to_le_bytes()].concat()
+    }
+  }
+}
+
+async fn stream_samples(
+  state: Arc<Mutex<SimState>>,
+  verb: &'static str,
+  interval: Duration,
+  packet_loss: f64,
+) {
+  let mut n: u32 = 0;
+  loop {
+    tokio::time::sleep(interval).await;
+    let frame = sample(verb, n, &state.lock().unwrap().config);
+    deliver(&state, frame, packet_loss).await;
+    n = n.wrapping_add(1);
+  }
+}
+
+async fn deliver(state: &Arc<Mutex<SimState>>, frame: Vec<u8>, packet_loss: f64) {
+  let callback = {
+    let mut state = state.lock().unwrap();
+    if !state.active || state.next_random() < packet_loss {
+      return;
+    }
+    state.last_notify = frame.clone();
+    state.callback.clone()
+  };
+  if let Some(cb) = callback {
+    dispatch_notification(cb, frame).await;
+  }
+}
+
+#[async_trait]
+impl Transfer for SimTransfer {
+  fn get_mtu(&self) -> usize {
+    self.config.mtu
+  }
+
+  async fn activate(&self) -> Result<(), Error> {
+    let mut state = self.state.lock().unwrap();
+    state.active = true;
+    state.line.clear();
+    Ok(())
+  }
+
+  async fn deactivate(&self) -> Result<(), Error> {
+    let mut state = self.state.lock().unwrap();
+    state.active = false;
+    state.callback = None;
+    state.stop_streams();
+    Ok(())
+  }
+
+  async fn is_actived(&self) -> Result<bool, Error> {
+    Ok(self.state.lock().unwrap().active)
+  }
+
+  async fn send(&self, data: &[u8]) -> Result<(), Error> {
+    // 与 BLE 一致，超过 mtu 的数据分多次写入
+    send_fragmented(
+      data,
+      &AtomicUsize::new(self.config.mtu),
+      |chunk| async move { self.receive(&chunk).await },
+    )
+    .await
+  }
+
+  async fn read(&self) -> Result<Vec<u8>, Error> {
+    Ok(self.state.lock().unwrap().last_notify.clone())
+  }
+
+  async fn subscribe(
+    &self,
+    callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+  ) -> Result<(), Error> {
+    let mut state = self.state.lock().unwrap();
+    if !state.active {
+      return Err(Error::new(
+        ErrorKind::NotConnected,
+        "Simulated device disconnected",
+      ));
+    }
+    state.callback = Some(callback);
+    Ok(())
+  }
+
+  async fn unsubscribe(&self) -> Result<(), Error> {
+    self.state.lock().unwrap().callback = None;
+    Ok(())
+  }
+}
+
+#[cfg(feature = "gui")]
+// 命令经由会话访问虚拟设备，这里保留一份引用供故障注入使用
+static SIMULATOR: Mutex<Option<Arc<SimTransfer>>> = Mutex::new(None);
+
+#[cfg(feature = "gui")]
+pub fn simulator() -> Option<Arc<SimTransfer>> {
+  SIMULATOR.lock().unwrap().clone()
+}
+
+#[cfg(feature = "gui")]
+fn not_running() -> Error {
+  Error::new(ErrorKind::NotConnected, "Simulator is not running")
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn connect_simulator(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  config: Option<SimConfig>,
+) -> Result<(), Error> {
+  let config = config.unwrap_or_default();
+  log::info!("Starting device simulator: {:?}", config);
+  let name = format!("Simulator ({:?})", config.model);
+  let sim = Arc::new(SimTransfer::new(config));
+  let previous = SIMULATOR.lock().unwrap().replace(sim.clone());
+  if let Some(previous) = previous {
+    previous.deactivate().await.ok();
+  }
+  let session = devices.open(SIM_ADDRESS);
+  session.attach(sim).await?;
+  handshake(&session).await;
+  let _ = app_handle.emit("ble_status", BleDevice::new(name, SIM_ADDRESS, true));
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn disconnect_simulator(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+) -> Result<(), Error> {
+  let previous = SIMULATOR.lock().unwrap().take();
+  match previous {
+    Some(sim) => {
+      devices.close(SIM_ADDRESS).await.ok();
+      sim.deactivate().await?;
+      let name = format!("Simulator ({:?})", sim.config.model);
+      let _ = app_handle.emit("ble_status", BleDevice::new(name, SIM_ADDRESS, false));
+      Ok(())
+    }
+    None => Err(not_running()),
+  }
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn simulator_inject_fault(fault: SimFault) -> Result<(), Error> {
+  let sim = simulator().ok_or_else(not_running)?;
+  log::info!("Simulator fault injected: {:?}", fault);
+  sim.inject(fault);
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn simulator_clear_faults() -> Result<(), Error> {
+  simulator().ok_or_else(not_running)?.clear_faults();
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transfer::mock::{collector, received};
+  use tokio::sync::mpsc;
+
+  async fn subscribed(config: SimConfig) -> (SimTransfer, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let sim = SimTransfer::new(config);
+    let (rx, callback) = collector();
+    sim.subscribe(callback).await.unwrap();
+    (sim, rx)
+  }
+
+  fn code(frame: &[u8]) -> u16 {
+    u16::from_le_bytes([frame[0], frame[1]])
+  }
+
+  #[tokio::test]
+  async fn config_write_then_read_round_trips() {
+    let (sim, mut rx) = subscribed(SimConfig::default()).await;
+
+    sim
+      .send(b"config_write {\"tick\":42,\"dir\":true}\r\n")
+      .await
+      .unwrap();
+    assert_eq!(code(&received(&mut rx).await), CMD_OK);
+    sim.send(b"config_read\r\n").await.unwrap();
+    let reply = received(&mut rx).await;
+
+    assert_eq!(code(&reply), CMD_OK);
+    let config: Value = serde_json::from_slice(&reply[2..]).unwrap();
+    assert_eq!(config["tick"], 42);
+    assert_eq!(config["dir"], true);
+    assert_eq!(config["model"], "SIM-VALVE");
+  }
+
+  #[tokio::test]
+  async fn writes_above_mtu_are_fragmented() {
+    let (sim, mut rx) = subscribed(SimConfig {
+      mtu: 8,
+      ..SimConfig::default()
+    })
+    .await;
+
+    sim
+      .send(b"config_write {\"tick\":7,\"dir\":false}\r\n")
+      .await
+      .unwrap();
+
+    assert_eq!(code(&received(&mut rx).await), CMD_OK);
+  }
+
+  #[tokio::test]
+  async fn rejects_malformed_and_unknown_commands() {
+    let (sim, mut rx) = subscribed(SimConfig::default()).await;
+
+    sim.send(b"config_write not-json\r\n").await.unwrap();
+    assert_eq!(code(&received(&mut rx).await), CMD_ERR);
+    sim.send(b"airpressure_info 1\r\n").await.unwrap();
+    assert_eq!(code(&received(&mut rx).await), CMD_ERR);
+  }
+
+  #[tokio::test]
+  async fn streams_valve_samples_until_stopped() {
+    let config = SimConfig {
+      stream_interval_ms: 5,
+      ..SimConfig::default()
+    };
+    let (sim, mut rx) = subscribed(config).await;
+
+    sim.send(b"valve_info 1\r\n").await.unwrap();
+    // 采样可能先于延迟的ACK到达
+    while code(&received(&mut rx).await) != CMD_OK {}
+    for _ in 0..3 {
+      assert_eq!(received(&mut rx).await.len(), 8);
+    }
+    sim.send(b"valve_info 0\r\n").await.unwrap();
+    while code(&received(&mut rx).await) != CMD_OK {}
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert!(rx.try_recv().is_err());
+  }
+
+  #[cfg(feature = "gui")]
+  #[tokio::test]
+  async fn injected_faults_and_packet_loss() {
+    let (sim, mut rx) = subscribed(SimConfig::default()).await;
+
+    sim.inject(SimFault::Reject("ping".to_string()));
+    sim.send(b"ping\r\n").await.unwrap();
+    assert_eq!(code(&received(&mut rx).await), CMD_ERR);
+
+    sim.clear_faults();
+    sim.inject(SimFault::Disconnect("reboot".to_string()));
+    sim.send(b"reboot\r\n").await.unwrap();
+    assert!(!sim.is_actived().await.unwrap());
+    assert!(sim.send(b"ping\r\n").await.is_err());
+
+    let lossy = SimConfig {
+      packet_loss: 1.0,
+      ..SimConfig::default()
+    };
+    let (sim, mut rx) = subscribed(lossy).await;
+    sim.send(b"ping\r\n").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert!(rx.try_recv().is_err());
+  }
+}
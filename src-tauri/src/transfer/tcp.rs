@@ -0,0 +1,478 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tauri::{Emitter, State};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::task::JoinHandle;
+
+use super::ble::BleDevice;
+use super::framing::Framing;
+use super::{Transfer, dispatch_notification, send_fragmented};
+use crate::commands::handshake::handshake;
+use crate::commands::reconnect::link_lost;
+use crate::error::{Error, ErrorKind};
+use crate::registry::DeviceRegistry;
+
+type NotifyCallback = Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>;
+type DisconnectHandler = Arc<dyn Fn() + Send + Sync + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpFraming {
+  /// Notifications end with `\n`; writes go out as-is. Only suitable for
+  /// gateways that forward the text console.
+  Newline,
+  /// Every frame in either direction is prefixed with its length as a
+  /// little-endian `u16`, so binary payloads and write boundaries survive.
+  LengthPrefixed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TcpConfig {
+  pub host: String,
+  pub port: u16,
+  pub framing: TcpFraming,
+  /// Largest single write, matching the BLE payload the gateway forwards to.
+  pub mtu: usize,
+  pub connect_timeout_ms: u64,
+}
+
+impl Default for TcpConfig {
+  fn default() -> Self {
+    TcpConfig {
+      host: "127.0.0.1".to_string(),
+      port: 9000,
+      framing: TcpFraming::LengthPrefixed,
+      mtu: 247 - 3,
+      connect_timeout_ms: 5000,
+    }
+  }
+}
+
+impl TcpConfig {
+  fn address(&self) -> String {
+    format!("{}:{}", self.host, self.port)
+  }
+}
+
+impl TcpFraming {
+  fn encode(self, data: &[u8]) -> Vec<u8> {
+    match self {
+      TcpFraming::Newline => data.to_vec(),
+      TcpFraming::LengthPrefixed => {
+        let mut frame = (data.len() as u16).to_le_bytes().to_vec();
+        frame.extend_from_slice(data);
+        frame
+      }
+    }
+  }
+
+  async fn read_frame(self, reader: &mut BufReader<OwnedReadHalf>) -> std::io::Result<Vec<u8>> {
+    match self {
+      TcpFraming::Newline => {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+          return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.ends_with(b"\n") {
+          line.pop();
+        }
+        Ok(line)
+      }
+      TcpFraming::LengthPrefixed => {
+        let len = reader.read_u16_le().await? as usize;
+        let mut frame = vec![0u8; len];
+        reader.read_exact(&mut frame).await?;
+        Ok(frame)
+      }
+    }
+  }
+}
+
+/// `Transfer` to a device behind a gateway that bridges the BLE UART service
+/// to TCP.
+pub struct TcpTransfer {
+  config: TcpConfig,
+  writer: tokio::sync::Mutex<Option<OwnedWriteHalf>>,
+  connected: Arc<Mutex<bool>>,
+  callback: Arc<Mutex<Option<NotifyCallback>>>,
+  reader: Mutex<Option<JoinHandle<()>>>,
+  on_disconnect: Option<DisconnectHandler>,
+}
+
+impl TcpTransfer {
+  pub fn new(config: TcpConfig) -> Self {
+    TcpTransfer {
+      config,
+      writer: tokio::sync::Mutex::new(None),
+      connected: Arc::new(Mutex::new(false)),
+      callback: Arc::new(Mutex::new(None)),
+      reader: Mutex::new(None),
+      on_disconnect: None,
+    }
+  }
+
+  /// Called when the gateway closes the connection underneath an active link.
+  pub fn with_disconnect_handler(mut self, handler: impl Fn() + Send + Sync + 'static) -> Self {
+    self.on_disconnect = Some(Arc::new(handler));
+    self
+  }
+
+  pub fn address(&self) -> String {
+    self.config.address()
+  }
+
+  fn spawn_reader(&self, read_half: OwnedReadHalf) -> JoinHandle<()> {
+    let framing = self.config.framing;
+    let connected = self.connected.clone();
+    let callback = self.callback.clone();
+    let on_disconnect = self.on_disconnect.clone();
+    let address = self.address();
+    tokio::spawn(async move {
+      let mut reader = BufReader::new(read_half);
+      loop {
+        match framing.read_frame(&mut reader).await {
+          Ok(frame) => {
+            let cb = callback.lock().unwrap().clone();
+            if let Some(cb) = cb {
+              dispatch_notification(cb, frame).await;
+            }
+          }
+          Err(e) => {
+            log::warn!("Gateway {} closed: {}", address, e);
+            *connected.lock().unwrap() = false;
+            if let Some(handler) = on_disconnect {
+              handler();
+            }
+            break;
+          }
+        }
+      }
+    })
+  }
+}
+
+impl Drop for TcpTransfer {
+  fn drop(&mut self) {
+    if let Some(reader) = self.reader.lock().unwrap().take() {
+      reader.abort();
+    }
+  }
+}
+
+#[async_trait]
+impl Transfer for TcpTransfer {
+  fn framing(&self) -> Framing {
+    match self.config.framing {
+      // 网关已按行拆分并去掉了换行符
+      TcpFraming::Newline => Framing::Notification,
+      // 长度前缀已界定每帧，二进制负载中的 \r\n 不能再拆分
+      TcpFraming::LengthPrefixed => Framing::Notification,
+    }
+  }
+
+  fn get_mtu(&self) -> usize {
+    self.config.mtu
+  }
+
+  async fn activate(&self) -> Result<(), Error> {
+    let mut writer = self.writer.lock().await;
+    if writer.is_some() && *self.connected.lock().unwrap() {
+      return Ok(());
+    }
+    let address = self.address();
+    let stream = tokio::time::timeout(
+      Duration::from_millis(self.config.connect_timeout_ms),
+      TcpStream::connect(&address),
+    )
+    .await
+    .map_err(|_| Error::timeout(format!("Connecting to {} timed out", address)))?
+    .map_err(|e| {
+      Error::new(
+        ErrorKind::Unavailable,
+        format!("Failed to connect {}: {}", address, e),
+      )
+    })?;
+    stream.set_nodelay(true).ok();
+
+    let (read_half, write_half) = stream.into_split();
+    *writer = Some(write_half);
+    *self.connected.lock().unwrap() = true;
+    let reader = self.spawn_reader(read_half);
+    if let Some(previous) = self.reader.lock().unwrap().replace(reader) {
+      previous.abort();
+    }
+    log::info!("Connected to gateway {}", address);
+    Ok(())
+  }
+
+  async fn deactivate(&self) -> Result<(), Error> {
+    if let Some(reader) = self.reader.lock().unwrap().take() {
+      reader.abort();
+    }
+    *self.connected.lock().unwrap() = false;
+    *self.callback.lock().unwrap() = None;
+    if let Some(mut writer) = self.writer.lock().await.take() {
+      writer.shutdown().await.ok();
+    }
+    Ok(())
+  }
+
+  async fn is_actived(&self) -> Result<bool, Error> {
+    Ok(*self.connected.lock().unwrap())
+  }
+
+  async fn send(&self, data: &[u8]) -> Result<(), Error> {
+    if !*self.connected.lock().unwrap() {
+      return Err(Error::not_connected());
+    }
+    // 网关按帧转发给设备，超过 mtu 的数据与 BLE 一样分成多帧
+    send_fragmented(
+      data,
+      &AtomicUsize::new(self.config.mtu),
+      |chunk| async move {
+        let mut writer = self.writer.lock().await;
+        let writer = writer.as_mut().ok_or_else(Error::not_connected)?;
+        writer
+          .write_all(&self.config.framing.encode(&chunk))
+          .await
+          .map_err(|e| Error::transport(format!("TCP send failed: {}", e)))
+      },
+    )
+    .await
+  }
+
+  async fn read(&self) -> Result<Vec<u8>, Error> {
+    Err(Error::new(
+      ErrorKind::Unavailable,
+      "TCP transfer delivers data through notifications only",
+    ))
+  }
+
+  async fn subscribe(
+    &self,
+    callback: Arc<dyn Fn(Vec<u8>) + Send + Sync + 'static>,
+  ) -> Result<(), Error> {
+    *self.callback.lock().unwrap() = Some(callback);
+    Ok(())
+  }
+
+  async fn unsubscribe(&self) -> Result<(), Error> {
+    *self.callback.lock().unwrap() = None;
+    Ok(())
+  }
+}
+
+// 已打开的网关连接，按地址索引
+static GATEWAYS: LazyLock<Mutex<HashMap<String, Arc<TcpTransfer>>>> =
+  LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn gateway_device(address: &str, isconnected: bool) -> BleDevice {
+  BleDevice::new(format!("Gateway ({})", address), address, isconnected)
+}
+
+#[tauri::command]
+pub async fn connect_tcp(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  config: TcpConfig,
+) -> Result<(), Error> {
+  log::info!("Connecting gateway: {:?}", config);
+  let address = config.address();
+  let tcp = Arc::new(TcpTransfer::new(config).with_disconnect_handler({
+    let app_handle = app_handle.clone();
+    let address = address.clone();
+    move || link_lost(&app_handle, gateway_device(&address, true))
+  }));
+  tcp.activate().await?;
+  let previous = GATEWAYS
+    .lock()
+    .unwrap()
+    .insert(address.clone(), tcp.clone());
+  if let Some(previous) = previous {
+    previous.deactivate().await.ok();
+  }
+  let session = devices.open(&address);
+  session.attach(tcp.clone()).await?;
+  handshake(&session).await;
+  let _ = app_handle.emit("ble_status", gateway_device(&address, true));
+  Ok(())
+}
+
+#[tauri::command]
+pub async fn disconnect_tcp(
+  app_handle: tauri::AppHandle,
+  devices: State<'_, DeviceRegistry>,
+  device: String,
+) -> Result<(), Error> {
+  let previous = GATEWAYS.lock().unwrap().remove(&device);
+  match previous {
+    Some(tcp) => {
+      devices.close(&device).await.ok();
+      tcp.deactivate().await?;
+      let _ = app_handle.emit("ble_status", gateway_device(&device, false));
+      Ok(())
+    }
+    None => Err(Error::new(
+      ErrorKind::NotConnected,
+      format!("Gateway {} not connected", device),
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ota::sample::{DFU_PAGE_LEN, SampleOta};
+  use crate::session::DeviceSession;
+  use crate::transfer::sim::{SimConfig, SimTransfer};
+  use tokio::net::TcpListener;
+  use tokio::sync::mpsc;
+
+  // 测试用网关：把一个 TCP 连接桥接到模拟器，帧格式为长度前缀
+  async fn simulator_gateway(config: SimConfig) -> (TcpConfig, Arc<SimTransfer>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let sim = Arc::new(SimTransfer::new(config));
+    let device = sim.clone();
+    tokio::spawn(async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      let (read_half, mut write_half) = stream.into_split();
+      let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+      device
+        .subscribe(Arc::new(move |data| {
+          let _ = tx.send(data);
+        }))
+        .await
+        .unwrap();
+      tokio::spawn(async move {
+        while let Some(data) = rx.recv().await {
+          let frame = TcpFraming::LengthPrefixed.encode(&data);
+          if write_half.write_all(&frame).await.is_err() {
+            break;
+          }
+        }
+      });
+      let mut reader = BufReader::new(read_half);
+      while let Ok(frame) = TcpFraming::LengthPrefixed.read_frame(&mut reader).await {
+        device.send(&frame).await.ok();
+      }
+    });
+    let config = TcpConfig {
+      port,
+      ..Default::default()
+    };
+    (config, sim)
+  }
+
+  #[tokio::test]
+  async fn commands_run_through_gateway() {
+    let (config, _sim) = simulator_gateway(SimConfig::default()).await;
+    let tcp = Arc::new(TcpTransfer::new(config));
+    tcp.activate().await.unwrap();
+    let session = DeviceSession::new();
+    session.attach(tcp.clone()).await.unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let (request, rx_ack) = session.begin_request(
+      "config_read\r\n",
+      Some(Arc::new(move |data| {
+        let _ = tx.send(data);
+      })),
+    );
+    tcp.send(&request).await.unwrap();
+
+    let code = tokio::time::timeout(Duration::from_secs(2), rx_ack)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(code, crate::commands::CMD_OK);
+    let json: serde_json::Value = serde_json::from_slice(&rx.recv().await.unwrap()).unwrap();
+    assert_eq!(json["model"], "SIM-VALVE");
+  }
+
+  #[tokio::test]
+  async fn ota_runs_through_gateway() {
+    let (config, _sim) = simulator_gateway(SimConfig {
+      latency_ms: 0,
+      ..Default::default()
+    })
+    .await;
+    let tcp = Arc::new(TcpTransfer::new(config));
+    tcp.activate().await.unwrap();
+    let firmware: Arc<Vec<u8>> = Arc::new((0..DFU_PAGE_LEN + 100).map(|i| i as u8).collect());
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let sink = progress.clone();
+
+    SampleOta::new(tcp)
+      .run(firmware, move |p| sink.lock().unwrap().push(p))
+      .await
+      .unwrap();
+
+    assert_eq!(progress.lock().unwrap().last(), Some(&100));
+  }
+
+  #[tokio::test]
+  async fn newline_framing_splits_lines() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      stream.write_all(b"hello\nworld\n").await.unwrap();
+      let mut buf = [0u8; 64];
+      let _ = stream.read(&mut buf).await;
+    });
+    let tcp = TcpTransfer::new(TcpConfig {
+      port,
+      framing: TcpFraming::Newline,
+      ..Default::default()
+    });
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tcp
+      .subscribe(Arc::new(move |data| {
+        let _ = tx.send(data);
+      }))
+      .await
+      .unwrap();
+    tcp.activate().await.unwrap();
+
+    assert_eq!(rx.recv().await.unwrap(), b"hello");
+    assert_eq!(rx.recv().await.unwrap(), b"world");
+  }
+
+  #[tokio::test]
+  async fn closed_gateway_reports_disconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      drop(stream);
+    });
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let tcp = TcpTransfer::new(TcpConfig {
+      port,
+      ..Default::default()
+    })
+    .with_disconnect_handler(move || {
+      let _ = tx.send(());
+    });
+
+    tcp.activate().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), rx.recv())
+      .await
+      .unwrap();
+
+    assert!(!tcp.is_actived().await.unwrap());
+    assert_eq!(
+      tcp.send(b"ping\r\n").await.unwrap_err().kind,
+      ErrorKind::NotConnected
+    );
+  }
+}
@@ -6,6 +6,8 @@ import DeviceListSidebar from "@/components/bluetooth/device-list-sidebar";
 import DeviceDetailsView from "@/components/bluetooth/device-details-view";
 import { Toaster, toast } from "sonner";
 import { listen } from "@tauri-apps/api/event";
+import { DeviceError, describeError } from "@/types/error";
+import { ConnectedDevice, DeviceEvent } from "@/context/DeviceContext";
 
 interface BleStatus {
   name: string;
@@ -13,6 +15,11 @@ interface BleStatus {
   isconnected: boolean;
 }
 
+type LinkState =
+  | { state: "reconnecting"; attempt: number; delay_ms: number }
+  | { state: "restored"; streams: string[] }
+  | { state: "given_up"; error: DeviceError };
+
 export default function Home() {
   return (
     <SidebarProvider>
@@ -23,22 +30,49 @@ export default function Home() {
 
 function LayoutContent() {
   const { setOpenMobile } = useSidebar();
-  const [connectedDeviceName, setConnectedDeviceName] = useState<string | null>(null);
+  const [connectedDevices, setConnectedDevices] = useState<ConnectedDevice[]>([]);
+  const [selectedAddress, setSelectedAddress] = useState<string | null>(null);
   useEffect(() => {
     const connectUnlisten = listen("ble_status", (event) => {
       const device = event.payload as BleStatus;
       if (device.isconnected) {
         toast.success(`已连接到设备: ${device.name}(${device.address})`);
-        setConnectedDeviceName(device.name.toString());
+        setConnectedDevices((prev) => [
+          ...prev.filter((d) => d.address !== device.address),
+          { name: device.name, address: device.address },
+        ]);
+        setSelectedAddress(device.address);
         setOpenMobile(false); // Close mobile sidebar
       } else {
         toast.warning(`已从设备断开连接: ${device.name}(${device.address})`);
-        setConnectedDeviceName(null);
+        setConnectedDevices((prev) => {
+          const remaining = prev.filter((d) => d.address !== device.address);
+          setSelectedAddress((selected) =>
+            selected === device.address ? (remaining[0]?.address ?? null) : selected
+          );
+          return remaining;
+        });
+      }
+    });
+
+    const linkUnlisten = listen("link_state", (event) => {
+      const link = event.payload as DeviceEvent<LinkState>;
+      switch (link.state) {
+        case "reconnecting":
+          toast.info(`正在重新连接 ${link.address}（第 ${link.attempt} 次）...`, { id: `link_state_${link.address}` });
+          break;
+        case "restored":
+          toast.success(`${link.address} 连接已恢复`, { id: `link_state_${link.address}` });
+          break;
+        case "given_up":
+          toast.error(`${link.address} 重新连接失败: ${describeError(link.error)}`, { id: `link_state_${link.address}` });
+          break;
       }
     });
 
     return () => {
       connectUnlisten.then((unlisten) => unlisten());
+      linkUnlisten.then((unlisten) => unlisten());
     };
   }, []);
 
@@ -47,7 +81,11 @@ function LayoutContent() {
       <div className="flex h-screen w-screen">
         <DeviceListSidebar />
         <div className="flex flex-col flex-grow">
-          <DeviceDetailsView deviceName={connectedDeviceName} />
+          <DeviceDetailsView
+            device={connectedDevices.find((d) => d.address === selectedAddress) ?? null}
+            devices={connectedDevices}
+            onSelectDevice={setSelectedAddress}
+          />
         </div>
       </div>
       <Toaster position="top-center" />
@@ -5,7 +5,8 @@ interface DeviceCardProps {
     deviceName: string;
     macAddress: string;
     rssi: number;
-    deviceType: string; // e.g., "BLE", "Classic"
+    deviceType: string; // e.g., "Valve", "Air Pressure", "Serial"
+    firmware?: string;
     isSelected: boolean;
     onSelect: () => void;
 }
@@ -15,6 +16,7 @@ export default function DeviceCard({
     macAddress,
     rssi,
     deviceType,
+    firmware,
     isSelected,
     onSelect,
 }: DeviceCardProps) {
@@ -27,6 +29,9 @@ export default function DeviceCard({
                 <BluetoothIcon className="h-8 w-8 text-blue-500 flex-shrink-0" /> {/* Device type icon */}
                 <div className="flex-grow min-w-0">
                     <p className="text-lg font-semibold truncate">{deviceName}</p>
+                    <p className="text-xs text-gray-500 truncate">
+                        {deviceType}{firmware ? ` · v${firmware}` : ""}
+                    </p>
                     <p className="text-xs text-gray-500 truncate">MAC: {macAddress}</p>
                     <p className="text-sm text-gray-500">RSSI: {rssi} dBm</p>
                 </div>
@@ -6,6 +6,7 @@ import { SidebarTrigger } from "@/components/ui/sidebar";
 import { Tabs, TabsContent, TabsList, TabsTrigger } from "@/components/ui/tabs";
 import { Label } from "@/components/ui/label";
 import { OtaProgressProvider } from "@/context/OtaProgressContext";
+import { ConnectedDevice, DeviceProvider } from "@/context/DeviceContext";
 import DeviceOta from "@/components/bluetooth/device-ota";
 import ValveInfo from "@/components/device/valve/valve-info";
 import ValveConfig from "@/components/device/valve/valve-conig";
@@ -13,16 +14,36 @@ import ChannelConfig from "@/components/device/channel/channel-config";
 import ChannelInfo from "@/components/device/channel/channel-info";
 import AirPressureConfig from "@/components/device/airpressure/airpressure-config";
 import AirPressureInfo from "@/components/device/airpressure/airpressure-info";
+import DeviceConsole from "@/components/device/console/device-console";
 import { toast } from 'sonner';
-import { useCallback } from 'react';
+import { useEffect, useState } from 'react';
+import { describeError } from "@/types/error";
+import { DeviceInfo } from "@/types/device-info";
 
 interface DeviceDetailsViewProps {
-  deviceName: string | null;
+  device: ConnectedDevice | null;
+  devices: ConnectedDevice[];
+  onSelectDevice: (address: string) => void;
 }
 
-export default function DeviceDetailsView({ deviceName }: DeviceDetailsViewProps) {
-  // 根据设备名称确定设备类型
+export default function DeviceDetailsView({ device, devices, onSelectDevice }: DeviceDetailsViewProps) {
+  const deviceName = device?.name ?? null;
+  const [deviceInfo, setDeviceInfo] = useState<DeviceInfo | null>(null);
+
+  useEffect(() => {
+    setDeviceInfo(null);
+    if (!device) return;
+    invoke<DeviceInfo | null>('device_info', { device: device.address })
+      .then(setDeviceInfo)
+      .catch((e) => console.error(`Failed to get device info: ${describeError(e)}`));
+  }, [device?.address]);
+
+  // 优先使用设备上报的能力，未识别的旧固件再根据名称推断
   const getDeviceType = (name: string | null) => {
+    const capability = deviceInfo?.capabilities.find(
+      (c) => c === 'valve' || c === 'channel' || c === 'airpressure'
+    );
+    if (capability) return capability;
     if (!name) return 'unknown';
     
     const lowerName = name.toLowerCase();
@@ -77,17 +98,38 @@ export default function DeviceDetailsView({ deviceName }: DeviceDetailsViewProps
     <>
       <header className="flex items-center justify-between p-4 border-b">
         <SidebarTrigger />
-        <h1 className="text-lg font-semibold">{deviceName || "未选择设备"}</h1>
-        <div></div>
+        {devices.length > 1 ? (
+          <select
+            className="text-lg font-semibold bg-transparent"
+            value={device?.address}
+            onChange={(e) => onSelectDevice(e.target.value)}
+          >
+            {devices.map((d) => (
+              <option key={d.address} value={d.address}>{d.name}</option>
+            ))}
+          </select>
+        ) : (
+          <h1 className="text-lg font-semibold">{deviceName || "未选择设备"}</h1>
+        )}
+        <div className="text-xs text-gray-500 text-right">
+          {deviceInfo && (
+            <>
+              <p>{deviceInfo.model} · HW {deviceInfo.hardware}</p>
+              <p>FW {deviceInfo.firmware}{deviceInfo.serial ? ` · SN ${deviceInfo.serial}` : ""}</p>
+            </>
+          )}
+        </div>
       </header>
       <main className="p-2 flex-grow flex flex-col items-center justify-center relative">
-        {deviceName ?
-          (<OtaProgressProvider>
+        {device ?
+          (<DeviceProvider address={device.address} key={device.address}>
+          <OtaProgressProvider>
             <Tabs defaultValue="command" className="w-full h-full flex flex-col">
-              <TabsList className="grid w-full grid-cols-3">
+              <TabsList className="grid w-full grid-cols-4">
                 <TabsTrigger value="command">配置</TabsTrigger>
                 <TabsTrigger value="ota">OTA</TabsTrigger>
                 <TabsTrigger value="info">实时数据</TabsTrigger>
+                <TabsTrigger value="console">控制台</TabsTrigger>
               </TabsList>
               <TabsContent value="command" className="flex-grow mt-4">
                 {renderConfigComponent()}
@@ -98,15 +140,18 @@ export default function DeviceDetailsView({ deviceName }: DeviceDetailsViewProps
               <TabsContent value="info" className="flex-grow mt-4">
                 {renderInfoComponent()}
               </TabsContent>
+              <TabsContent value="console" className="flex-grow mt-4">
+                <DeviceConsole />
+              </TabsContent>
             </Tabs>
             <button
               onClick={() => {
-                invoke('reboot_valve')
+                invoke('reboot_valve', { device: device.address })
                   .then(() => {
                     toast.success('设备重启命令已发送，请稍候');
                   })
                   .catch((error) => {
-                    toast.error(`发送设备重启命令失败，请稍后重试: ${error}`);
+                    toast.error(`发送设备重启命令失败，请稍后重试: ${describeError(error)}`);
                   });
               }}
               className="absolute bottom-6 right-4 p-3 bg-blue-500 text-white rounded-full shadow-lg"
@@ -119,7 +164,8 @@ export default function DeviceDetailsView({ deviceName }: DeviceDetailsViewProps
                 priority
               />
             </button>
-          </OtaProgressProvider>) : (
+          </OtaProgressProvider>
+          </DeviceProvider>) : (
             <div className="flex flex-col items-center justify-center flex-grow px-6">
               <Label className="text-center text-3xl font-bold">
                 欢迎来到蓝牙设备管理系统
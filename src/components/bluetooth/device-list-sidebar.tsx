@@ -5,45 +5,66 @@ import { Sidebar } from "@/components/ui/sidebar";
 import ScanButton from "./scan-button";
 import DeviceCard from "./device-card";
 import { info, error } from '@tauri-apps/plugin-log';
-import { BleDevice, startScan, stopScan, connect, disconnect } from '@mnlphlp/plugin-blec';
 import { toast } from "sonner";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import { describeError, isDeviceError } from "@/types/error";
+import { ScanUpdate, deviceKindLabel } from "@/types/scan";
+
+const SCAN_TIMEOUT_MS = 30000;
 
 export default function DeviceListSidebar() {
-  const [devices, setDevices] = useState<BleDevice[]>([]);
-  const [selectedDeviceId, setSelectedDeviceId] = useState<string | null>(null);
+  const [devices, setDevices] = useState<ScanUpdate[]>([]);
   const [isScanning, setIsScanning] = useState(0);
-  const [connectedDeviceAddress, setConnectedDeviceAddress] = useState<string | null>(null);
+  const [serialPorts, setSerialPorts] = useState<string[]>([]);
+  // 串口仅在类 Unix 系统上可用，其他平台提示而不是显示空列表
+  const [serialUnavailable, setSerialUnavailable] = useState(false);
+  // 已连接的设备及其链路类型，可同时连接多个设备
+  const [connected, setConnected] = useState<Record<string, "ble" | "serial">>({});
+
+  const markConnected = (address: string, link: "ble" | "serial" | null) => {
+    setConnected((prev) => {
+      const next = { ...prev };
+      if (link) {
+        next[address] = link;
+      } else {
+        delete next[address];
+      }
+      return next;
+    });
+  };
+
+  useEffect(() => {
+    const unlisten = listen<ScanUpdate>("scan_update", (event) => {
+      const update = event.payload;
+      setDevices((prev) => {
+        const index = prev.findIndex((d) => d.address === update.address);
+        if (index === -1) {
+          console.log("New device found:", update);
+          return [...prev, update];
+        }
+        const next = [...prev];
+        next[index] = update;
+        return next;
+      });
+    });
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
 
   useEffect(() => {
     if (isScanning === 1) {
       console.log("Starting BLE scan...", isScanning);
-      startScan((newDevices) => {
-        setDevices((prev) => {
-          const updatedDevices = [...prev];
-          newDevices.forEach((newDevice) => {
-            if (!updatedDevices.some((d) => d.address === newDevice.address)
-              && newDevice.name
-              && newDevice.name !== ""
-              && !newDevice.name.startsWith("hci")
-              && !/^([0-9A-Fa-f]{2}[:-]){5}([0-9A-Fa-f]{2})$/.test(newDevice.name)
-              && newDevice.name.includes("CYG")
-            ) {
-              console.log("New device found:", newDevice);
-              updatedDevices.push(newDevice);
-            }
-          });
-          return updatedDevices;
-        });
-      }, 0).catch((e) => {
-        error(`Failed to start scan: ${e}`);
-        toast.error(`Failed to start scan: ${e}`);
+      invoke("scan", { options: { name_prefix: "CYG" }, timeoutMs: SCAN_TIMEOUT_MS }).catch((e) => {
+        error(`Failed to start scan: ${describeError(e)}`);
+        toast.error(`Failed to start scan: ${describeError(e)}`);
       });
     } else if (isScanning === 2) {
       console.log("Stopping BLE scan...", isScanning);
-      stopScan().catch((e) => {
-        error(`Failed to stop scan: ${e}`);
-        toast.error(`Failed to stop scan: ${e}`);
+      invoke("stop_scan").catch((e) => {
+        error(`Failed to stop scan: ${describeError(e)}`);
+        toast.error(`Failed to stop scan: ${describeError(e)}`);
       });
     }
   }, [isScanning]);
@@ -52,31 +73,68 @@ export default function DeviceListSidebar() {
     setIsScanning(scanning);
     if (scanning === 1) {
       setDevices([]);
+      invoke<string[]>("list_serial_ports")
+        .then(setSerialPorts)
+        .catch((e) => {
+          if (isDeviceError(e) && e.kind === "unavailable") {
+            setSerialUnavailable(true);
+            return;
+          }
+          error(`Failed to list serial ports: ${describeError(e)}`);
+        });
     }
   };
 
-  const handleDeviceSelect = async (dev: BleDevice) => {
-    if (connectedDeviceAddress && connectedDeviceAddress !== dev.address) {
-      try {
-        await invoke("disconnect");
-        info(`Disconnected from previous device: ${connectedDeviceAddress}`);
-      } catch (e) {
-        error(`Failed to disconnect from previous device ${connectedDeviceAddress}: ${e}`);
+  const disconnectDevice = async (address: string) => {
+    try {
+      await invoke(connected[address] === "serial" ? "disconnect_serial" : "disconnect", { device: address });
+      info(`Disconnected from device: ${address}`);
+    } catch (e) {
+      error(`Failed to disconnect from device ${address}: ${describeError(e)}`);
+    }
+    markConnected(address, null);
+  };
+
+  const handleSerialSelect = async (port: string) => {
+    if (connected[port]) {
+      await disconnectDevice(port);
+      return;
+    }
+    try {
+      await invoke("connect_serial", { config: { port } });
+      info(`Connected to serial port: ${port}`);
+      markConnected(port, "serial");
+    } catch (e) {
+      error(`Failed to connect to serial port ${port}: ${describeError(e)}`);
+      toast.error(`Failed to connect to serial port ${port}: ${describeError(e)}`);
+    }
+  };
+
+  const handleDeviceSelect = async (dev: ScanUpdate) => {
+    if (connected[dev.address]) {
+      await disconnectDevice(dev.address);
+      return;
+    }
+    // 蓝牙同一时间只能连接一个设备，先断开之前的蓝牙设备
+    for (const [address, link] of Object.entries(connected)) {
+      if (link === "ble") {
+        await disconnectDevice(address);
       }
     }
 
-    setSelectedDeviceId(dev.address);
     const connectedDevice = devices.find(d => d.address === dev.address);
     if (connectedDevice) {
       try {
         await invoke("connect", { device: { name: dev.name, address: dev.address, isconnected: false } });
         info(`Connected to device: ${dev.name}(${dev.address})`);
-        setConnectedDeviceAddress(dev.address);
+        markConnected(dev.address, "ble");
       } catch (e) {
-        error(`Failed to connect to device ${dev.name}(${dev.address}): ${e}`);
-        toast.error(`Failed to connect to device ${dev.name}: ${e}`);
-        invoke("disconnect");
-        setConnectedDeviceAddress(null);
+        error(`Failed to connect to device ${dev.name}(${dev.address}): ${describeError(e)}`);
+        toast.error(`Failed to connect to device ${dev.name}: ${describeError(e)}`);
+        // 另一台 BLE 设备占用着链路时不能断开，否则会断掉它
+        if (!(isDeviceError(e) && e.kind === "busy")) {
+          invoke("disconnect", { device: dev.address });
+        }
       }
     }
   };
@@ -85,7 +143,7 @@ export default function DeviceListSidebar() {
     <Sidebar className="flex flex-col h-full">
       <div className="flex-grow overflow-y-auto p-4 space-y-2">
         <h2 className="text-lg text-center font-semibold mb-4">Available Devices</h2>
-        {devices.length === 0 && isScanning !== 1 && (
+        {devices.length === 0 && serialPorts.length === 0 && isScanning !== 1 && (
           <p className="text-gray-500">点击“开始扫描”查找设备。</p>
         )}
         {isScanning === 1 && devices.length === 0 && (
@@ -96,12 +154,27 @@ export default function DeviceListSidebar() {
             key={device.address}
             deviceName={device.name || "Unknown Device"}
             macAddress={device.address}
-            rssi={device.rssi}
-            deviceType={"BLE"} // Assuming all devices are BLE for now
-            isSelected={selectedDeviceId === device.address}
+            rssi={device.rssi ?? 0}
+            deviceType={deviceKindLabel(device.advertisement)}
+            firmware={device.advertisement?.firmware}
+            isSelected={connected[device.address] !== undefined}
             onSelect={() => handleDeviceSelect(device)}
           />
         ))}
+        {serialUnavailable && (
+          <p className="text-sm text-gray-500">此平台不支持串口连接。</p>
+        )}
+        {serialPorts.map((port) => (
+          <DeviceCard
+            key={port}
+            deviceName={`Serial (${port})`}
+            macAddress={port}
+            rssi={0}
+            deviceType={"Serial"}
+            isSelected={connected[port] !== undefined}
+            onSelect={() => handleSerialSelect(port)}
+          />
+        ))}
       </div>
       <div className="p-4 border-t">
         <ScanButton onScanToggle={handleScanToggle} />
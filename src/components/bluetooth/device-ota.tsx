@@ -7,25 +7,32 @@ import { Button } from "@/components/ui/button";
 import { Card, CardContent } from "@/components/ui/card";
 import { Progress } from "@/components/ui/progress";
 import { useOtaProgress } from "@/context/OtaProgressContext";
+import { useDeviceAddress, DeviceEvent } from "@/context/DeviceContext";
 import { toast } from 'sonner';
 import { useEffect } from "react";
 import Image from "next/image";
+import { describeError, DeviceError } from "@/types/error";
 
 
 export default function DeviceOta() {
   const { otaProgress, setOtaProgress, otaInProgress, setOtaInProgress } = useOtaProgress();
+  const address = useDeviceAddress();
 
   useEffect(() => {
     const unlistenProgress = listen("ota_progress", (event) => {
-      setOtaProgress(event.payload as number);
-      if (event.payload === 100) {
+      const progress = event.payload as DeviceEvent<{ percentage: number }>;
+      if (progress.address !== address) return;
+      setOtaProgress(progress.percentage);
+      if (progress.percentage === 100) {
         toast.error(`OTA Sucess`);
         setOtaInProgress(false);
       }
     });
 
     const unlistenError = listen("ota_error", (event) => {
-      toast.error(`OTA Error: ${event.payload}`);
+      const otaError = event.payload as DeviceEvent<DeviceError>;
+      if (otaError.address !== address) return;
+      toast.error(`OTA Error: ${describeError(otaError)}`);
       setOtaInProgress(false);
       setOtaProgress(0); // Reset progress on error
     });
@@ -34,16 +41,25 @@ export default function DeviceOta() {
       unlistenProgress.then((f) => f());
       unlistenError.then((f) => f());
     };
-  }, []);
+  }, [address]);
 
   const handleFileSelect = async () => {
+    let path: string | null;
+    try {
+      path = await invoke<string | null>("pick_firmware");
+    } catch (pickError) {
+      toast.error(`Failed to pick firmware: ${describeError(pickError)}`);
+      return;
+    }
+    // 用户取消选择时不启动升级
+    if (!path) return;
     setOtaInProgress(true);
     setOtaProgress(0);
     try {
-      await invoke("start_valve_ota");
+      await invoke("start_valve_ota", { device: address, path });
     } catch (invokeError) {
-      error(`Failed to start OTA: ${invokeError}`);
-      toast.error(`Failed to start OTA: ${invokeError}`);
+      error(`Failed to start OTA: ${describeError(invokeError)}`);
+      toast.error(`Failed to start OTA: ${describeError(invokeError)}`);
       setOtaInProgress(false);
     }
   };
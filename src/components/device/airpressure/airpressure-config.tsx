@@ -9,6 +9,8 @@ import { zodResolver } from "@hookform/resolvers/zod";
 import * as z from "zod";
 import { toast } from "sonner";
 import { invoke } from "@tauri-apps/api/core";
+import { describeError } from "@/types/error";
+import { useDeviceAddress } from "@/context/DeviceContext";
 
 const formSchema = z.object({
   model: z.string().min(1, { message: "气压检测装置型号不能为空" }),
@@ -16,6 +18,7 @@ const formSchema = z.object({
 });
 
 export default function AirPressureConfig({ deviceName }: { deviceName: string }) {
+  const address = useDeviceAddress();
   const form = useForm<z.infer<typeof formSchema>>({
     resolver: zodResolver(formSchema),
     defaultValues: {
@@ -25,20 +28,20 @@ export default function AirPressureConfig({ deviceName }: { deviceName: string }
 
   const handleReadConfig = useCallback(async () => {
     try {
-        await invoke("airpressure_readconfig");
+        await invoke("airpressure_readconfig", { device: address });
     } catch (error) {
-        toast.error("读取配置失败：" + error);
+        toast.error("读取配置失败：" + describeError(error));
     }
-  }, []);
+  }, [address]);
 
   const handleRefactory = useCallback(async () => {
     try {
-        await invoke("airpressure_refactory");
+        await invoke("airpressure_refactory", { device: address });
         toast.info("重置配置成功");
     } catch (error) {
-        toast.error("重置配置失败：" + error);
+        toast.error("重置配置失败：" + describeError(error));
     }
-  }, []);
+  }, [address]);
 
   async function onSubmit(values: z.infer<typeof formSchema>) {
     const data = {
@@ -46,10 +49,10 @@ export default function AirPressureConfig({ deviceName }: { deviceName: string }
     };
     try {
       console.log(`${data}`)
-      await invoke<string>("airpressure_configure", { config: data });
+      await invoke<string>("airpressure_configure", { device: address, config: data });
       toast.success("配置成功！");
     } catch (error: any) {
-      toast.error("配置失败：" + error);
+      toast.error("配置失败：" + describeError(error));
     }
   }
 
@@ -6,24 +6,28 @@ import { info, error } from '@tauri-apps/plugin-log';
 import { useEffect, useState } from "react";
 import { Separator } from "@/components/ui/separator";
 import { AirPressureVal } from "@/types/airpressure";
+import { describeError } from "@/types/error";
+import { useDeviceAddress, DeviceEvent } from "@/context/DeviceContext";
 
 export default function AirPressureInfo() {
+  const address = useDeviceAddress();
   const [airPressureInfo, setAirPressureInfo] = useState<AirPressureVal>({
     current_pressure: 0,
   });
   const setup = async () => {
     try {
-      await invoke<AirPressureVal>('start_airpressure_info');
+      await invoke('start_airpressure_info', { device: address });
       info('start_airpressure_info invoked');
     } catch (e) {
-      error(`Error invoking start_airpressure_info: ${e}`);
+      error(`Error invoking start_airpressure_info: ${describeError(e)}`);
     }
   };
   // 类似 Vue 的 mounted + updated（依赖 count）
   useEffect(() => {
     // 监听事件
     const unlisten = listen('airpressure_info', (event) => {
-      const data = event.payload as AirPressureVal;
+      const data = event.payload as DeviceEvent<AirPressureVal>;
+      if (data.address !== address) return;
       setAirPressureInfo(data);
     });
 
@@ -31,11 +35,11 @@ export default function AirPressureInfo() {
 
     return () => {
       unlisten.then((f) => f());
-      invoke('stop_airpressure_info')
+      invoke('stop_airpressure_info', { device: address })
         .then(() => info('stop_airpressure_info invoked'))
-        .catch((e) => error(`Error invoking stop_airpressure_info: ${e}`));
+        .catch((e) => error(`Error invoking stop_airpressure_info: ${describeError(e)}`));
     };
-  }, []); // 依赖项为 count
+  }, [address]);
 
   return (
     <div className="flex flex-row items-center justify-around w-full h-full p-4 space-x-1">
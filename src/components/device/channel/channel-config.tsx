@@ -10,12 +10,15 @@ import * as z from "zod";
 import { toast } from "sonner";
 import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
+import { describeError } from "@/types/error";
+import { useDeviceAddress, DeviceEvent } from "@/context/DeviceContext";
 
 const formSchema = z.object({
     model: z.string().min(1, { message: "通道门型号不能为空" }),
 });
 
 export default function ChannelConfig({ deviceName }: { deviceName: string }) {
+    const address = useDeviceAddress();
     const form = useForm<z.infer<typeof formSchema>>({
         resolver: zodResolver(formSchema),
         defaultValues: {
@@ -25,25 +28,26 @@ export default function ChannelConfig({ deviceName }: { deviceName: string }) {
 
     const handleReadConfig = useCallback(async () => {
         try {
-            await invoke("channel_readconfig");
+            await invoke("channel_readconfig", { device: address });
         } catch (error) {
-            toast.error("读取配置失败：" + error);
+            toast.error("读取配置失败：" + describeError(error));
         }
-    }, []);
+    }, [address]);
 
     const handleRefactory = useCallback(async () => {
         try {
-            await invoke("channel_refactory");
+            await invoke("channel_refactory", { device: address });
             toast.info("重置配置成功");
         } catch (error) {
-            toast.error("重置配置失败：" + error);
+            toast.error("重置配置失败：" + describeError(error));
         }
-    }, []);
+    }, [address]);
 
     useEffect(() => {
         // 监听阀门配置事件
         const unlisten = listen('channel_config', (event) => {
-            const config = event.payload as { model: string; };
+            const config = event.payload as DeviceEvent<{ model: string; }>;
+            if (config.address !== address) return;
             form.reset({
                 model: config.model,
             });
@@ -53,7 +57,7 @@ export default function ChannelConfig({ deviceName }: { deviceName: string }) {
         return () => {
             unlisten.then((f) => f());
         };
-    }, [form]);
+    }, [form, address]);
 
     async function onSubmit(values: z.infer<typeof formSchema>) {
         const data = {
@@ -61,10 +65,10 @@ export default function ChannelConfig({ deviceName }: { deviceName: string }) {
         };
         try {
             console.log(`${data}`)
-            await invoke<string>("channel_configure", { config: data });
+            await invoke<string>("channel_configure", { device: address, config: data });
             toast.success("配置成功！");
         } catch (error: any) {
-            toast.error("配置失败：" + error);
+            toast.error("配置失败：" + describeError(error));
         }
     }
 
@@ -0,0 +1,119 @@
+"use client";
+
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from "@tauri-apps/api/event";
+import { error } from '@tauri-apps/plugin-log';
+import { KeyboardEvent, useEffect, useRef, useState } from "react";
+import { toast } from 'sonner';
+import { Button } from "@/components/ui/button";
+import { Input } from "@/components/ui/input";
+import { ConsoleInput, ConsoleLine, formatConsoleTime } from "@/types/console";
+import { describeError } from "@/types/error";
+import { useDeviceAddress, DeviceEvent } from "@/context/DeviceContext";
+
+// 只保留最近的记录，避免长时间运行后界面卡顿
+const MAX_LINES = 500;
+
+export default function DeviceConsole() {
+    const address = useDeviceAddress();
+    const [lines, setLines] = useState<ConsoleLine[]>([]);
+    const [history, setHistory] = useState<ConsoleInput[]>([]);
+    const [historyIndex, setHistoryIndex] = useState<number | null>(null);
+    const [format, setFormat] = useState<ConsoleInput["format"]>("text");
+    const [text, setText] = useState("");
+    const bottomRef = useRef<HTMLDivElement>(null);
+
+    useEffect(() => {
+        const unlisten = listen('console', (event) => {
+            const line = event.payload as DeviceEvent<ConsoleLine>;
+            if (line.address !== address) return;
+            setLines((prev) => [...prev.slice(-(MAX_LINES - 1)), line]);
+        });
+
+        invoke('console_open', { device: address })
+            .catch((e) => error(`Error invoking console_open: ${describeError(e)}`));
+        invoke<ConsoleInput[]>('console_history')
+            .then(setHistory)
+            .catch((e) => error(`Error invoking console_history: ${describeError(e)}`));
+
+        return () => {
+            unlisten.then((f) => f());
+            invoke('console_close', { device: address })
+                .catch((e) => error(`Error invoking console_close: ${describeError(e)}`));
+        };
+    }, [address]);
+
+    useEffect(() => {
+        bottomRef.current?.scrollIntoView({ block: "end" });
+    }, [lines]);
+
+    const send = async () => {
+        if (!text) return;
+        const input = { format, data: text } as ConsoleInput;
+        try {
+            await invoke('console_send', { device: address, input });
+            setHistory((prev) => [...prev, input]);
+            setHistoryIndex(null);
+            setText("");
+        } catch (e) {
+            toast.error(`发送失败: ${describeError(e)}`);
+        }
+    };
+
+    // 上下键浏览历史命令
+    const onKeyDown = (e: KeyboardEvent<HTMLInputElement>) => {
+        if (e.key === "Enter") {
+            send();
+            return;
+        }
+        if (e.key !== "ArrowUp" && e.key !== "ArrowDown" || history.length === 0) return;
+        e.preventDefault();
+        const current = historyIndex ?? history.length;
+        const next = e.key === "ArrowUp"
+            ? Math.max(current - 1, 0)
+            : Math.min(current + 1, history.length);
+        setHistoryIndex(next === history.length ? null : next);
+        if (next === history.length) {
+            setText("");
+        } else {
+            setFormat(history[next].format);
+            setText(history[next].data);
+        }
+    };
+
+    return (
+        <div className="flex flex-col w-full h-full p-2 gap-2">
+            <div className="flex-grow overflow-y-auto rounded border bg-black p-2 font-mono text-xs text-gray-200">
+                {lines.map((line, index) => (
+                    <div key={index} className="flex gap-3 whitespace-pre">
+                        <span className="text-gray-500">{formatConsoleTime(line.timestamp_ms)}</span>
+                        <span className={line.direction === "tx" ? "text-blue-400" : "text-green-400"}>
+                            {line.direction === "tx" ? "TX" : "RX"}
+                        </span>
+                        <span>{line.hex}</span>
+                        <span className="text-gray-400">|{line.ascii}|</span>
+                        {line.decoded && <span className="text-yellow-300">{line.decoded}</span>}
+                    </div>
+                ))}
+                <div ref={bottomRef} />
+            </div>
+            <div className="flex gap-2">
+                <Button
+                    variant="outline"
+                    onClick={() => setFormat(format === "text" ? "hex" : "text")}
+                >
+                    {format === "text" ? "文本" : "HEX"}
+                </Button>
+                <Input
+                    className="font-mono"
+                    value={text}
+                    placeholder={format === "text" ? "valve_info 1" : "a5 01 00 ff"}
+                    onChange={(e) => setText(e.target.value)}
+                    onKeyDown={onKeyDown}
+                />
+                <Button onClick={send}>发送</Button>
+                <Button variant="outline" onClick={() => setLines([])}>清空</Button>
+            </div>
+        </div>
+    );
+}
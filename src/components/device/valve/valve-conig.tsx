@@ -11,6 +11,8 @@ import { listen } from "@tauri-apps/api/event";
 import * as z from "zod";
 import { toast } from "sonner";
 import { ValveVal } from "@/types/valve";
+import { describeError } from "@/types/error";
+import { useDeviceAddress, DeviceEvent } from "@/context/DeviceContext";
 
 const formSchema = z.object({
   model: z.string().min(1, { message: "阀门型号不能为空" }),
@@ -19,6 +21,7 @@ const formSchema = z.object({
 });
 
 export default function ValveConfig({ deviceName }: { deviceName: string }) {
+  const address = useDeviceAddress();
   const form = useForm<z.infer<typeof formSchema>>({
     resolver: zodResolver(formSchema),
     defaultValues: {
@@ -40,55 +43,57 @@ export default function ValveConfig({ deviceName }: { deviceName: string }) {
       if (prev) {
         toast.info("开始标定")
         try {
-          invoke("valve_tuning_start")
+          invoke("valve_tuning_start", { device: address })
         } catch (error) {
           toast.error("标定开始失败")
         }
       } else {
         toast.info("停止标定")
         try {
-          invoke("valve_tuning_stop")
+          invoke("valve_tuning_stop", { device: address })
         } catch (error) {
           toast.error("标定停止失败")
         }
       }
       return !prev
     });
-  }, []);
+  }, [address]);
 
   const handleReadConfig = useCallback(async () => {
     try {
-      await invoke("valve_readconfig");
+      await invoke("valve_readconfig", { device: address });
     } catch (error) {
-      toast.error("读取配置失败：" + error);
+      toast.error("读取配置失败：" + describeError(error));
     }
-  }, []);
+  }, [address]);
 
   const handleRefactory = useCallback(async () => {
     try {
-      await invoke("valve_refactory");
+      await invoke("valve_refactory", { device: address });
       toast.info("重置配置成功");
     } catch (error) {
-      toast.error("重置配置失败：" + error);
+      toast.error("重置配置失败：" + describeError(error));
     }
-  }, []);
+  }, [address]);
 
   useEffect(() => {
     // 监听事件
     const unlisten = listen('valve_tuning', (event) => {
-      const data = event.payload as ValveVal;
+      const data = event.payload as DeviceEvent<ValveVal>;
+      if (data.address !== address) return;
       setValveInfo(data);
     });
 
     return () => {
       unlisten.then((f) => f());
     };
-  }, [isTuningDisabled]);
+  }, [isTuningDisabled, address]);
 
   useEffect(() => {
     // 监听阀门配置事件
     const unlisten = listen('valve_config', (event) => {
-      const config = event.payload as { model: string; tick: number; dir: boolean };
+      const config = event.payload as DeviceEvent<{ model: string; tick: number; dir: boolean }>;
+      if (config.address !== address) return;
       form.reset({
         model: config.model,
         tick: config.tick.toString(),
@@ -100,7 +105,7 @@ export default function ValveConfig({ deviceName }: { deviceName: string }) {
     return () => {
       unlisten.then((f) => f());
     };
-  }, [form]);
+  }, [form, address]);
 
   async function onSubmit(values: z.infer<typeof formSchema>) {
     const data = {
@@ -109,10 +114,10 @@ export default function ValveConfig({ deviceName }: { deviceName: string }) {
     };
     try {
       console.log(`${data}`)
-      await invoke<string>("valve_configure", { config: data });
+      await invoke<string>("valve_configure", { device: address, config: data });
       toast.success("配置成功！");
     } catch (error: any) {
-      toast.error("配置失败：" + error);
+      toast.error("配置失败：" + describeError(error));
     }
   }
 
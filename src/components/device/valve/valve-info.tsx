@@ -6,25 +6,29 @@ import { info, error } from '@tauri-apps/plugin-log';
 import { useEffect, useState } from "react";
 import { Separator } from "@/components/ui/separator";
 import { ValveVal } from "@/types/valve";
+import { describeError } from "@/types/error";
+import { useDeviceAddress, DeviceEvent } from "@/context/DeviceContext";
 
 export default function ValveInfo() {
+    const address = useDeviceAddress();
     const [valveInfo, setValveInfo] = useState<ValveVal>({
         total_ticks: 0,
         current_status: 0,
     });
     const setup = async () => {
         try {
-            await invoke<ValveVal>('start_valve_info');
+            await invoke('start_valve_info', { device: address });
             info('start_valve_info invoked');
         } catch (e) {
-            error(`Error invoking get_valve_info: ${e}`);
+            error(`Error invoking get_valve_info: ${describeError(e)}`);
         }
     };
     // 类似 Vue 的 mounted + updated（依赖 count）
     useEffect(() => {
         // 监听事件
         const unlisten = listen('valve_info', (event) => {
-            const data = event.payload as ValveVal;
+            const data = event.payload as DeviceEvent<ValveVal>;
+            if (data.address !== address) return;
             setValveInfo(data);
         });
 
@@ -32,11 +36,11 @@ export default function ValveInfo() {
 
         return () => {
             unlisten.then((f) => f());
-            invoke('stop_valve_info')
+            invoke('stop_valve_info', { device: address })
                 .then(() => info('stop_valve_info invoked'))
-                .catch((e) => error(`Error invoking stop_valve_info: ${e}`));
+                .catch((e) => error(`Error invoking stop_valve_info: ${describeError(e)}`));
         };
-    }, []); // 依赖项为 count
+    }, [address]);
 
     return (
         <div className="flex flex-row items-center justify-around w-full h-full p-4 space-x-1">
@@ -55,6 +59,9 @@ export default function ValveInfo() {
                         <p className="text-green-400">开</p>
                     )}
                 </div>
+                {valveInfo.fault_code ? (
+                    <p className="text-sm text-red-400">故障码 {valveInfo.fault_code}</p>
+                ) : null}
             </div>
             <Separator orientation="vertical" />
             <div className="flex flex-col items-center gap-5">
@@ -0,0 +1,28 @@
+import React, { createContext, useContext, ReactNode } from 'react';
+
+// 当前页面操作的设备地址，所有命令都需带上它
+const DeviceContext = createContext<string | undefined>(undefined);
+
+export const DeviceProvider = ({ address, children }: { address: string; children: ReactNode }) => {
+  return (
+    <DeviceContext.Provider value={address}>
+      {children}
+    </DeviceContext.Provider>
+  );
+};
+
+export const useDeviceAddress = () => {
+  const address = useContext(DeviceContext);
+  if (address === undefined) {
+    throw new Error('useDeviceAddress must be used within a DeviceProvider');
+  }
+  return address;
+};
+
+export interface ConnectedDevice {
+  name: string;
+  address: string;
+}
+
+// 带设备地址的事件负载
+export type DeviceEvent<T> = T & { address: string };
@@ -0,0 +1,18 @@
+// 控制台收发记录，对应 Rust 端 commands::console
+export type ConsoleInput =
+    | { format: "text"; data: string }
+    | { format: "hex"; data: string };
+
+export interface ConsoleLine {
+    timestamp_ms: number;
+    direction: "tx" | "rx";
+    hex: string;
+    ascii: string;
+    decoded: string | null;
+}
+
+export const formatConsoleTime = (timestamp_ms: number) => {
+    const date = new Date(timestamp_ms);
+    const pad = (value: number, width = 2) => value.toString().padStart(width, "0");
+    return `${pad(date.getHours())}:${pad(date.getMinutes())}:${pad(date.getSeconds())}.${pad(date.getMilliseconds(), 3)}`;
+};
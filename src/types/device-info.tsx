@@ -0,0 +1,18 @@
+// 设备连接时上报的身份信息，对应 Rust 端 commands::identify
+export type Capability = "valve" | "channel" | "airpressure" | "ota" | "ota_resume" | "unknown";
+
+export interface FlashLayout {
+    start: number;
+    size: number;
+}
+
+export interface DeviceInfo {
+    model: string;
+    hardware: string;
+    firmware: string;
+    serial: string | null;
+    bootloader: string | null;
+    flash: FlashLayout | null;
+    capabilities: Capability[];
+    protocol_version: number;
+}
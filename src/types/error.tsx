@@ -0,0 +1,31 @@
+export type DeviceErrorKind =
+    | "not_connected"
+    | "unavailable"
+    | "transport"
+    | "timeout"
+    | "rejected"
+    | "mtu_exceeded"
+    | "cancelled"
+    | "invalid_data"
+    | "io"
+    | "device_fault"
+    | "unsupported"
+    | "busy";
+
+export interface DeviceError {
+    kind: DeviceErrorKind;
+    message: string;
+    command: string | null;
+    retryable: boolean;
+}
+
+export function isDeviceError(value: unknown): value is DeviceError {
+    return typeof value === "object" && value !== null && "kind" in value && "message" in value;
+}
+
+export function describeError(value: unknown): string {
+    if (isDeviceError(value)) {
+        return value.command ? `${value.message} (${value.command})` : value.message;
+    }
+    return String(value);
+}
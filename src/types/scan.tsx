@@ -0,0 +1,29 @@
+// 广播中携带的设备信息，对应 Rust 端 transfer::advertisement
+export type DeviceKind = "valve" | "channel" | "airpressure" | "unknown";
+
+export interface Advertisement {
+    kind: DeviceKind;
+    firmware: string;
+    serial: string | null;
+}
+
+export interface ScanUpdate {
+    name: string;
+    address: string;
+    rssi: number | null;
+    rssi_avg: number | null;
+    advertisement: Advertisement | null;
+}
+
+export const deviceKindLabel = (advertisement: Advertisement | null) => {
+    switch (advertisement?.kind) {
+        case "valve":
+            return "Valve";
+        case "channel":
+            return "Channel";
+        case "airpressure":
+            return "Air Pressure";
+        default:
+            return "BLE";
+    }
+};
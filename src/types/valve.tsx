@@ -1,4 +1,7 @@
 export interface ValveVal {
     total_ticks: number;
     current_status: number;
+    // 协议 v2 起才有的字段
+    target_ticks?: number;
+    fault_code?: number;
 }